@@ -1,8 +1,24 @@
 use std::any::Any;
 
-use smithay::{backend::renderer::{element::surface::WaylandSurfaceRenderElement, gles::GlesRenderer}, desktop::space::SpaceRenderElements};
+use smithay::{
+    backend::renderer::{element::RenderElementStates, gles::GlesRenderer},
+    output::Output,
+};
 
-use crate::{state::OutputRenderElements, Tsuki};
+use crate::{
+    config::OutputConfig, protocols::output_management::OutputHead, state::OutputRenderElements,
+    Tsuki,
+};
+
+pub enum RenderResult {
+    /// A frame was queued, the backend will call `Tsuki::on_vblank` once it is presented. The
+    /// states tell which elements ended up on screen and how.
+    Submitted(RenderElementStates),
+    /// The frame had no damage so nothing was submitted.
+    NoDamage,
+    /// Rendering failed or was not possible.
+    Skipped,
+}
 
 pub trait Backend: Any {
     fn seat_name(&self) -> String;
@@ -10,9 +26,19 @@ pub trait Backend: Any {
     fn render(
         &mut self,
         tsuki: &mut Tsuki,
-        elements: &[OutputRenderElements<GlesRenderer,
-            WaylandSurfaceRenderElement<GlesRenderer>>]
-    );
+        output: &Output,
+        elements: &[OutputRenderElements<GlesRenderer>]
+    ) -> RenderResult;
     fn init(&mut self, tsuki: &mut Tsuki); 
+    /// Heads to advertise over wlr-output-management, including connected but disabled outputs.
+    fn output_heads(&self) -> Vec<OutputHead>;
+    /// Re-applies `tsuki.config.outputs` to the connected outputs. Stops at the first output
+    /// that can't be configured, after putting back the modes it already changed.
+    fn apply_output_config(&mut self, tsuki: &mut Tsuki) -> anyhow::Result<()>;
+    /// Checks a configuration from wlr-output-management the way applying it would, without
+    /// changing anything.
+    fn check_output_config(&self, configs: &[OutputConfig]) -> anyhow::Result<()>;
+    /// Blanks the output or brings it back, `Tsuki` stops rendering to outputs that are off.
+    fn set_output_power(&mut self, output: &Output, on: bool);
     fn as_any (&mut self) -> &mut dyn Any;
 }
\ No newline at end of file
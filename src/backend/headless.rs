@@ -0,0 +1,308 @@
+use std::any::Any;
+
+use smithay::{
+    backend::{
+        allocator::Fourcc,
+        egl::{native::EGLSurfacelessDisplay, EGLContext, EGLDisplay},
+        renderer::{
+            damage::OutputDamageTracker,
+            gles::{GlesRenderer, GlesTexture},
+            Bind, Offscreen,
+        },
+    },
+    output::{Mode, Output, PhysicalProperties, Subpixel},
+    reexports::wayland_server::backend::GlobalId,
+};
+
+#[cfg(test)]
+use smithay::{
+    backend::renderer::{ExportMem, Texture, Unbind},
+    utils::{Physical, Rectangle},
+};
+
+use crate::{
+    backend::{Backend, RenderResult},
+    config::OutputConfig,
+    frame_clock::mode_refresh_interval,
+    protocols::output_management::OutputHead,
+    state::{OutputRenderElements, Tsuki},
+};
+
+fn default_mode() -> Mode {
+    Mode {
+        size: (1920, 1080).into(),
+        refresh: 60_000,
+    }
+}
+
+/// Renders into offscreen textures, without any real display. Useful for testing.
+pub struct Headless {
+    renderer: GlesRenderer,
+    outputs: Vec<HeadlessOutput>,
+}
+
+struct HeadlessOutput {
+    output: Output,
+    global: Option<GlobalId>,
+    damage_tracker: OutputDamageTracker,
+    framebuffer: Option<GlesTexture>,
+    /// What the last frame repainted, `None` when it had no damage.
+    #[cfg(test)]
+    last_damage: Option<Vec<Rectangle<i32, Physical>>>,
+    /// The last frame rendered in full, to compare against the damaged framebuffer.
+    #[cfg(test)]
+    from_scratch: Option<GlesTexture>,
+}
+
+impl Backend for Headless {
+    fn seat_name(&self) -> String {
+        "headless".to_owned()
+    }
+
+    fn renderer(&mut self) -> Option<&mut GlesRenderer> {
+        Some(&mut self.renderer)
+    }
+
+    fn render(
+        &mut self,
+        tsuki: &mut Tsuki,
+        output: &Output,
+        elements: &[OutputRenderElements<GlesRenderer>]
+    ) -> RenderResult {
+        let Some(headless_output) = self.outputs.iter_mut().find(|o| &o.output == output) else {
+            return RenderResult::Skipped;
+        };
+
+        let mode = output.current_mode().unwrap_or_else(default_mode);
+
+        // The framebuffer keeps its contents between frames, so after the first frame the
+        // buffer age is always 1.
+        let age = if headless_output.framebuffer.is_some() { 1 } else { 0 };
+        let framebuffer = match headless_output.framebuffer.take() {
+            Some(framebuffer) => framebuffer,
+            None => match self.renderer.create_buffer(Fourcc::Abgr8888, (mode.size.w, mode.size.h).into()) {
+                Ok(framebuffer) => framebuffer,
+                Err(err) => {
+                    log::error!("error creating headless framebuffer: {err}");
+                    return RenderResult::Skipped;
+                }
+            },
+        };
+
+        if let Err(err) = self.renderer.bind(framebuffer.clone()) {
+            log::error!("error binding headless framebuffer: {err}");
+            return RenderResult::Skipped;
+        }
+        headless_output.framebuffer = Some(framebuffer);
+
+        let res = match headless_output
+            .damage_tracker
+            .render_output(&mut self.renderer, age, elements, [0.0, 0.0, 0.0, 1.0]) {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("error rendering frame: {err}");
+                return RenderResult::Skipped;
+            }
+        };
+
+        #[cfg(test)]
+        {
+            headless_output.last_damage = res.damage.cloned();
+
+            let mut damage_tracker = OutputDamageTracker::from_output(output);
+            let from_scratch: GlesTexture = self
+                .renderer
+                .create_buffer(Fourcc::Abgr8888, (mode.size.w, mode.size.h).into())
+                .unwrap();
+            self.renderer.bind(from_scratch.clone()).unwrap();
+            damage_tracker
+                .render_output(&mut self.renderer, 0, elements, [0.0, 0.0, 0.0, 1.0])
+                .unwrap();
+            headless_output.from_scratch = Some(from_scratch);
+        }
+
+        if res.damage.is_none() {
+            return RenderResult::NoDamage;
+        }
+
+        tsuki.simulate_vblank(output);
+        RenderResult::Submitted(res.states)
+    }
+
+    fn init(&mut self, tsuki: &mut Tsuki) {
+        tsuki.create_dmabuf_global(&self.renderer);
+        for headless_output in &mut self.outputs {
+            let output = headless_output.output.clone();
+            headless_output.global = Some(output.create_global::<Tsuki>(&tsuki.display_handle));
+            tsuki.add_output(output.clone(), mode_refresh_interval(default_mode()), None);
+        }
+
+        if let Err(err) = self.apply_output_config(tsuki) {
+            log::error!("error applying output config: {err:?}");
+        }
+    }
+
+    fn output_heads(&self) -> Vec<OutputHead> {
+        self.outputs
+            .iter()
+            .map(|headless_output| {
+                let mut head = OutputHead::from_output(&headless_output.output);
+                head.custom_modes = true;
+                head.state = head.state.filter(|_| headless_output.global.is_some());
+                head
+            })
+            .collect()
+    }
+
+    fn apply_output_config(&mut self, tsuki: &mut Tsuki) -> anyhow::Result<()> {
+        for headless_output in &mut self.outputs {
+            let output = headless_output.output.clone();
+            let physical = output.physical_properties();
+            let Some(config) = tsuki
+                .config
+                .output(&output.name(), &physical.make, &physical.model, "")
+                .cloned()
+            else {
+                continue;
+            };
+
+            if !config.enabled {
+                if let Some(global) = headless_output.global.take() {
+                    tsuki.display_handle.remove_global::<Tsuki>(global);
+                    tsuki.remove_output(&output);
+                }
+                continue;
+            }
+
+            let mode = config
+                .mode
+                .map(|mode| Mode {
+                    size: (mode.width as i32, mode.height as i32).into(),
+                    refresh: mode.refresh.map_or(60_000, |hz| (hz * 1000.).round() as i32),
+                })
+                .unwrap_or_else(|| output.current_mode().unwrap_or_else(default_mode));
+
+            if headless_output.global.is_none() {
+                headless_output.global = Some(output.create_global::<Tsuki>(&tsuki.display_handle));
+                tsuki.add_output(output.clone(), mode_refresh_interval(mode), None);
+            }
+
+            if output.current_mode() != Some(mode) {
+                for old_mode in output.modes() {
+                    output.delete_mode(old_mode);
+                }
+                output.change_current_state(Some(mode), None, None, None);
+                output.set_preferred(mode);
+                tsuki.set_refresh_interval(&output, mode_refresh_interval(mode));
+                headless_output.framebuffer = None;
+                headless_output.damage_tracker = OutputDamageTracker::from_output(&output);
+            }
+
+            tsuki.reconfigure_output(&output, &config);
+        }
+
+        tsuki.output_management_state.notify_changes::<Tsuki>(self.output_heads());
+        Ok(())
+    }
+
+    fn check_output_config(&self, _configs: &[OutputConfig]) -> anyhow::Result<()> {
+        // Any mode can be rendered offscreen.
+        Ok(())
+    }
+
+    fn set_output_power(&mut self, output: &Output, on: bool) {
+        // Like a screen that was off, the output comes back without its old contents.
+        if on {
+            return;
+        }
+        if let Some(headless_output) = self.outputs.iter_mut().find(|o| &o.output == output) {
+            headless_output.framebuffer = None;
+            headless_output.damage_tracker = OutputDamageTracker::from_output(output);
+        }
+    }
+
+    fn as_any (&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl Headless {
+    pub fn new() -> Self {
+        let count = std::env::var("TSUKI_HEADLESS_OUTPUTS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(1);
+        Self::with_outputs(count)
+    }
+
+    pub fn with_outputs(count: usize) -> Self {
+        let display = unsafe { EGLDisplay::new(EGLSurfacelessDisplay) }.unwrap();
+        let egl_context = EGLContext::new(&display).unwrap();
+        let renderer = unsafe { GlesRenderer::new(egl_context) }.unwrap();
+
+        let outputs = (1..=count)
+            .map(|i| {
+                let output = Output::new(
+                    format!("HEADLESS-{i}"),
+                    PhysicalProperties {
+                        size: (0, 0).into(),
+                        subpixel: Subpixel::Unknown,
+                        make: "Tsuki".into(),
+                        model: "Headless".into(),
+                    },
+                );
+                output.change_current_state(Some(default_mode()), None, None, None);
+                output.set_preferred(default_mode());
+
+                HeadlessOutput {
+                    damage_tracker: OutputDamageTracker::from_output(&output),
+                    output,
+                    global: None,
+                    framebuffer: None,
+                    #[cfg(test)]
+                    last_damage: None,
+                    #[cfg(test)]
+                    from_scratch: None,
+                }
+            })
+            .collect();
+
+        Self { renderer, outputs }
+    }
+}
+
+#[cfg(test)]
+impl Headless {
+    pub fn last_damage(&self, output: &Output) -> Option<&[Rectangle<i32, Physical>]> {
+        let headless_output = self.outputs.iter().find(|o| &o.output == output)?;
+        headless_output.last_damage.as_deref()
+    }
+
+    /// Reads back what the frames so far left in the framebuffer of `output`, as Abgr8888.
+    pub fn framebuffer_pixels(&mut self, output: &Output) -> Vec<u8> {
+        let headless_output = self.outputs.iter().find(|o| &o.output == output).unwrap();
+        let framebuffer = headless_output.framebuffer.clone().expect("nothing was rendered yet");
+        self.read_pixels(framebuffer)
+    }
+
+    /// Reads back the last frame of `output` rendered from scratch, as Abgr8888. It matches
+    /// [`Self::framebuffer_pixels`] if the damage was right.
+    pub fn full_repaint_pixels(&mut self, output: &Output) -> Vec<u8> {
+        let headless_output = self.outputs.iter().find(|o| &o.output == output).unwrap();
+        let from_scratch = headless_output.from_scratch.clone().expect("nothing was rendered yet");
+        self.read_pixels(from_scratch)
+    }
+
+    fn read_pixels(&mut self, texture: GlesTexture) -> Vec<u8> {
+        let size = texture.size();
+
+        self.renderer.bind(texture).unwrap();
+        let mapping = self
+            .renderer
+            .copy_framebuffer(Rectangle::from_size(size), Fourcc::Abgr8888)
+            .unwrap();
+        let pixels = self.renderer.map_texture(&mapping).unwrap().to_vec();
+        self.renderer.unbind().unwrap();
+        pixels
+    }
+}
@@ -1,7 +1,9 @@
 mod backend;
+mod headless;
 mod winit;
 mod tty;
 
-pub use backend::Backend;
+pub use backend::{Backend, RenderResult};
+pub use headless::Headless;
 pub use winit::Winit;
 pub use tty::Tty;
\ No newline at end of file
@@ -1,9 +1,7 @@
 use std::any::Any;
-use std::cell::{Ref, RefCell};
+use std::collections::HashMap;
 use std::os::fd::FromRawFd;
 use std::path::PathBuf;
-use std::rc::Rc;
-use std::time::Duration;
 
 use anyhow::{anyhow};
 use smithay::output::{Mode, Output, OutputModeSource, PhysicalProperties, Subpixel};
@@ -13,33 +11,39 @@ use nix::libc::dev_t;
 use smithay::backend::allocator::dmabuf::Dmabuf;
 use smithay::backend::allocator::gbm::GbmAllocator;
 use smithay::backend::drm::compositor::{DrmCompositor, FrameFlags};
-use smithay::backend::drm::{DrmDevice, DrmDeviceFd, DrmEvent};
-use smithay::backend::egl::{EGLContext, EGLDisplay};
+use smithay::backend::drm::{DrmDevice, DrmDeviceFd, DrmEvent, DrmEventTime, DrmNode, DrmSurface};
+use smithay::reexports::drm::control::{connector, crtc, Mode as DrmMode, ResourceHandles};
+use smithay::reexports::wayland_server::backend::GlobalId;
+use smithay::backend::egl::{EGLContext, EGLDevice, EGLDisplay};
 use smithay::backend::libinput::{LibinputInputBackend, LibinputSessionInterface};
-use smithay::backend::renderer::element::surface::WaylandSurfaceRenderElement;
 use smithay::backend::renderer::gles::GlesRenderer;
-use smithay::backend::renderer::{Bind, ImportEgl};
+use smithay::backend::renderer::element::utils::select_dmabuf_feedback;
+use smithay::backend::renderer::{Bind, ImportDma, ImportEgl};
 use smithay::backend::session::libseat::LibSeatSession;
 use smithay::backend::allocator::Fourcc;
 use smithay::backend::allocator::gbm::GbmDevice;
 use smithay::backend::session::Session;
 use smithay::backend::udev::{self, UdevBackend};
 use smithay::desktop::space::SpaceRenderElements;
-use smithay::reexports::calloop::timer::{TimeoutAction, Timer};
 use smithay::reexports::calloop::{LoopHandle, RegistrationToken};
 use smithay::reexports::input::Libinput;
 use smithay::reexports::rustix::fs::OFlags;
 use smithay::utils::DeviceFd;
 use smithay::reexports::drm::control::{Device, ModeTypeFlags};
-use smithay::reexports::drm::control::connector::{
-    Interface as ConnectorInterface, State as ConnectorState
-};
+use smithay::reexports::drm::control::connector::State as ConnectorState;
 use smithay::backend::allocator::gbm::GbmBufferFlags;
-
+use smithay::backend::allocator::format::FormatSet;
+use smithay::reexports::wayland_protocols::wp::linux_dmabuf::zv1::server::zwp_linux_dmabuf_feedback_v1::TrancheFlags;
+use smithay::reexports::wayland_protocols::wp::presentation_time::server::wp_presentation_feedback::Kind as PresentationKind;
+use smithay::wayland::dmabuf::{DmabufFeedback, DmabufFeedbackBuilder, DmabufGlobal};
+
+use crate::frame_clock::mode_refresh_interval;
+use crate::config::{ModeConfig, OutputConfig};
+use crate::protocols::output_management::OutputHead;
 use crate::state::OutputRenderElements;
 use crate::{CalloopData, Tsuki};
 
-use super::Backend;
+use super::{Backend, RenderResult};
 
 const SUPPORTED_COLOR_FORMATS: &[Fourcc] = &[Fourcc::Argb8888, Fourcc::Abgr8888];
 
@@ -47,7 +51,10 @@ const SUPPORTED_COLOR_FORMATS: &[Fourcc] = &[Fourcc::Argb8888, Fourcc::Abgr8888]
 pub struct Tty {
     session: LibSeatSession,
     primary_gpu_path: PathBuf,
-    output_device: Option<OutputDevice>
+    output_device: Option<OutputDevice>,
+    // Client buffers and the cursor go straight on planes when the device can take them, so
+    // that they skip composition. DrmCompositor composites them otherwise.
+    frame_flags: FrameFlags,
 }
 
 type GbmDrmCompositor =
@@ -58,10 +65,32 @@ struct OutputDevice {
     path: PathBuf,
     token: RegistrationToken,
     drm: DrmDevice,
+    gbm: GbmDevice<DrmDeviceFd>,
     gles: GlesRenderer,
+    // The node clients should allocate their buffers on, dmabuf feedback points them at it.
+    render_node: DrmNode,
+    dmabuf_global: DmabufGlobal,
+    surfaces: HashMap<crtc::Handle, Surface>,
+    // Connected connectors that are disabled in the config, still advertised as heads.
+    disabled_heads: HashMap<connector::Handle, OutputHead>
+}
+
+struct Surface {
+    output: Output,
+    global: GlobalId,
+    connector: connector::Handle,
+    serial: String,
+    dmabuf_feedback: Option<SurfaceDmabufFeedback>,
     drm_compositor: GbmDrmCompositor
 }
 
+/// Surfaces that end up on a plane get told about the formats the plane can scan out, the
+/// others only about what the renderer can import.
+struct SurfaceDmabufFeedback {
+    render: DmabufFeedback,
+    scanout: DmabufFeedback,
+}
+
 impl Backend for Tty {
     fn seat_name(&self) -> String {
         self.session.seat()
@@ -78,43 +107,57 @@ impl Backend for Tty {
     fn render(
         &mut self,
         tsuki: &mut crate::Tsuki,
-        elements: &[OutputRenderElements<GlesRenderer,
-            WaylandSurfaceRenderElement<GlesRenderer>>]
-    ) {
-        let output_device = self.output_device.as_mut().unwrap();
-
-        let res = output_device
+        output: &Output,
+        elements: &[OutputRenderElements<GlesRenderer>]
+    ) -> RenderResult {
+        let frame_flags = self.frame_flags;
+        let Some(output_device) = self.output_device.as_mut() else {
+            return RenderResult::Skipped;
+        };
+
+        let Some(surface) = output_device
+            .surfaces
+            .values_mut()
+            .find(|surface| &surface.output == output) else {
+            return RenderResult::Skipped;
+        };
+
+        let res = surface
             .drm_compositor
             .render_frame(
                 &mut output_device.gles,
                 elements,
                 [0.1, 0.1, 0.1, 1.],
-                FrameFlags::empty()
+                frame_flags
             );
 
         match res {
             Ok(res) => {
                 assert!(!res.needs_sync());
-                if !res.is_empty {
-                    output_device
-                        .drm_compositor
-                        .queue_frame(())
-                        .expect("Failed to queue frame");
-                } 
-                tsuki.waiting_for_vblank = true;
+                let states = res.states;
+                if let Some(feedback) = surface.dmabuf_feedback.as_ref() {
+                    tsuki.send_dmabuf_feedback(output, |surface| {
+                        select_dmabuf_feedback(surface, &states, &feedback.render, &feedback.scanout)
+                    });
+                }
+
+                if res.is_empty {
+                    return RenderResult::NoDamage;
+                }
+
+                match surface.drm_compositor.queue_frame(()) {
+                    Ok(()) => RenderResult::Submitted(states),
+                    Err(err) => {
+                        log::error!("error queueing frame: {err}");
+                        RenderResult::Skipped
+                    }
+                }
             },
             Err(err) => {
-                log::error!("error rendering frame: {err}")
+                log::error!("error rendering frame: {err}");
+                RenderResult::Skipped
             }
         }
-
-        tsuki.event_loop.insert_source(
-        Timer::from_duration(Duration::from_millis(6)),
-        |_, _, data| {
-            data.tsuki.queue_redraw();
-            smithay::reexports::calloop::timer::TimeoutAction::Drop
-        }).unwrap();
-
     }
 
     fn init(&mut self, tsuki: &mut Tsuki) {
@@ -143,14 +186,129 @@ impl Backend for Tty {
                         if let Err(err) = tty.device_added(device_id, path, tsuki) {
                             log::error!("error adding device: {err:?}");
                         }
-                        tsuki.queue_redraw();
+                        tsuki.queue_redraw_all();
                     },
                     udev::UdevEvent::Changed { device_id } => tty.device_changed(device_id, tsuki),
                     udev::UdevEvent::Removed { device_id } => tty.device_removed(device_id, tsuki)
                 }
             }).unwrap();
 
-        tsuki.queue_redraw();
+        tsuki.queue_redraw_all();
+    }
+
+    fn output_heads(&self) -> Vec<OutputHead> {
+        let Some(output_device) = self.output_device.as_ref() else {
+            return Vec::new();
+        };
+
+        let enabled = output_device.surfaces.values().map(|surface| {
+            let mut head = OutputHead::from_output(&surface.output);
+            head.serial = surface.serial.clone();
+            head
+        });
+        enabled
+            .chain(output_device.disabled_heads.values().cloned())
+            .collect()
+    }
+
+    fn apply_output_config(&mut self, tsuki: &mut Tsuki) -> anyhow::Result<()> {
+        let Some(output_device) = self.output_device.as_mut() else {
+            return Ok(());
+        };
+
+        let crtcs: Vec<_> = output_device.surfaces.keys().copied().collect();
+        let mut previous_modes = Vec::new();
+        for crtc in crtcs {
+            let surface = &output_device.surfaces[&crtc];
+            let physical = surface.output.physical_properties();
+            let Some(config) = tsuki
+                .config
+                .output(&surface.output.name(), &physical.make, &physical.model, &surface.serial)
+                .cloned()
+            else {
+                continue;
+            };
+
+            if !config.enabled {
+                let surface = output_device.surfaces.remove(&crtc).unwrap();
+                log::info!("disabling {}", surface.output.name());
+                tsuki.display_handle.remove_global::<Tsuki>(surface.global);
+                tsuki.remove_output(&surface.output);
+                continue;
+            }
+
+            let previous_mode = output_device.surfaces[&crtc].drm_compositor.pending_mode();
+            if let Err(err) = output_device.apply_mode(crtc, &config, tsuki) {
+                for (crtc, mode) in previous_modes {
+                    if let Err(err) = output_device.set_mode(crtc, mode, tsuki) {
+                        log::error!("error restoring mode: {err:?}");
+                    }
+                }
+                return Err(err.context(format!("error changing the mode of {}", config.name)));
+            }
+            if output_device.surfaces[&crtc].drm_compositor.pending_mode() != previous_mode {
+                previous_modes.push((crtc, previous_mode));
+            }
+
+            let surface = &output_device.surfaces[&crtc];
+            tsuki.reconfigure_output(&surface.output.clone(), &config);
+        }
+
+        // Picks up connectors that were just enabled.
+        self.scan_connectors(tsuki);
+        Ok(())
+    }
+
+    fn check_output_config(&self, configs: &[OutputConfig]) -> anyhow::Result<()> {
+        let Some(output_device) = self.output_device.as_ref() else {
+            return Err(anyhow!("no drm device"));
+        };
+
+        let enabled: Vec<_> = configs.iter().filter(|config| config.enabled).collect();
+        let crtcs = output_device.drm.resource_handles()?.crtcs().len();
+        if enabled.len() > crtcs {
+            return Err(anyhow!("{} outputs enabled, but there are only {crtcs} CRTCs", enabled.len()));
+        }
+
+        let heads = self.output_heads();
+        for config in enabled {
+            let head = heads
+                .iter()
+                .find(|head| head.name == config.name)
+                .ok_or_else(|| anyhow!("unknown output {}", config.name))?;
+
+            // Applying falls back to the preferred mode, which is not what the client asked for.
+            if let Some(mode) = config.mode {
+                let size = (mode.width as i32, mode.height as i32).into();
+                let refresh = mode.refresh.map(|hz| (hz * 1000.).round() as i32);
+                if !head
+                    .modes
+                    .iter()
+                    .any(|m| m.size == size && refresh.is_none_or(|refresh| refresh == m.refresh))
+                {
+                    return Err(anyhow!("{} has no mode {mode:?}", config.name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_output_power(&mut self, output: &Output, on: bool) {
+        let Some(surface) = self
+            .output_device
+            .as_mut()
+            .and_then(|device| device.surfaces.values_mut().find(|surface| &surface.output == output))
+        else {
+            return;
+        };
+
+        // Queueing the next frame enables the CRTC again.
+        if !on {
+            if let Err(err) = surface.drm_compositor.clear() {
+                log::error!("error turning off {}: {err}", output.name());
+            }
+        }
     }
 }
 
@@ -202,20 +360,31 @@ impl Tty {
                                 log::warn!("Failed to activate DRM device: {err}");
                             }
 
-                            tty.device_changed(id, tsuki);
+                            tty.device_reset(id, tsuki);
                         }
 
-                        tsuki.queue_redraw();
+                        tsuki.queue_redraw_all();
                     }
                 }
             }).unwrap();
         
         let primary_gpu_path = udev::primary_gpu(&seat_name).unwrap().unwrap();
 
+        let mut frame_flags = FrameFlags::DEFAULT;
+        if std::env::var_os("TSUKI_DISABLE_HW_CURSOR").is_some() {
+            log::info!("hardware cursor disabled");
+            frame_flags.remove(FrameFlags::ALLOW_CURSOR_PLANE_SCANOUT);
+        }
+        if std::env::var_os("TSUKI_DISABLE_DIRECT_SCANOUT").is_some() {
+            log::info!("direct scanout disabled");
+            frame_flags.remove(FrameFlags::ALLOW_PRIMARY_PLANE_SCANOUT | FrameFlags::ALLOW_OVERLAY_PLANE_SCANOUT);
+        }
+
         Self {
             session,
             primary_gpu_path,
-            output_device: None
+            output_device: None,
+            frame_flags,
         }
     }
 
@@ -254,48 +423,82 @@ impl Tty {
         
         let device_fd =  DrmDeviceFd::new(DeviceFd::from(fd));
 
-        let (mut drm, drm_notifier) = DrmDevice::new(device_fd.clone(), true)?;
+        let (drm, drm_notifier) = DrmDevice::new(device_fd.clone(), true)?;
         let gbm = GbmDevice::new(device_fd)?;
 
         let display = unsafe { EGLDisplay::new(gbm.clone()) }?;
         let egl_context = EGLContext::new(&display)?;
 
+        let render_node = EGLDevice::device_for_display(&display)
+            .ok()
+            .and_then(|device| device.try_get_render_node().ok().flatten())
+            .map_or_else(|| DrmNode::from_dev_id(device_id), Ok)?;
+        log::info!("render node: {render_node}");
+
         let mut gles = unsafe { GlesRenderer::new(egl_context)? };
+        // Kept for clients that only know wl_drm.
         let _ = gles.bind_wl_display(&tsuki.display_handle);
-
-        let drm_compositor = self.create_drm_compositor(&mut drm, &gbm, &gles, tsuki)?;
+        let dmabuf_global = tsuki.create_dmabuf_global(&gles);
 
         let token = tsuki
             .event_loop
             .insert_source(drm_notifier, move |event, metadata, data| {
                 let mut binding = data.backend.borrow_mut();
                 let tty = binding.as_any().downcast_mut::<Tty>().unwrap();
-                let tty = Rc::new(RefCell::new(tty));
                 match event {
-                    DrmEvent::VBlank(_crtc) => {
-                        let mut binding = tty.borrow_mut();
-                        let output_device = binding.output_device.as_mut().unwrap();
-
-                        if let Err(err) = output_device.drm_compositor.frame_submitted() {
-                            // print error message 
+                    DrmEvent::VBlank(crtc) => {
+                        let output_device = tty.output_device.as_mut().unwrap();
+                        let Some(surface) = output_device.surfaces.get_mut(&crtc) else {
+                            log::warn!("vblank for unknown crtc {crtc:?}");
+                            return;
+                        };
+
+                        if let Err(err) = surface.drm_compositor.frame_submitted() {
+                            log::error!("error marking frame as submitted: {err}");
                         }
-                        data.tsuki.waiting_for_vblank = false;
-                        data.tsuki.queue_redraw();
+
+                        let sequence = metadata.as_ref().map_or(0, |metadata| metadata.sequence as u64);
+                        let (presentation_time, flags) = match metadata.as_ref().map(|metadata| metadata.time) {
+                            Some(DrmEventTime::Monotonic(time)) => {
+                                (time, PresentationKind::Vsync | PresentationKind::HwClock | PresentationKind::HwCompletion)
+                            }
+                            // Without a hardware timestamp the time we got the event is the best guess.
+                            _ => (data.tsuki.clock.now().into(), PresentationKind::Vsync),
+                        };
+                        data.tsuki.on_vblank(&surface.output, presentation_time, sequence, flags);
                     },
                     DrmEvent::Error(error) => {log::error!("DRM error: {error}")}
                 }
             }).unwrap();
 
-        self.output_device = Some(OutputDevice { id: device_id, path, token, drm, gles, drm_compositor });
+        self.output_device = Some(OutputDevice {
+            id: device_id,
+            path,
+            token,
+            drm,
+            gbm,
+            gles,
+            render_node,
+            dmabuf_global,
+            surfaces: HashMap::new(),
+            disabled_heads: HashMap::new(),
+        });
+
+        self.scan_connectors(tsuki);
 
         Ok(())
     }
 
     fn device_changed(&mut self, device_id: dev_t, tsuki: &mut Tsuki) {
+        if self.output_device.as_ref().is_some_and(|device| device.id == device_id) {
+            log::info!("output device changed");
+            self.scan_connectors(tsuki);
+        }
+    }
+
+    fn device_reset(&mut self, device_id: dev_t, tsuki: &mut Tsuki) {
         if let Some(output_device) = &self.output_device {
             if output_device.id == device_id {
-                log::info!("output device changed");
-
                 let path = output_device.path.clone();
                 self.device_removed(device_id, tsuki);
 
@@ -313,29 +516,37 @@ impl Tty {
                 return;
             }
 
+            for (_, surface) in output_device.surfaces.drain() {
+                tsuki.display_handle.remove_global::<Tsuki>(surface.global);
+                tsuki.remove_output(&surface.output);
+            }
+
             tsuki.event_loop.remove(output_device.token);
-            tsuki.output = None;
+            tsuki
+                .dmabuf_state
+                .destroy_global::<Tsuki>(&tsuki.display_handle, output_device.dmabuf_global);
             output_device.gles.unbind_wl_display();
         }
     }
 
-    fn create_drm_compositor(
-        &mut self,
-        drm: &mut DrmDevice,
-        gbm: &GbmDevice<DrmDeviceFd>,
-        gles: &GlesRenderer,
-        tsuki: &mut Tsuki
-    ) -> anyhow::Result<GbmDrmCompositor> {
-        let formats = Bind::<Dmabuf>::supported_formats(gles)
-            .ok_or_else(|| anyhow!("no supported formats"))?;
+    /// Brings the surfaces in sync with the connectors, called on startup and on hotplug.
+    fn scan_connectors(&mut self, tsuki: &mut Tsuki) {
+        let Some(output_device) = self.output_device.as_mut() else {
+            return;
+        };
 
-        let resources = drm.resource_handles()?;
+        let resources = match output_device.drm.resource_handles() {
+            Ok(resources) => resources,
+            Err(err) => {
+                log::error!("error getting drm resources: {err}");
+                return;
+            }
+        };
 
-        let mut connector = None;
-        resources
+        let connectors: Vec<_> = resources
             .connectors()
             .iter()
-            .filter_map(|conn| match drm.get_connector(*conn, true) {
+            .filter_map(|conn| match output_device.drm.get_connector(*conn, true) {
                 Ok(info) => Some(info),
                 Err(err) => {
                     log::error!("error probing connector: {err}");
@@ -351,39 +562,105 @@ impl Tty {
                     conn.modes().len()
                 );
             })
-            .filter(|conn| conn.state() == ConnectorState::Connected)
-            .filter(|conn| conn.interface() == ConnectorInterface::EmbeddedDisplayPort)
-            .for_each(|conn| connector = Some(conn));
+            .collect();
+
+        let disconnected: Vec<_> = output_device
+            .surfaces
+            .iter()
+            .filter(|(_, surface)| {
+                !connectors.iter().any(|conn| {
+                    conn.handle() == surface.connector && conn.state() == ConnectorState::Connected
+                })
+            })
+            .map(|(crtc, _)| *crtc)
+            .collect();
+
+        for crtc in disconnected {
+            let surface = output_device.surfaces.remove(&crtc).unwrap();
+            log::info!("disconnecting {}", surface.output.name());
+            tsuki.display_handle.remove_global::<Tsuki>(surface.global);
+            tsuki.remove_output(&surface.output);
+        }
+
+        output_device.disabled_heads.clear();
+        for connector in connectors {
+            if connector.state() != ConnectorState::Connected
+                || output_device.surfaces.values().any(|surface| surface.connector == connector.handle())
+            {
+                continue;
+            }
 
-        let connector = connector.ok_or_else(|| anyhow!("no compatible connector"))?;
-        log::info!(
-            "picking connector: {} {}",
+            if let Err(err) = output_device.connector_connected(connector, &resources, tsuki) {
+                log::error!("error connecting connector: {err:?}");
+            }
+        }
+
+        tsuki.queue_redraw_all();
+
+        let heads = self.output_heads();
+        tsuki.output_management_state.notify_changes::<Tsuki>(heads);
+    }
+}
+
+impl OutputDevice {
+    fn connector_connected(
+        &mut self,
+        connector: connector::Info,
+        resources: &ResourceHandles,
+        tsuki: &mut Tsuki,
+    ) -> anyhow::Result<()> {
+        let output_name = format!(
+            "{}-{}",
             connector.interface().as_str(),
             connector.interface_id()
         );
 
-        let mut mode = connector.modes().first();
-        connector.modes().iter().for_each(|m| {
-            log::info!("mode: {m:?}");
+        let info = display_info::for_connector(&self.drm, connector.handle());
+        let make = info.as_ref().and_then(|info| info.make()).unwrap_or_else(|| "Unknown".to_string());
+        let model = info.as_ref().and_then(|info| info.model()).unwrap_or_else(|| "Unknown".to_string());
+        let serial = info.as_ref().and_then(|info| info.serial()).unwrap_or_default();
+
+        let config = tsuki.config.output(&output_name, &make, &model, &serial).cloned();
+        if config.as_ref().is_some_and(|config| !config.enabled) {
+            log::info!("{output_name} is disabled in the config, skipping");
+
+            let modes: Vec<_> = connector.modes().iter().map(|m| Mode::from(*m)).collect();
+            let preferred_mode = connector
+                .modes()
+                .iter()
+                .find(|m| m.mode_type().contains(ModeTypeFlags::PREFERRED))
+                .map(|m| Mode::from(*m));
+            let (physical_width, physical_height) = connector.size().unwrap_or((0, 0));
+            self.disabled_heads.insert(connector.handle(), OutputHead {
+                description: format!("{make} {model} {serial}").trim_end().to_string(),
+                name: output_name,
+                make,
+                model,
+                serial,
+                physical_size: (physical_width as i32, physical_height as i32),
+                modes,
+                preferred_mode,
+                custom_modes: false,
+                state: None,
+            });
+            return Ok(());
+        }
 
-            if m.mode_type().contains(ModeTypeFlags::PREFERRED) && mode
-                    .map(|curr| curr.vrefresh() < m.vrefresh())
-                    .unwrap_or(true) {
-                mode = Some(m);
-            }
-        });
-        let mode = mode.ok_or_else(|| anyhow!("no mode"))?;
+        log::info!("picking connector: {output_name}");
+
+        let mode = pick_mode(&connector, config.as_ref().and_then(|config| config.mode))
+            .ok_or_else(|| anyhow!("no mode"))?;
         log::info!("picking mode: {mode:?}");
 
         let encoders = connector.encoders().iter()
-            .filter_map(|enc| drm.get_encoder(*enc).ok());
+            .filter_map(|enc| self.drm.get_encoder(*enc).ok());
 
         let mut all_crtcs = Vec::new();
 
         for enc in encoders {
             let mut crtcs = resources.filter_crtcs(enc.possible_crtcs());
         
-            crtcs.sort_by_cached_key(|crtc| match drm.planes(crtc) {
+            crtcs.sort_by_cached_key(|crtc| match self.drm.planes(crtc) {
                 Ok(planes) => -(planes.overlay.len() as isize),
                 Err(err) => {
                     log::error!("error probing planes for CRTC: {err}");
@@ -394,59 +671,186 @@ impl Tty {
             all_crtcs.extend(crtcs);
         }
 
-        let surface = all_crtcs.into_iter().find_map(|crtc| match drm.create_surface(crtc, *mode, &[connector.handle()]) {
-            Ok(surface) => Some(surface),
-            Err(err) => {
-                log::error!("error creating drm surface: {err}");
-                None
-            }
-        });
+        let surface = all_crtcs
+            .into_iter()
+            .filter(|crtc| !self.surfaces.contains_key(crtc))
+            .find_map(|crtc| match self.drm.create_surface(crtc, mode, &[connector.handle()]) {
+                Ok(surface) => Some(surface),
+                Err(err) => {
+                    log::error!("error creating drm surface: {err}");
+                    None
+                }
+            });
         
         let surface = surface.ok_or_else(|| anyhow!("no surface"))?;
+        let crtc = surface.crtc();
+
+        let formats = Bind::<Dmabuf>::supported_formats(&self.gles)
+            .ok_or_else(|| anyhow!("no supported formats"))?;
 
         let gbm_flags = GbmBufferFlags::RENDERING | GbmBufferFlags::SCANOUT;
-        let allocator = GbmAllocator::new(gbm.clone(), gbm_flags);
+        let allocator = GbmAllocator::new(self.gbm.clone(), gbm_flags);
 
         let (physical_width, physical_height) = connector.size().unwrap_or((0, 0));
-        let output_name = format!(
-            "{}-{}",
-            connector.interface().as_str(),
-            connector.interface_id()
-        );
-
-        let (make, model) = display_info::for_connector(drm, connector.handle())
-            .map(|info| (info.make(), info.model()))
-            .unwrap_or_else(|| (Some("Unknown".to_string()), Some("Unknown".to_string())));
+        let output_serial = serial.clone();
 
         let output = Output::new(
             output_name,
             PhysicalProperties {
                 size: (physical_width as i32, physical_height as i32).into(),
                 subpixel: Subpixel::Unknown,
-                model: model.unwrap(),
-                make: make.unwrap()      
+                model,
+                make
             } 
         );
 
-        let wl_mode = Mode::from(*mode);
-        output.change_current_state(Some(wl_mode), None, None, Some((0, 0).into()));
-        output.set_preferred(wl_mode);
+        for m in connector.modes() {
+            output.add_mode(Mode::from(*m));
+        }
 
-        let _global = output.create_global::<Tsuki>(&tsuki.display_handle);
-        tsuki.space.map_output(&output, (0, 0));
-        tsuki.output = Some(output.clone());
+        let wl_mode = Mode::from(mode);
+        let preferred = connector
+            .modes()
+            .iter()
+            .find(|m| m.mode_type().contains(ModeTypeFlags::PREFERRED))
+            .map(|m| Mode::from(*m))
+            .unwrap_or(wl_mode);
+        output.set_preferred(preferred);
+        output.change_current_state(
+            Some(wl_mode),
+            config.as_ref().and_then(|config| config.transform()),
+            config.as_ref().and_then(|config| config.scale()),
+            None,
+        );
 
-        let compositor = DrmCompositor::new(
-            OutputModeSource::Auto(output),
+        let global = output.create_global::<Tsuki>(&tsuki.display_handle);
+        tsuki.add_output(
+            output.clone(),
+            mode_refresh_interval(wl_mode),
+            config.as_ref().and_then(|config| config.position()),
+        );
+
+        let dmabuf_feedback = match self.surface_dmabuf_feedback(&surface) {
+            Ok(feedback) => Some(feedback),
+            Err(err) => {
+                log::warn!("error building dmabuf feedback for {}: {err:?}", output.name());
+                None
+            }
+        };
+
+        let drm_compositor = DrmCompositor::new(
+            OutputModeSource::Auto(output.clone()),
             surface, 
             None,
             allocator,
-            gbm.clone(), 
+            self.gbm.clone(), 
             SUPPORTED_COLOR_FORMATS.iter().copied(),
             formats, 
-            drm.cursor_size(),
-            Some(gbm.clone()) 
+            self.drm.cursor_size(),
+            Some(self.gbm.clone()) 
         )?;
-        Ok(compositor)
+
+        self.surfaces.insert(crtc, Surface {
+            output,
+            global,
+            connector: connector.handle(),
+            serial: output_serial,
+            dmabuf_feedback,
+            drm_compositor,
+        });
+
+        Ok(())
+    }
+
+    /// Feedback for the surfaces shown on a CRTC. The scanout one lists the formats its planes
+    /// can scan out first, so that clients that can allocate them give us a chance at direct
+    /// scanout.
+    fn surface_dmabuf_feedback(&self, surface: &DrmSurface) -> anyhow::Result<SurfaceDmabufFeedback> {
+        let render_formats = self.gles.dmabuf_formats();
+        let planes = surface.planes();
+        let plane_formats: FormatSet = surface
+            .plane_info()
+            .formats
+            .iter()
+            .chain(planes.overlay.iter().flat_map(|plane| plane.formats.iter()))
+            .copied()
+            .collect();
+        let scanout_formats: FormatSet = plane_formats.intersection(&render_formats).copied().collect();
+
+        let builder = DmabufFeedbackBuilder::new(self.render_node.dev_id(), render_formats.clone());
+        let render = builder.clone().build()?;
+        let scanout = builder
+            .add_preference_tranche(self.id, Some(TrancheFlags::Scanout), scanout_formats)
+            .add_preference_tranche(self.render_node.dev_id(), None, render_formats)
+            .build()?;
+        Ok(SurfaceDmabufFeedback { render, scanout })
+    }
+
+    fn apply_mode(&mut self, crtc: crtc::Handle, config: &OutputConfig, tsuki: &mut Tsuki) -> anyhow::Result<()> {
+        let Some(mode_config) = config.mode else {
+            return Ok(());
+        };
+
+        let surface = self.surfaces.get_mut(&crtc).unwrap();
+        let connector = self.drm.get_connector(surface.connector, false)?;
+        let mode = pick_mode(&connector, Some(mode_config)).ok_or_else(|| anyhow!("no mode"))?;
+        if mode == surface.drm_compositor.pending_mode() {
+            return Ok(());
+        }
+
+        self.set_mode(crtc, mode, tsuki)
+    }
+
+    fn set_mode(&mut self, crtc: crtc::Handle, mode: DrmMode, tsuki: &mut Tsuki) -> anyhow::Result<()> {
+        let surface = self.surfaces.get_mut(&crtc).unwrap();
+        log::info!("switching {} to {mode:?}", surface.output.name());
+        surface.drm_compositor.use_mode(mode)?;
+
+        let wl_mode = Mode::from(mode);
+        surface.output.change_current_state(Some(wl_mode), None, None, None);
+        tsuki.set_refresh_interval(&surface.output, mode_refresh_interval(wl_mode));
+
+        Ok(())
+    }
+}
+
+/// Picks the configured mode if the connector has it, otherwise the preferred mode with the
+/// highest refresh rate.
+fn pick_mode(connector: &connector::Info, config: Option<ModeConfig>) -> Option<DrmMode> {
+    for m in connector.modes() {
+        log::info!("mode: {m:?}");
+    }
+
+    if let Some(config) = config {
+        let refresh = |m: &DrmMode| Mode::from(*m).refresh;
+        let mut matching: Vec<_> = connector
+            .modes()
+            .iter()
+            .filter(|m| m.size() == (config.width, config.height))
+            .collect();
+
+        match config.refresh {
+            Some(hz) => {
+                let target = (hz * 1000.).round() as i32;
+                matching.sort_by_key(|m| (refresh(m) - target).abs());
+            }
+            None => matching.sort_by_key(|m| -refresh(m)),
+        }
+
+        if let Some(mode) = matching.first() {
+            return Some(**mode);
+        }
+
+        log::warn!("configured mode {config:?} not found, using the preferred mode");
     }
+
+    let mut mode = connector.modes().first();
+    connector.modes().iter().for_each(|m| {
+        if m.mode_type().contains(ModeTypeFlags::PREFERRED) && mode
+                .map(|curr| curr.vrefresh() < m.vrefresh())
+                .unwrap_or(true) {
+            mode = Some(m);
+        }
+    });
+    mode.copied()
 }
\ No newline at end of file
@@ -1,23 +1,32 @@
-use std::{any::Any, cell::RefCell, rc::Rc, time::Duration};
+use std::{any::Any, time::Duration};
 
+use anyhow::anyhow;
 use smithay::{
     backend::{
-        renderer::{
-            damage::OutputDamageTracker, element::surface::WaylandSurfaceRenderElement, gles::GlesRenderer,
-        },
-        winit::{self, WinitEvent, WinitEventLoop, WinitGraphicsBackend},
+        renderer::{damage::OutputDamageTracker, gles::GlesRenderer},
+        winit::{self, WinitEvent, WinitGraphicsBackend},
     },
     output::{Mode, Output, PhysicalProperties, Subpixel},
-    reexports::{calloop::{timer::{TimeoutAction, Timer}, EventLoop, LoopHandle}, winit::platform::pump_events::PumpStatus},
-    utils::{Point, Rectangle, Transform},
+    reexports::{calloop::LoopHandle, wayland_server::backend::GlobalId},
+    utils::Transform,
 };
 
-use crate::{backend::Backend, state::{OutputRenderElements, Tsuki}, CalloopData};
+use crate::{
+    backend::{Backend, RenderResult},
+    config::{OutputConfig, TransformConfig},
+    protocols::output_management::OutputHead,
+    state::{OutputRenderElements, Tsuki},
+    CalloopData,
+};
+
+const REFRESH_RATE: i32 = 60_000;
 
 pub struct Winit {
     output: Output,
+    // The transform from the config, without the flip needed for GL.
+    transform: Transform,
+    global: Option<GlobalId>,
     backend: WinitGraphicsBackend<GlesRenderer>,
-    winit_event_loop: WinitEventLoop,
     damage_tracker: OutputDamageTracker
 }
 
@@ -33,23 +42,134 @@ impl Backend for Winit {
     fn render(
         &mut self,
         tsuki: &mut Tsuki,
-        elements: &[OutputRenderElements<GlesRenderer,
-            WaylandSurfaceRenderElement<GlesRenderer>>]
-    ) {
-        let size = self.backend.window_size();
-        let damage = Rectangle::new(Point::from((0, 0)), size);
-        self.damage_tracker
-            .render_output(self.backend.renderer(), 0, elements, [0.0, 0.0, 0.0, 1.0])
-            .unwrap();
-        self.backend.submit(Some(&[damage])).unwrap()
+        output: &Output,
+        elements: &[OutputRenderElements<GlesRenderer>]
+    ) -> RenderResult {
+        if let Err(err) = self.backend.bind() {
+            log::error!("error binding winit backend: {err}");
+            return RenderResult::Skipped;
+        }
+
+        // The buffer age tells the damage tracker how many frames old the contents of the back
+        // buffer are, so it only has to repaint what changed since then.
+        let age = self.backend.buffer_age().unwrap_or(0);
+        let res = match self
+            .damage_tracker
+            .render_output(self.backend.renderer(), age, elements, [0.0, 0.0, 0.0, 1.0]) {
+            Ok(res) => res,
+            Err(err) => {
+                log::error!("error rendering frame: {err}");
+                return RenderResult::Skipped;
+            }
+        };
+
+        let Some(damage) = res.damage else {
+            return RenderResult::NoDamage;
+        };
+        let states = res.states;
+
+        if let Err(err) = self.backend.submit(Some(damage)) {
+            log::error!("error submitting frame: {err}");
+            return RenderResult::Skipped;
+        }
+
+        // The host compositor doesn't tell us when the frame hits the screen.
+        tsuki.simulate_vblank(output);
+
+        RenderResult::Submitted(states)
     }
     
     fn init(&mut self, tsuki: &mut Tsuki) {
-        let _global = self.output.create_global::<Tsuki>(&tsuki.display_handle);
-            tsuki.space.map_output(&self.output, (0, 0));
-            tsuki.output = Some(self.output.clone());
+        tsuki.create_dmabuf_global(self.backend.renderer());
+        self.global = Some(self.output.create_global::<Tsuki>(&tsuki.display_handle));
+        tsuki.add_output(self.output.clone(), Some(refresh_interval()), None);
+        if let Err(err) = self.apply_output_config(tsuki) {
+            log::error!("error applying output config: {err:?}");
+        }
     }
-    
+
+    fn output_heads(&self) -> Vec<OutputHead> {
+        let mut head = OutputHead::from_output(&self.output);
+        head.state = head.state.filter(|_| self.global.is_some()).map(|mut state| {
+            state.transform = self.transform;
+            state
+        });
+        vec![head]
+    }
+
+    fn apply_output_config(&mut self, tsuki: &mut Tsuki) -> anyhow::Result<()> {
+        // The mode follows the window size, everything else can come from the config.
+        let physical = self.output.physical_properties();
+        let config = tsuki
+            .config
+            .output(&self.output.name(), &physical.make, &physical.model, "")
+            .cloned();
+
+        match config {
+            Some(config) if !config.enabled => {
+                if let Some(global) = self.global.take() {
+                    tsuki.display_handle.remove_global::<Tsuki>(global);
+                    tsuki.remove_output(&self.output);
+                }
+            }
+            Some(mut config) => {
+                if self.global.is_none() {
+                    self.global = Some(self.output.create_global::<Tsuki>(&tsuki.display_handle));
+                    tsuki.add_output(self.output.clone(), Some(refresh_interval()), None);
+                }
+
+                if let Some(transform) = config.transform() {
+                    self.transform = transform;
+                }
+                // GL renders upside down, so the configured transform goes on top of the flip.
+                config.transform = Some(TransformConfig(Transform::Flipped180 + self.transform));
+                tsuki.reconfigure_output(&self.output, &config);
+            }
+            None => (),
+        }
+
+        tsuki.output_management_state.notify_changes::<Tsuki>(self.output_heads());
+        Ok(())
+    }
+
+    fn check_output_config(&self, configs: &[OutputConfig]) -> anyhow::Result<()> {
+        // The mode follows the window size, clients can't pick another one.
+        let size = self.output.current_mode().map(|mode| mode.size);
+        for config in configs.iter().filter(|config| config.enabled) {
+            if let Some(mode) = config.mode {
+                if size != Some((mode.width as i32, mode.height as i32).into()) {
+                    return Err(anyhow!("the winit output can't change its mode"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_output_power(&mut self, _output: &Output, on: bool) {
+        // A blank window stands in for a screen that is off.
+        if on {
+            return;
+        }
+        if let Err(err) = self.backend.bind() {
+            log::error!("error binding winit backend: {err}");
+            return;
+        }
+
+        let age = self.backend.buffer_age().unwrap_or(0);
+        let elements: &[OutputRenderElements<GlesRenderer>] = &[];
+        match self
+            .damage_tracker
+            .render_output(self.backend.renderer(), age, elements, [0.0, 0.0, 0.0, 1.0])
+        {
+            Ok(res) => {
+                if let Err(err) = self.backend.submit(res.damage.map(Vec::as_slice)) {
+                    log::error!("error submitting frame: {err}");
+                }
+            }
+            Err(err) => log::error!("error rendering frame: {err}"),
+        }
+    }
+
     fn as_any (&mut self) -> &mut dyn Any {
         self
     }
@@ -63,7 +183,7 @@ impl Winit {
 
         let mode = Mode {
             size: backend.window_size(),
-            refresh: 60_000
+            refresh: REFRESH_RATE
         };
 
         let output = Output::new(
@@ -86,59 +206,49 @@ impl Winit {
 
         let damage_tracker = OutputDamageTracker::from_output(&output);
 
-        let timer = Timer::immediate();
-
         event_loop
-            .insert_source(timer, move |_, _, data| {
-                let mut binding = data.backend.borrow_mut();
-                let binding = binding.as_any().downcast_mut::<Winit>();
-
-                if binding.is_none() {
-                    return TimeoutAction::Drop
+            .insert_source(winit_event_loop, move |event, _, data| {
+                let tsuki = &mut data.tsuki;
+                match event {
+                    WinitEvent::Resized { size, .. } => {
+                        let mut backend = data.backend.borrow_mut();
+                        let winit = backend.as_any().downcast_mut::<Winit>().unwrap();
+                        let output = winit.output.clone();
+
+                        let mode = Mode {
+                            size,
+                            refresh: REFRESH_RATE
+                        };
+                        for old_mode in output.modes() {
+                            output.delete_mode(old_mode);
+                        }
+                        output.change_current_state(
+                            Some(mode), 
+                            None, 
+                            None, 
+                        None);
+                        output.set_preferred(mode);
+
+                        tsuki.queue_redraw(&output);
+                        tsuki.output_management_state.notify_changes::<Tsuki>(winit.output_heads());
+                    },
+                    WinitEvent::CloseRequested => {tsuki.loop_signal.stop();}, 
+                    WinitEvent::Input(event) => tsuki.process_input_event(event),
+                    WinitEvent::Focus(_) => (),
+                    WinitEvent::Redraw => tsuki.queue_redraw_all(),
                 }
-                // println!("Type id: {:?}", data.backend.borrow_mut().as_any().type_id());
-                // println!("Expected: {:?}", std::any::TypeId::of::<Rc<RefCell<Winit>>>());
-                let backend = binding.unwrap();
-                backend.dispatch(&mut data.tsuki);
-                
-                smithay::reexports::calloop::timer::TimeoutAction::ToDuration(Duration::from_millis(16))
             }).unwrap();
         
         Self {
             output,
+            transform: Transform::Normal,
+            global: None,
             backend,
-            winit_event_loop,
             damage_tracker
         }
     }
+}
 
-    fn dispatch(&mut self, tsuki: &mut Tsuki) {
-        let res = self
-            .winit_event_loop
-            .dispatch_new_events(|event| match event {
-                WinitEvent::Resized { size, scale_factor } => {
-                    tsuki.output.as_ref().unwrap().change_current_state(
-                        Some(Mode {
-                            size,
-                            refresh: 60_000
-                        }), 
-                        None, 
-                        None, 
-                    None);
-                },
-                WinitEvent::CloseRequested => {tsuki.loop_signal.stop();}, 
-                WinitEvent::Input(event) => tsuki.process_input_event(event),
-                WinitEvent::Focus(_) => (),
-                WinitEvent::Redraw => tsuki.queue_redraw(),
-                _ => ()
-            });
-        
-        if let PumpStatus::Exit(val)= res {
-            tsuki.loop_signal.stop();
-            return;
-        } 
-
-        self.backend.bind().unwrap();
-        tsuki.queue_redraw();
-    }
+fn refresh_interval() -> Duration {
+    Duration::from_micros(1_000_000_000 / REFRESH_RATE as u64)
 }
\ No newline at end of file
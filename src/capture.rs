@@ -0,0 +1,429 @@
+use std::{collections::HashMap, mem, ptr, time::Duration};
+
+use anyhow::{bail, Context};
+use nix::libc::dev_t;
+use smithay::{
+    backend::{
+        allocator::{Buffer, Format, Fourcc},
+        renderer::{
+            damage::OutputDamageTracker,
+            element::utils::{Relocate, RelocateRenderElement},
+            gles::{GlesRenderer, GlesTexture},
+            ExportMem, Offscreen, Unbind,
+        },
+    },
+    desktop::Window,
+    output::Output,
+    reexports::{
+        wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_frame_v1::FailureReason,
+        wayland_server::{
+            backend::ObjectId,
+            protocol::{wl_buffer::WlBuffer, wl_shm},
+        },
+    },
+    utils::{Buffer as BufferCoords, Physical, Point, Rectangle, Scale, Size, Transform},
+    wayland::{
+        dmabuf::get_dmabuf,
+        shm::{with_buffer_contents, with_buffer_contents_mut},
+    },
+};
+
+use crate::{
+    backend::Backend,
+    protocols::{
+        image_copy_capture::{ImageCaptureSource, ImageCopyFrame},
+        screencopy::ScreencopyFrame,
+    },
+    state::OutputRenderElements,
+    Tsuki,
+};
+
+/// Formats shm capture buffers can have, both are filled the same way.
+pub const SHM_FORMATS: [wl_shm::Format; 2] = [wl_shm::Format::Xrgb8888, wl_shm::Format::Argb8888];
+
+/// The buffers a capture can be copied into.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BufferConstraints {
+    pub size: Size<i32, BufferCoords>,
+    pub dmabuf: Option<DmabufConstraints>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DmabufConstraints {
+    /// The render node dmabufs need to be allocated on.
+    pub device: dev_t,
+    pub formats: Vec<Format>,
+}
+
+impl BufferConstraints {
+    pub fn check_buffer(&self, buffer: &WlBuffer) -> bool {
+        if let Ok(dmabuf) = get_dmabuf(buffer) {
+            return dmabuf.size() == self.size
+                && self
+                    .dmabuf
+                    .as_ref()
+                    .is_some_and(|constraints| constraints.formats.contains(&dmabuf.format()));
+        }
+
+        with_buffer_contents(buffer, |_, _, data| {
+            SHM_FORMATS.contains(&data.format)
+                && data.width == self.size.w
+                && data.height == self.size.h
+                && data.stride >= self.size.w * 4
+        })
+        .unwrap_or(false)
+    }
+}
+
+pub enum CaptureFrame {
+    Screencopy(ScreencopyFrame),
+    ImageCopy(ImageCopyFrame),
+}
+
+impl CaptureFrame {
+    fn buffer(&self) -> &WlBuffer {
+        match self {
+            CaptureFrame::Screencopy(frame) => &frame.buffer,
+            CaptureFrame::ImageCopy(frame) => &frame.buffer,
+        }
+    }
+
+    fn paint_cursor(&self) -> bool {
+        match self {
+            CaptureFrame::Screencopy(frame) => frame.overlay_cursor,
+            CaptureFrame::ImageCopy(frame) => frame.paint_cursors,
+        }
+    }
+
+    fn wait_for_damage(&self) -> bool {
+        match self {
+            CaptureFrame::Screencopy(frame) => frame.with_damage,
+            CaptureFrame::ImageCopy(frame) => frame.wait_for_damage,
+        }
+    }
+
+    /// Damage is reported relative to the previous capture with the same key.
+    fn damage_key(&self) -> ObjectId {
+        match self {
+            CaptureFrame::Screencopy(frame) => frame.manager.clone(),
+            CaptureFrame::ImageCopy(frame) => frame.session_id(),
+        }
+    }
+
+    fn target(&self, tsuki: &Tsuki) -> Option<CaptureTarget> {
+        let (output, region) = match self {
+            CaptureFrame::Screencopy(frame) => (frame.output.clone(), Some(frame.region)),
+            CaptureFrame::ImageCopy(frame) => match &frame.source {
+                ImageCaptureSource::Output(output) => (output.upgrade()?, None),
+                ImageCaptureSource::Toplevel(handle) => {
+                    let handle = handle.upgrade().filter(|handle| !handle.is_closed())?;
+                    let window = tsuki.window_for_foreign_toplevel(&handle)?;
+                    let (bbox, scale) = tsuki.window_capture_geometry(&window);
+                    return Some(CaptureTarget::Window { window, bbox, scale });
+                }
+            },
+        };
+
+        if !tsuki.space.outputs().any(|o| o == &output) {
+            return None;
+        }
+        let mode = output.current_mode()?;
+        let output_rect = Rectangle::from_size(output.current_transform().transform_size(mode.size));
+        let region = region.unwrap_or(output_rect);
+        output_rect
+            .contains_rect(region)
+            .then_some(CaptureTarget::Output { output, region })
+    }
+
+    fn success(self, transform: Transform, damage: &[Rectangle<i32, BufferCoords>], time: Duration) {
+        match self {
+            CaptureFrame::Screencopy(frame) => frame.success(damage, time),
+            CaptureFrame::ImageCopy(frame) => frame.success(transform, damage, time),
+        }
+    }
+
+    fn failed(self) {
+        match self {
+            CaptureFrame::Screencopy(frame) => frame.failed(),
+            CaptureFrame::ImageCopy(frame) => frame.failed(FailureReason::Unknown),
+        }
+    }
+
+    /// For captures whose source went away.
+    fn stopped(self) {
+        match self {
+            CaptureFrame::Screencopy(frame) => frame.failed(),
+            CaptureFrame::ImageCopy(frame) => frame.failed(FailureReason::Stopped),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum CaptureTarget {
+    /// An area of an output, in physical output coordinates.
+    Output {
+        output: Output,
+        region: Rectangle<i32, Physical>,
+    },
+    /// A window with its popups, regardless of what covers it on screen.
+    Window {
+        window: Window,
+        bbox: Rectangle<i32, Physical>,
+        scale: f64,
+    },
+}
+
+impl CaptureTarget {
+    fn size(&self) -> Size<i32, Physical> {
+        match self {
+            CaptureTarget::Output { region, .. } => region.size,
+            CaptureTarget::Window { bbox, .. } => bbox.size,
+        }
+    }
+
+    fn scale(&self) -> Scale<f64> {
+        match self {
+            CaptureTarget::Output { output, .. } => output.current_scale().fractional_scale().into(),
+            CaptureTarget::Window { scale, .. } => (*scale).into(),
+        }
+    }
+
+    /// Windows are captured upright, outputs in the orientation of their buffers.
+    fn transform(&self) -> Transform {
+        match self {
+            CaptureTarget::Output { output, .. } => output.current_transform(),
+            CaptureTarget::Window { .. } => Transform::Normal,
+        }
+    }
+}
+
+struct PendingCapture {
+    frame: CaptureFrame,
+    /// Set when something was redrawn since the capture last found no damage.
+    check: bool,
+}
+
+struct CaptureDamage {
+    tracker: OutputDamageTracker,
+    target: CaptureTarget,
+    scale: Scale<f64>,
+    transform: Transform,
+}
+
+/// Captures waiting to be rendered, shared by wlr-screencopy and ext-image-copy-capture.
+#[derive(Default)]
+pub struct CaptureState {
+    /// Set once the renderer is known, without it only shm buffers are offered.
+    pub dmabuf: Option<DmabufConstraints>,
+    pending: Vec<PendingCapture>,
+    damage: HashMap<ObjectId, CaptureDamage>,
+}
+
+impl CaptureState {
+    pub fn queue(&mut self, frame: CaptureFrame) {
+        self.pending.push(PendingCapture { frame, check: true });
+    }
+
+    /// Lets the captures waiting for damage look again, after something was redrawn or committed.
+    pub fn check_damage(&mut self) {
+        for capture in &mut self.pending {
+            capture.check = true;
+        }
+    }
+}
+
+impl Tsuki {
+    pub fn process_captures(&mut self, backend: &mut dyn Backend) {
+        if !self.capture_state.pending.iter().any(|capture| capture.check) {
+            return;
+        }
+        let Some(renderer) = backend.renderer() else {
+            return;
+        };
+
+        // Drop the damage of managers and sessions that are gone.
+        let handle = self.display_handle.backend_handle();
+        self.capture_state
+            .damage
+            .retain(|id, _| handle.object_info(id.clone()).is_ok());
+
+        for capture in mem::take(&mut self.capture_state.pending) {
+            if !capture.check {
+                self.capture_state.pending.push(capture);
+                continue;
+            }
+
+            if let Some(frame) = self.capture(renderer, capture.frame) {
+                self.capture_state.pending.push(PendingCapture { frame, check: false });
+            }
+        }
+    }
+
+    /// The area a window capture covers relative to the window's surface, and the scale of the
+    /// output the window is on.
+    pub fn window_capture_geometry(&self, window: &Window) -> (Rectangle<i32, Physical>, f64) {
+        let scale = self
+            .output_for_window(window)
+            .map_or(1.0, |output| output.current_scale().fractional_scale());
+        let bbox = window.bbox_with_popups().to_f64().to_physical_precise_round(scale);
+        (bbox, scale)
+    }
+
+    /// Copies the frame's target into its buffer, returns the frame when it still has to wait
+    /// for damage.
+    fn capture(&mut self, renderer: &mut GlesRenderer, frame: CaptureFrame) -> Option<CaptureFrame> {
+        let Some(target) = frame.target(self) else {
+            frame.stopped();
+            return None;
+        };
+        // Windows don't show through the lock screen, their captures wait for the unlock.
+        if self.is_locked() && matches!(target, CaptureTarget::Window { .. }) {
+            return Some(frame);
+        }
+        let scale = target.scale();
+        let transform = target.transform();
+        let size = target.size();
+
+        let elements = match &target {
+            CaptureTarget::Output { output, region } => {
+                let mut elements = Vec::new();
+                if frame.paint_cursor() {
+                    elements.extend(self.pointer_render_elements(renderer, output));
+                }
+                elements.extend(self.output_elements(renderer, output));
+                elements
+                    .into_iter()
+                    .map(|element| {
+                        RelocateRenderElement::from_element(element, region.loc.upscale(-1), Relocate::Relative)
+                    })
+                    .collect()
+            }
+            CaptureTarget::Window { window, bbox, scale } => {
+                let (mut elements, window_elements) =
+                    self.window_elements(renderer, window, Point::default(), *scale);
+                elements.extend(window_elements);
+                elements
+                    .into_iter()
+                    .map(|element| {
+                        RelocateRenderElement::from_element(element, bbox.loc.upscale(-1), Relocate::Relative)
+                    })
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let key = frame.damage_key();
+        let damage = match self.capture_state.damage.get_mut(&key) {
+            Some(damage)
+                if damage.target == target
+                    && damage.scale == scale
+                    && damage.transform == transform =>
+            {
+                damage
+            }
+            _ => {
+                let tracker = OutputDamageTracker::new(transform.transform_size(size), scale, transform);
+                self.capture_state.damage.insert(
+                    key.clone(),
+                    CaptureDamage {
+                        tracker,
+                        target,
+                        scale,
+                        transform,
+                    },
+                );
+                self.capture_state.damage.get_mut(&key).unwrap()
+            }
+        };
+
+        let damage: Vec<_> = match damage.tracker.damage_output(1, &elements) {
+            Ok((Some(damage), _)) => damage
+                .iter()
+                .map(|rect| {
+                    rect.to_logical(1)
+                        .to_buffer(1, transform, &size.to_logical(1))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        if frame.wait_for_damage() && damage.is_empty() {
+            return Some(frame);
+        }
+
+        let size = transform.transform_size(size);
+        match render_to_buffer(renderer, frame.buffer(), size, scale, transform, &elements) {
+            Ok(()) => frame.success(transform, &damage, self.clock.now().into()),
+            Err(err) => {
+                log::warn!("error capturing: {err:?}");
+                frame.failed();
+            }
+        }
+        None
+    }
+}
+
+fn render_to_buffer(
+    renderer: &mut GlesRenderer,
+    buffer: &WlBuffer,
+    size: Size<i32, Physical>,
+    scale: Scale<f64>,
+    transform: Transform,
+    elements: &[RelocateRenderElement<OutputRenderElements<GlesRenderer>>],
+) -> anyhow::Result<()> {
+    // Everything is redrawn, the damage reported to clients comes from the capture's tracker.
+    if let Ok(dmabuf) = get_dmabuf(buffer) {
+        let mut tracker = OutputDamageTracker::new(size, scale, transform);
+        let res = tracker
+            .render_output_with(renderer, dmabuf.clone(), 0, elements, [0.0, 0.0, 0.0, 1.0].into())
+            .context("error rendering into the dmabuf")?;
+        res.sync.wait()?;
+        renderer.unbind()?;
+        return Ok(());
+    }
+
+    // Argb8888 has the byte order of both shm formats.
+    let pixels = render_to_memory(renderer, size, scale, transform, elements, Fourcc::Argb8888)?;
+
+    with_buffer_contents_mut(buffer, |ptr, len, data| {
+        let row = size.w as usize * 4;
+        let stride = data.stride as usize;
+        let offset = data.offset as usize;
+        if offset + stride * (size.h as usize - 1) + row > len || pixels.len() < row * size.h as usize {
+            bail!("shm buffer is too small");
+        }
+
+        for y in 0..size.h as usize {
+            // SAFETY: the bounds were checked above and the pool stays mapped during the call.
+            unsafe {
+                ptr::copy_nonoverlapping(pixels[y * row..].as_ptr(), ptr.add(offset + y * stride), row);
+            }
+        }
+        Ok(())
+    })?
+}
+
+/// Renders the elements offscreen and reads back tightly packed rows in `format`.
+pub fn render_to_memory(
+    renderer: &mut GlesRenderer,
+    size: Size<i32, Physical>,
+    scale: Scale<f64>,
+    transform: Transform,
+    elements: &[RelocateRenderElement<OutputRenderElements<GlesRenderer>>],
+    format: Fourcc,
+) -> anyhow::Result<Vec<u8>> {
+    let mut tracker = OutputDamageTracker::new(size, scale, transform);
+    let buffer_size = Size::from((size.w, size.h));
+
+    let texture: GlesTexture = renderer
+        .create_buffer(Fourcc::Abgr8888, buffer_size)
+        .context("error creating the capture texture")?;
+    let res = tracker
+        .render_output_with(renderer, texture, 0, elements, [0.0, 0.0, 0.0, 1.0].into())
+        .context("error rendering the capture")?;
+    res.sync.wait()?;
+
+    let pixels = renderer
+        .copy_framebuffer(Rectangle::from_size(buffer_size), format)
+        .and_then(|mapping| renderer.map_texture(&mapping).map(|pixels| pixels.to_vec()));
+    renderer.unbind()?;
+    Ok(pixels?)
+}
@@ -0,0 +1,288 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    os::fd::{AsRawFd, OwnedFd},
+    rc::Rc,
+    sync::Arc,
+    time::Duration,
+};
+
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use smithay::{
+    input::Seat,
+    reexports::{
+        calloop::{
+            generic::Generic,
+            timer::{TimeoutAction, Timer},
+            Interest, Mode, PostAction,
+        },
+        wayland_server::backend::ClientId,
+    },
+    wayland::selection::{
+        data_device::{request_data_device_client_selection, set_data_device_selection},
+        primary_selection::{request_primary_client_selection, set_primary_selection},
+        SelectionSource, SelectionTarget,
+    },
+};
+
+use crate::Tsuki;
+
+/// How long a client gets to send a selection before the copy is given up.
+const SELECTION_READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Contents of a compositor-owned selection by mime type.
+pub type SelectionContents = Arc<HashMap<String, Vec<u8>>>;
+
+/// Copies of the selections set by clients, offered by the compositor once their client is
+/// gone. Only filled when `clipboard.persist` is enabled.
+#[derive(Default)]
+pub struct ClipboardCache {
+    clipboard: Option<CachedSelection>,
+    primary: Option<CachedSelection>,
+    // Bumped for every new selection, so that reads of a replaced one are dropped.
+    serial: u64,
+    /// The client whose selection device request is being handled, it owns the selections
+    /// set meanwhile.
+    pub requesting_client: Option<ClientId>,
+}
+
+struct CachedSelection {
+    serial: u64,
+    owner: ClientId,
+    contents: HashMap<String, Vec<u8>>,
+    size: usize,
+    pending_reads: usize,
+}
+
+impl ClipboardCache {
+    fn selection_mut(&mut self, target: SelectionTarget) -> &mut Option<CachedSelection> {
+        match target {
+            SelectionTarget::Clipboard => &mut self.clipboard,
+            SelectionTarget::Primary => &mut self.primary,
+        }
+    }
+}
+
+impl Tsuki {
+    /// Starts copying every mime type of a selection a client just set.
+    pub fn cache_selection(&mut self, target: SelectionTarget, source: Option<SelectionSource>, seat: &Seat<Self>) {
+        *self.clipboard_cache.selection_mut(target) = None;
+
+        if !self.config.clipboard.persist {
+            return;
+        }
+        let Some(source) = source else {
+            return;
+        };
+
+        let Some(owner) = self.clipboard_cache.requesting_client.clone() else {
+            return;
+        };
+
+        self.clipboard_cache.serial += 1;
+        let serial = self.clipboard_cache.serial;
+
+        *self.clipboard_cache.selection_mut(target) = Some(CachedSelection {
+            serial,
+            owner,
+            contents: HashMap::new(),
+            size: 0,
+            pending_reads: 0,
+        });
+
+        // The seat only gets the new selection after this, reading now would copy the old one.
+        let seat = seat.clone();
+        let mime_types = source.mime_types();
+        self.event_loop.insert_idle(move |data| {
+            data.tsuki.read_selections(target, &seat, serial, mime_types);
+        });
+    }
+
+    fn read_selections(&mut self, target: SelectionTarget, seat: &Seat<Self>, serial: u64, mime_types: Vec<String>) {
+        // Replaced already, by a later request in the same dispatch.
+        if !matches!(self.clipboard_cache.selection_mut(target), Some(cached) if cached.serial == serial) {
+            return;
+        }
+
+        for mime_type in mime_types {
+            match self.read_selection(target, seat, serial, mime_type.clone()) {
+                Ok(()) => {
+                    if let Some(cached) = self.clipboard_cache.selection_mut(target) {
+                        cached.pending_reads += 1;
+                    }
+                }
+                Err(err) => log::warn!("error reading the {mime_type:?} selection: {err:?}"),
+            }
+        }
+    }
+
+    fn read_selection(
+        &mut self,
+        target: SelectionTarget,
+        seat: &Seat<Self>,
+        serial: u64,
+        mime_type: String,
+    ) -> anyhow::Result<()> {
+        let (reader, writer) = io::pipe()?;
+        fcntl(reader.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+
+        match target {
+            SelectionTarget::Clipboard => request_data_device_client_selection(seat, mime_type.clone(), writer.into())?,
+            SelectionTarget::Primary => request_primary_client_selection(seat, mime_type.clone(), writer.into())?,
+        }
+
+        let max_size = self.config.clipboard.max_size;
+        let mut contents = Vec::new();
+        let timer = Rc::new(Cell::new(None));
+        let reader_timer = timer.clone();
+        let reader_token = self
+            .event_loop
+            .insert_source(Generic::new(reader, Interest::READ, Mode::Level), move |_, reader, data| {
+                let mut buf = [0; 4096];
+                let contents = loop {
+                    match unsafe { reader.get_mut() }.read(&mut buf) {
+                        Ok(0) => break Some(mem::take(&mut contents)),
+                        Ok(len) => {
+                            contents.extend_from_slice(&buf[..len]);
+                            // No need to read further, the whole selection is dropped anyway.
+                            if contents.len() > max_size {
+                                break Some(mem::take(&mut contents));
+                            }
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(PostAction::Continue),
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            log::warn!("error reading the {mime_type:?} selection: {err}");
+                            break None;
+                        }
+                    }
+                };
+
+                if let Some(token) = reader_timer.take() {
+                    data.tsuki.event_loop.remove(token);
+                }
+                data.tsuki.on_selection_read(target, serial, mime_type.clone(), contents);
+                Ok(PostAction::Remove)
+            })
+            .map_err(|err| err.error)?;
+
+        // Clients that never close the pipe would keep the read, and the selection with it,
+        // pending forever.
+        let timer_token = self
+            .event_loop
+            .insert_source(Timer::from_duration(SELECTION_READ_TIMEOUT), move |_, _, data| {
+                log::warn!("timed out reading a selection");
+                data.tsuki.event_loop.remove(reader_token);
+                data.tsuki.on_selection_read(target, serial, String::new(), None);
+                TimeoutAction::Drop
+            })
+            .map_err(|err| {
+                self.event_loop.remove(reader_token);
+                err.error
+            })?;
+        timer.set(Some(timer_token));
+
+        Ok(())
+    }
+
+    fn on_selection_read(&mut self, target: SelectionTarget, serial: u64, mime_type: String, contents: Option<Vec<u8>>) {
+        let max_size = self.config.clipboard.max_size;
+        let selection = self.clipboard_cache.selection_mut(target);
+        let Some(cached) = selection.as_mut().filter(|cached| cached.serial == serial) else {
+            return;
+        };
+
+        cached.pending_reads -= 1;
+        let Some(contents) = contents else {
+            return;
+        };
+
+        cached.size += contents.len();
+        if cached.size > max_size {
+            log::info!("selection is larger than clipboard.max_size, not keeping it");
+            *selection = None;
+            return;
+        }
+
+        cached.contents.insert(mime_type, contents);
+    }
+
+    /// Offers the copy of a selection as the compositor's own once the client that set it is
+    /// gone. Called on every event loop iteration.
+    pub fn persist_selections(&mut self) {
+        for target in [SelectionTarget::Clipboard, SelectionTarget::Primary] {
+            let backend = self.display_handle.backend_handle();
+            let owner_gone = self
+                .clipboard_cache
+                .selection_mut(target)
+                .as_ref()
+                .is_some_and(|cached| cached.pending_reads == 0 && backend.get_client_data(cached.owner.clone()).is_err());
+            if !owner_gone {
+                continue;
+            }
+
+            let cached = self.clipboard_cache.selection_mut(target).take().unwrap();
+            if cached.contents.is_empty() {
+                continue;
+            }
+
+            let mime_types = cached.contents.keys().cloned().collect();
+            let contents = Arc::new(cached.contents);
+            match target {
+                SelectionTarget::Clipboard => {
+                    set_data_device_selection(&self.display_handle, &self.seat, mime_types, contents)
+                }
+                SelectionTarget::Primary => set_primary_selection(&self.display_handle, &self.seat, mime_types, contents),
+            }
+        }
+    }
+
+    /// Replaces the clipboard with contents owned by the compositor.
+    pub fn set_clipboard(&mut self, contents: HashMap<String, Vec<u8>>) {
+        self.clipboard_cache.clipboard = None;
+        let mime_types = contents.keys().cloned().collect();
+        set_data_device_selection(&self.display_handle, &self.seat, mime_types, Arc::new(contents));
+    }
+
+    /// Writes a compositor-owned selection to a client without blocking the event loop.
+    pub fn send_cached_selection(&mut self, mime_type: String, fd: OwnedFd, contents: &SelectionContents) {
+        if !contents.contains_key(&mime_type) {
+            return;
+        }
+
+        let file = File::from(fd);
+        if let Err(err) = fcntl(file.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
+            log::warn!("error sending the {mime_type:?} selection: {err}");
+            return;
+        }
+
+        let contents = contents.clone();
+        let mut written = 0;
+        let res = self
+            .event_loop
+            .insert_source(Generic::new(file, Interest::WRITE, Mode::Level), move |_, file, _| {
+                let data = &contents[&mime_type];
+                loop {
+                    if written == data.len() {
+                        return Ok(PostAction::Remove);
+                    }
+
+                    match unsafe { file.get_mut() }.write(&data[written..]) {
+                        Ok(len) => written += len,
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(PostAction::Continue),
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(err) => {
+                            log::warn!("error sending the {mime_type:?} selection: {err}");
+                            return Ok(PostAction::Remove);
+                        }
+                    }
+                }
+            });
+        if let Err(err) = res {
+            log::warn!("error inserting the selection writer: {}", err.error);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Loaded from `$TSUKI_CONFIG` or `$XDG_CONFIG_HOME/tsuki/config.toml`:
+//!
+//! ```toml
+//! [[output]]
+//! name = "eDP-1"            # or "<make> <model> <serial>"
+//! mode = "1920x1080@144"
+//! position = { x = 0, y = 0 }
+//! scale = 1.5               # above 0 and at most 10
+//! transform = "90"          # normal, 90, 180, 270, flipped, flipped-90, ...
+//! enabled = true
+//!
+//! [clipboard]
+//! persist = true            # keep the selections after their client exits
+//! max_size = 16777216       # in bytes, larger selections are not kept
+//!
+//! [screenshot]
+//! directory = "~/Pictures/Screenshots"
+//! clipboard = true          # also put every screenshot on the clipboard
+//!
+//! [lock]
+//! allowed_keybindings = ["change-vt", "outputs-power"]   # quit, change-vt, screenshot, outputs-power
+//! ```
+
+use std::{env, fs, path::PathBuf};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use smithay::{
+    output::Scale,
+    utils::{Logical, Point, Transform},
+};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(rename = "output")]
+    pub outputs: Vec<OutputConfig>,
+    pub clipboard: ClipboardConfig,
+    pub screenshot: ScreenshotConfig,
+    pub lock: LockConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OutputConfig {
+    /// Connector name (e.g. `eDP-1`) or `"<make> <model> <serial>"`.
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub mode: Option<ModeConfig>,
+    pub position: Option<PositionConfig>,
+    pub scale: Option<ScaleConfig>,
+    pub transform: Option<TransformConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ModeConfig {
+    pub width: u16,
+    pub height: u16,
+    /// Refresh rate in Hz, the highest one is picked when not set.
+    pub refresh: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct PositionConfig {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// An output scale, between 0 (exclusive) and [`MAX_SCALE`].
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "f64")]
+pub struct ScaleConfig(pub f64);
+
+/// Larger scales leave even 8K outputs under 800 logical pixels wide, they are most likely typos.
+pub const MAX_SCALE: f64 = 10.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TransformConfig(pub Transform);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClipboardConfig {
+    /// Copy the selections so that they survive the client that set them.
+    pub persist: bool,
+    /// Largest selection that is copied, in bytes, counting every mime type.
+    pub max_size: usize,
+}
+
+impl Default for ClipboardConfig {
+    fn default() -> Self {
+        Self {
+            persist: false,
+            max_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ScreenshotConfig {
+    /// Where screenshots are written, `~/Pictures/Screenshots` when not set.
+    pub directory: Option<PathBuf>,
+    /// Put screenshots on the clipboard as `image/png` as well.
+    pub clipboard: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LockConfig {
+    /// Keybindings that keep working while the session is locked, the keys of the others go
+    /// to the lock screen.
+    pub allowed_keybindings: Vec<Keybinding>,
+}
+
+impl Default for LockConfig {
+    fn default() -> Self {
+        Self {
+            allowed_keybindings: vec![Keybinding::ChangeVt, Keybinding::OutputsPower],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Keybinding {
+    Quit,
+    ChangeVt,
+    Screenshot,
+    OutputsPower,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Config {
+    pub fn load() -> Self {
+        let Some(path) = config_path() else {
+            return Self::default();
+        };
+
+        if !path.exists() {
+            log::info!("no config at {path:?}, using defaults");
+            return Self::default();
+        }
+
+        match Self::load_from(&path) {
+            Ok(config) => config,
+            Err(err) => {
+                log::error!("error loading config: {err:?}");
+                Self::default()
+            }
+        }
+    }
+
+    fn load_from(path: &PathBuf) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path).with_context(|| format!("reading {path:?}"))?;
+        let config = toml::from_str(&contents).with_context(|| format!("parsing {path:?}"))?;
+        Ok(config)
+    }
+
+    /// Finds the settings for an output, matching either the connector name or the
+    /// make/model/serial triple from the EDID.
+    pub fn output(&self, name: &str, make: &str, model: &str, serial: &str) -> Option<&OutputConfig> {
+        let description = format!("{make} {model} {serial}");
+        self.outputs
+            .iter()
+            .find(|output| output.name == name || output.name == description.trim_end())
+    }
+}
+
+impl OutputConfig {
+    pub fn scale(&self) -> Option<Scale> {
+        let scale = self.scale?.0;
+        if scale.fract() == 0. {
+            Some(Scale::Integer(scale as i32))
+        } else {
+            Some(Scale::Fractional(scale))
+        }
+    }
+
+    pub fn transform(&self) -> Option<Transform> {
+        self.transform.map(|transform| transform.0)
+    }
+
+    pub fn position(&self) -> Option<Point<i32, Logical>> {
+        self.position.map(|position| (position.x, position.y).into())
+    }
+}
+
+impl ScreenshotConfig {
+    pub fn directory(&self) -> Option<PathBuf> {
+        let home = env::var_os("HOME").map(PathBuf::from);
+        match &self.directory {
+            Some(directory) => match directory.strip_prefix("~") {
+                Ok(rest) => Some(home?.join(rest)),
+                Err(_) => Some(directory.clone()),
+            },
+            None => Some(home?.join("Pictures").join("Screenshots")),
+        }
+    }
+}
+
+impl LockConfig {
+    pub fn allows(&self, keybinding: Keybinding) -> bool {
+        self.allowed_keybindings.contains(&keybinding)
+    }
+}
+
+impl TryFrom<String> for ModeConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (size, refresh) = match value.split_once('@') {
+            Some((size, refresh)) => (size, Some(refresh)),
+            None => (value.as_str(), None),
+        };
+
+        let (width, height) = size
+            .split_once('x')
+            .ok_or_else(|| anyhow!("mode {value:?} should look like 1920x1080@60"))?;
+
+        let refresh = refresh
+            .map(|refresh| refresh.trim_end_matches("Hz").parse::<f64>())
+            .transpose()
+            .with_context(|| format!("invalid refresh rate in {value:?}"))?;
+
+        Ok(Self {
+            width: width.parse().with_context(|| format!("invalid width in {value:?}"))?,
+            height: height.parse().with_context(|| format!("invalid height in {value:?}"))?,
+            refresh,
+        })
+    }
+}
+
+impl TryFrom<f64> for ScaleConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        if !(value > 0. && value <= MAX_SCALE) {
+            return Err(anyhow!("scale {value} should be above 0 and at most {MAX_SCALE}"));
+        }
+        Ok(Self(value))
+    }
+}
+
+impl TryFrom<String> for TransformConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let transform = match value.as_str() {
+            "normal" | "0" => Transform::Normal,
+            "90" => Transform::_90,
+            "180" => Transform::_180,
+            "270" => Transform::_270,
+            "flipped" => Transform::Flipped,
+            "flipped-90" => Transform::Flipped90,
+            "flipped-180" => Transform::Flipped180,
+            "flipped-270" => Transform::Flipped270,
+            _ => return Err(anyhow!("unknown transform {value:?}")),
+        };
+        Ok(Self(transform))
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("TSUKI_CONFIG") {
+        return Some(path.into());
+    }
+
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_home.join("tsuki").join("config.toml"))
+}
@@ -0,0 +1,244 @@
+use std::{cell::RefCell, collections::HashMap, env, fs, rc::Rc};
+
+use anyhow::{anyhow, Context};
+use smithay::{
+    backend::{allocator::Fourcc, renderer::element::memory::MemoryRenderBuffer},
+    input::pointer::{CursorIcon, CursorImageStatus, CursorImageSurfaceData},
+    reexports::wayland_server::{protocol::wl_surface::WlSurface, Resource},
+    utils::{Logical, Physical, Point, Rectangle, Size, Transform},
+    wayland::compositor::with_states,
+};
+use xcursor::{parser::parse_xcursor, CursorTheme};
+
+const FALLBACK_SIZE: u32 = 16;
+
+type CursorCache = HashMap<(CursorIcon, i32), Option<Rc<XCursor>>>;
+
+/// Tracks the cursor image requested by clients and loads named cursors from the xcursor
+/// theme set in `XCURSOR_THEME`/`XCURSOR_SIZE`.
+pub struct CursorManager {
+    theme: CursorTheme,
+    size: u32,
+    image: CursorImageStatus,
+    // Loaded cursors by icon and integer output scale, `None` when the theme lacks the icon.
+    cache: RefCell<CursorCache>,
+    fallback: Rc<XCursor>,
+}
+
+pub enum RenderCursor {
+    Hidden,
+    Surface {
+        surface: WlSurface,
+        hotspot: Point<i32, Logical>,
+    },
+    Named {
+        cursor: Rc<XCursor>,
+        scale: i32,
+    },
+}
+
+/// A cursor loaded from an xcursor file, with one buffer per animation frame.
+pub struct XCursor {
+    frames: Vec<XCursorFrame>,
+    animation_duration: u32,
+}
+
+struct XCursorFrame {
+    buffer: MemoryRenderBuffer,
+    size: Size<i32, Physical>,
+    hotspot: Point<i32, Physical>,
+    delay: u32,
+}
+
+impl CursorManager {
+    pub fn new() -> Self {
+        let theme_name = env::var("XCURSOR_THEME").unwrap_or_else(|_| "default".to_owned());
+        let size = env::var("XCURSOR_SIZE")
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(24);
+
+        Self {
+            theme: CursorTheme::load(&theme_name),
+            size,
+            image: CursorImageStatus::default_named(),
+            cache: RefCell::new(HashMap::new()),
+            fallback: Rc::new(XCursor::fallback()),
+        }
+    }
+
+    pub fn image(&self) -> &CursorImageStatus {
+        &self.image
+    }
+
+    pub fn set_image(&mut self, image: CursorImageStatus) {
+        self.image = image;
+    }
+
+    /// Resolves the current image for an output with the given integer scale. Dead cursor
+    /// surfaces fall back to the default cursor.
+    pub fn render_cursor(&mut self, scale: i32) -> RenderCursor {
+        let icon = match &self.image {
+            CursorImageStatus::Hidden => return RenderCursor::Hidden,
+            CursorImageStatus::Named(icon) => *icon,
+            CursorImageStatus::Surface(surface) if surface.is_alive() => {
+                let hotspot = with_states(surface, |states| {
+                    states
+                        .data_map
+                        .get::<CursorImageSurfaceData>()
+                        .map(|attributes| attributes.lock().unwrap().hotspot)
+                        .unwrap_or_default()
+                });
+                return RenderCursor::Surface {
+                    surface: surface.clone(),
+                    hotspot,
+                };
+            }
+            CursorImageStatus::Surface(_) => {
+                self.image = CursorImageStatus::default_named();
+                CursorIcon::Default
+            }
+        };
+
+        let cursor = self
+            .named_cursor(icon, scale)
+            .or_else(|| self.named_cursor(CursorIcon::Default, scale))
+            .unwrap_or_else(|| self.fallback.clone());
+
+        RenderCursor::Named { cursor, scale }
+    }
+
+    /// Whether the current cursor needs redraws to animate.
+    pub fn is_animated(&mut self, scale: i32) -> bool {
+        match self.render_cursor(scale) {
+            RenderCursor::Named { cursor, .. } => cursor.is_animated(),
+            _ => false,
+        }
+    }
+
+    fn named_cursor(&self, icon: CursorIcon, scale: i32) -> Option<Rc<XCursor>> {
+        self.cache
+            .borrow_mut()
+            .entry((icon, scale))
+            .or_insert_with(|| {
+                let size = self.size * scale.max(1) as u32;
+                let cursor = std::iter::once(icon.name())
+                    .chain(icon.alt_names().iter().copied())
+                    .find_map(|name| XCursor::load(&self.theme, name, size, scale).ok());
+
+                if cursor.is_none() {
+                    log::warn!("no {:?} cursor in the xcursor theme", icon.name());
+                }
+
+                cursor.map(Rc::new)
+            })
+            .clone()
+    }
+}
+
+impl XCursor {
+    fn load(theme: &CursorTheme, name: &str, size: u32, scale: i32) -> anyhow::Result<Self> {
+        let path = theme
+            .load_icon(name)
+            .ok_or_else(|| anyhow!("no cursor named {name:?}"))?;
+        let contents = fs::read(&path).with_context(|| format!("reading {path:?}"))?;
+        let images = parse_xcursor(&contents).ok_or_else(|| anyhow!("error parsing {path:?}"))?;
+
+        // Files contain several nominal sizes, use the closest one to what was asked.
+        let nearest = images
+            .iter()
+            .min_by_key(|image| (size as i32 - image.size as i32).abs())
+            .ok_or_else(|| anyhow!("{path:?} has no images"))?
+            .size;
+
+        let frames: Vec<_> = images
+            .into_iter()
+            .filter(|image| image.size == nearest)
+            .map(|image| XCursorFrame {
+                buffer: MemoryRenderBuffer::from_slice(
+                    &image.pixels_rgba,
+                    Fourcc::Argb8888,
+                    (image.width as i32, image.height as i32),
+                    scale,
+                    Transform::Normal,
+                    None,
+                ),
+                size: (image.width as i32, image.height as i32).into(),
+                hotspot: (image.xhot as i32, image.yhot as i32).into(),
+                delay: image.delay,
+            })
+            .collect();
+
+        Ok(Self::from_frames(frames))
+    }
+
+    /// An arrow drawn by hand, for when the theme can't provide a cursor at all.
+    fn fallback() -> Self {
+        let mut pixels = vec![0u8; (FALLBACK_SIZE * FALLBACK_SIZE * 4) as usize];
+        for y in 0..FALLBACK_SIZE {
+            for x in 0..=y.min(FALLBACK_SIZE * 2 / 3) {
+                let edge = x == 0 || x == y || y == FALLBACK_SIZE - 1;
+                let value = if edge { 0x00 } else { 0xff };
+                let offset = ((y * FALLBACK_SIZE + x) * 4) as usize;
+                pixels[offset..offset + 4].copy_from_slice(&[value, value, value, 0xff]);
+            }
+        }
+
+        let buffer = MemoryRenderBuffer::from_slice(
+            &pixels,
+            Fourcc::Argb8888,
+            (FALLBACK_SIZE as i32, FALLBACK_SIZE as i32),
+            1,
+            Transform::Normal,
+            None,
+        );
+
+        Self::from_frames(vec![XCursorFrame {
+            buffer,
+            size: (FALLBACK_SIZE as i32, FALLBACK_SIZE as i32).into(),
+            hotspot: (0, 0).into(),
+            delay: 0,
+        }])
+    }
+
+    fn from_frames(frames: Vec<XCursorFrame>) -> Self {
+        let animation_duration = frames.iter().map(|frame| frame.delay).sum();
+        Self {
+            frames,
+            animation_duration,
+        }
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1 && self.animation_duration > 0
+    }
+
+    /// The buffer and hotspot to show `millis` into the animation.
+    pub fn frame(&self, millis: u32) -> (&MemoryRenderBuffer, Point<i32, Physical>) {
+        let frame = self.current_frame(millis);
+        (&frame.buffer, frame.hotspot)
+    }
+
+    /// Where the frame shown `millis` into the animation is drawn, relative to the pointer.
+    pub fn geometry(&self, millis: u32) -> Rectangle<i32, Physical> {
+        let frame = self.current_frame(millis);
+        Rectangle::new(Point::default() - frame.hotspot, frame.size)
+    }
+
+    fn current_frame(&self, millis: u32) -> &XCursorFrame {
+        let mut frame = &self.frames[0];
+
+        if self.is_animated() {
+            let mut millis = millis % self.animation_duration;
+            for candidate in &self.frames {
+                frame = candidate;
+                if millis < candidate.delay {
+                    break;
+                }
+                millis -= candidate.delay;
+            }
+        }
+
+        frame
+    }
+}
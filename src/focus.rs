@@ -0,0 +1,137 @@
+use std::borrow::Cow;
+
+use smithay::{
+    backend::input::KeyState,
+    desktop::{PopupKind, Window},
+    input::{
+        keyboard::{KeyboardTarget, KeysymHandle, ModifiersState},
+        Seat,
+    },
+    reexports::wayland_server::{backend::ObjectId, protocol::wl_surface::WlSurface},
+    utils::{IsAlive, Serial},
+    wayland::seat::WaylandFocus,
+};
+#[cfg(feature = "xwayland")]
+use smithay::xwayland::X11Surface;
+
+use crate::Tsuki;
+
+/// What the keyboard can be focused on. X11 windows need the X input focus moved along with
+/// the Wayland one, so they can't be focused through their surface alone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyboardFocusTarget {
+    Surface(WlSurface),
+    #[cfg(feature = "xwayland")]
+    X11 {
+        window: Box<X11Surface>,
+        surface: WlSurface,
+    },
+}
+
+impl KeyboardFocusTarget {
+    /// The target for a mapped window, `None` for X11 windows that have no surface yet.
+    pub fn from_window(window: &Window) -> Option<Self> {
+        if let Some(toplevel) = window.toplevel() {
+            return Some(Self::Surface(toplevel.wl_surface().clone()));
+        }
+
+        #[cfg(feature = "xwayland")]
+        if let Some(x11) = window.x11_surface() {
+            return Some(Self::X11 {
+                surface: x11.wl_surface()?,
+                window: Box::new(x11.clone()),
+            });
+        }
+
+        None
+    }
+
+    pub fn surface(&self) -> &WlSurface {
+        match self {
+            Self::Surface(surface) => surface,
+            #[cfg(feature = "xwayland")]
+            Self::X11 { surface, .. } => surface,
+        }
+    }
+}
+
+impl IsAlive for KeyboardFocusTarget {
+    fn alive(&self) -> bool {
+        match self {
+            Self::Surface(surface) => surface.alive(),
+            #[cfg(feature = "xwayland")]
+            Self::X11 { window, .. } => window.alive(),
+        }
+    }
+}
+
+impl WaylandFocus for KeyboardFocusTarget {
+    fn wl_surface(&self) -> Option<Cow<'_, WlSurface>> {
+        Some(Cow::Borrowed(self.surface()))
+    }
+
+    fn same_client_as(&self, object_id: &ObjectId) -> bool {
+        self.surface().same_client_as(object_id)
+    }
+}
+
+impl KeyboardTarget<Tsuki> for KeyboardFocusTarget {
+    fn enter(&self, seat: &Seat<Tsuki>, data: &mut Tsuki, keys: Vec<KeysymHandle<'_>>, serial: Serial) {
+        match self {
+            Self::Surface(surface) => KeyboardTarget::enter(surface, seat, data, keys, serial),
+            #[cfg(feature = "xwayland")]
+            Self::X11 { window, .. } => KeyboardTarget::enter(&**window, seat, data, keys, serial),
+        }
+    }
+
+    fn leave(&self, seat: &Seat<Tsuki>, data: &mut Tsuki, serial: Serial) {
+        match self {
+            Self::Surface(surface) => KeyboardTarget::leave(surface, seat, data, serial),
+            #[cfg(feature = "xwayland")]
+            Self::X11 { window, .. } => KeyboardTarget::leave(&**window, seat, data, serial),
+        }
+    }
+
+    fn key(
+        &self,
+        seat: &Seat<Tsuki>,
+        data: &mut Tsuki,
+        key: KeysymHandle<'_>,
+        state: KeyState,
+        serial: Serial,
+        time: u32,
+    ) {
+        match self {
+            Self::Surface(surface) => KeyboardTarget::key(surface, seat, data, key, state, serial, time),
+            #[cfg(feature = "xwayland")]
+            Self::X11 { window, .. } => KeyboardTarget::key(&**window, seat, data, key, state, serial, time),
+        }
+    }
+
+    fn modifiers(&self, seat: &Seat<Tsuki>, data: &mut Tsuki, modifiers: ModifiersState, serial: Serial) {
+        match self {
+            Self::Surface(surface) => KeyboardTarget::modifiers(surface, seat, data, modifiers, serial),
+            #[cfg(feature = "xwayland")]
+            Self::X11 { window, .. } => KeyboardTarget::modifiers(&**window, seat, data, modifiers, serial),
+        }
+    }
+}
+
+impl From<WlSurface> for KeyboardFocusTarget {
+    fn from(surface: WlSurface) -> Self {
+        Self::Surface(surface)
+    }
+}
+
+impl From<PopupKind> for KeyboardFocusTarget {
+    fn from(popup: PopupKind) -> Self {
+        Self::Surface(popup.wl_surface().clone())
+    }
+}
+
+// Popup grabs hand the keyboard focus over to the pointer.
+impl From<KeyboardFocusTarget> for WlSurface {
+    fn from(target: KeyboardFocusTarget) -> Self {
+        target.surface().clone()
+    }
+}
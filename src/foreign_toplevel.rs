@@ -0,0 +1,120 @@
+use smithay::{
+    desktop::Window,
+    wayland::{
+        compositor::with_states,
+        foreign_toplevel_list::ForeignToplevelHandle,
+        shell::xdg::XdgToplevelSurfaceData,
+    },
+};
+
+use crate::{
+    focus::KeyboardFocusTarget,
+    protocols::foreign_toplevel::ToplevelInfo,
+    window::window_state,
+    Tsuki,
+};
+
+/// The title and app id of a window, X11 windows use their class as app id.
+pub fn window_title_app_id(window: &Window) -> (String, String) {
+    if let Some(toplevel) = window.toplevel() {
+        return with_states(toplevel.wl_surface(), |states| {
+            let data = states.data_map.get::<XdgToplevelSurfaceData>().unwrap().lock().unwrap();
+            (
+                data.title.clone().unwrap_or_default(),
+                data.app_id.clone().unwrap_or_default(),
+            )
+        });
+    }
+
+    #[cfg(feature = "xwayland")]
+    if let Some(x11) = window.x11_surface() {
+        return (x11.title(), x11.class());
+    }
+
+    (String::new(), String::new())
+}
+
+impl Tsuki {
+    /// Announces the window to both foreign toplevel protocols, or sends its title, app id and
+    /// state changes. Called whenever one of those might have changed.
+    pub fn update_foreign_toplevel(&mut self, window: &Window) {
+        if is_override_redirect(window) {
+            return;
+        }
+
+        let (title, app_id) = window_title_app_id(window);
+        match self.foreign_toplevels.get(window) {
+            Some(handle) => {
+                let mut changed = false;
+                if handle.title() != title {
+                    handle.send_title(&title);
+                    changed = true;
+                }
+                if handle.app_id() != app_id {
+                    handle.send_app_id(&app_id);
+                    changed = true;
+                }
+                if changed {
+                    handle.send_done();
+                }
+            }
+            None => {
+                let handle = self
+                    .foreign_toplevel_list_state
+                    .new_toplevel::<Self>(title.clone(), app_id.clone());
+                self.foreign_toplevels.insert(window.clone(), handle);
+            }
+        }
+
+        let (maximized, minimized, fullscreen) = {
+            let state = window_state(window).borrow();
+            (state.maximized, state.minimized, state.fullscreen)
+        };
+        let focus = self.seat.get_keyboard().unwrap().current_focus();
+        let info = ToplevelInfo {
+            title,
+            app_id,
+            activated: focus.is_some() && focus == KeyboardFocusTarget::from_window(window),
+            maximized,
+            minimized,
+            fullscreen,
+            outputs: self.space.outputs_for_element(window),
+        };
+        self.foreign_toplevel_manager_state.update_toplevel::<Self>(window, info);
+    }
+
+    /// The keyboard focus and the outputs concern every announced window, they change rarely.
+    pub fn update_foreign_toplevels(&mut self) {
+        let windows: Vec<_> = self.foreign_toplevels.keys().cloned().collect();
+        for window in windows {
+            self.update_foreign_toplevel(&window);
+        }
+    }
+
+    /// Closes the handles of a window that went away.
+    pub fn remove_foreign_toplevel(&mut self, window: &Window) {
+        if let Some(handle) = self.foreign_toplevels.remove(window) {
+            self.foreign_toplevel_list_state.remove_toplevel(&handle);
+        }
+        self.foreign_toplevel_manager_state.remove_toplevel(window);
+    }
+
+    pub fn window_for_foreign_toplevel(&self, handle: &ForeignToplevelHandle) -> Option<Window> {
+        let identifier = handle.identifier();
+        self.foreign_toplevels
+            .iter()
+            .find(|(_, h)| h.identifier() == identifier)
+            .map(|(window, _)| window.clone())
+    }
+}
+
+/// Menus and tooltips of X11 clients aren't toplevels.
+fn is_override_redirect(window: &Window) -> bool {
+    #[cfg(feature = "xwayland")]
+    if let Some(x11) = window.x11_surface() {
+        return x11.is_override_redirect();
+    }
+
+    let _ = window;
+    false
+}
@@ -0,0 +1,82 @@
+use std::{num::NonZeroU64, time::Duration};
+
+use smithay::output::Mode;
+
+/// Assumed for outputs that don't report a refresh rate, so that they still get paced.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_micros(16_667);
+
+/// Keeps track of when an output last presented a frame so we can estimate when the next
+/// vblank is going to happen.
+#[derive(Debug)]
+pub struct FrameClock {
+    last_presentation_time: Option<Duration>,
+    refresh_interval_ns: Option<NonZeroU64>,
+}
+
+impl FrameClock {
+    pub fn new(refresh_interval: Option<Duration>) -> Self {
+        let refresh_interval_ns = refresh_interval
+            .and_then(|interval| NonZeroU64::new(interval.as_nanos() as u64));
+
+        Self {
+            last_presentation_time: None,
+            refresh_interval_ns,
+        }
+    }
+
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        self.refresh_interval_ns
+            .map(|ns| Duration::from_nanos(ns.get()))
+    }
+
+    pub fn presented(&mut self, presentation_time: Duration) {
+        if presentation_time.is_zero() {
+            // Some drivers report a zero timestamp, it is useless for estimation.
+            return;
+        }
+
+        self.last_presentation_time = Some(presentation_time);
+    }
+
+    /// Estimates the time of the next presentation after `now`.
+    ///
+    /// Falls back to `now` when we don't know the refresh rate or haven't presented yet.
+    pub fn next_presentation_time(&self, now: Duration) -> Duration {
+        let (Some(refresh_interval_ns), Some(last_presentation_time)) =
+            (self.refresh_interval_ns, self.last_presentation_time)
+        else {
+            return now;
+        };
+
+        let refresh_interval_ns = refresh_interval_ns.get();
+
+        if now <= last_presentation_time {
+            // Presentation timestamps can be slightly in the future.
+            return last_presentation_time + Duration::from_nanos(refresh_interval_ns);
+        }
+
+        let since_last = (now - last_presentation_time).as_nanos() as u64;
+        let intervals = since_last / refresh_interval_ns + 1;
+        last_presentation_time + Duration::from_nanos(intervals * refresh_interval_ns)
+    }
+
+    /// How long until the next estimated presentation after `now`. Without an estimate this is
+    /// a whole refresh cycle, of [`DEFAULT_REFRESH_INTERVAL`] when the refresh rate is unknown.
+    pub fn time_to_next_presentation(&self, now: Duration) -> Duration {
+        let next_presentation_time = self.next_presentation_time(now);
+        if next_presentation_time > now {
+            return next_presentation_time - now;
+        }
+
+        self.refresh_interval().unwrap_or(DEFAULT_REFRESH_INTERVAL)
+    }
+}
+
+/// The refresh interval of `mode`. Some virtual and broken connectors report a refresh rate of
+/// zero, their frames aren't paced then.
+pub fn mode_refresh_interval(mode: Mode) -> Option<Duration> {
+    u64::try_from(mode.refresh)
+        .ok()
+        .filter(|&refresh| refresh > 0)
+        .map(|refresh| Duration::from_nanos(1_000_000_000_000 / refresh))
+}
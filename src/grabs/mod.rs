@@ -2,4 +2,7 @@ pub mod move_grab;
 pub use move_grab::MoveSurfaceGrab;
 
 pub mod resize_grab;
-pub use resize_grab::ResizeSurfaceGrab;
\ No newline at end of file
+pub use resize_grab::ResizeSurfaceGrab;
+
+pub mod screenshot_grab;
+pub use screenshot_grab::ScreenshotRegionGrab;
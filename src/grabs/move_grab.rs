@@ -10,6 +10,8 @@ use smithay::{
     reexports::wayland_server::protocol::wl_surface::WlSurface,
     utils::{Logical, Point},
 };
+#[cfg(feature = "xwayland")]
+use smithay::utils::Rectangle;
 
 pub struct MoveSurfaceGrab {
     pub start_data: PointerGrabStartData<Tsuki>,
@@ -29,9 +31,16 @@ impl PointerGrab<Tsuki> for MoveSurfaceGrab {
         handle.motion(data, None, event);
 
         let delta = event.location - self.start_data.location;
-        let new_location = self.initial_window_location.to_f64() + delta;
+        let new_location = (self.initial_window_location.to_f64() + delta).to_i32_round();
         data.space
-            .map_element(self.window.clone(), new_location.to_i32_round(), true);
+            .map_element(self.window.clone(), new_location, true);
+        data.update_foreign_toplevel(&self.window);
+
+        // X11 clients position their popups in root coordinates, keep the server in sync.
+        #[cfg(feature = "xwayland")]
+        if let Some(x11) = self.window.x11_surface() {
+            let _ = x11.configure(Rectangle::new(new_location, x11.geometry().size));
+        }
     }
 
     fn relative_motion(
@@ -11,7 +11,7 @@ use smithay::{
         wayland_protocols::xdg::shell::server::xdg_toplevel, wayland_server::protocol::wl_surface::WlSurface,
     },
     utils::{Logical, Point, Rectangle, Size},
-    wayland::{compositor, shell::xdg::SurfaceCachedState},
+    wayland::{compositor, seat::WaylandFocus, shell::xdg::SurfaceCachedState},
 };
 use std::cell::RefCell;
 
@@ -57,9 +57,13 @@ impl ResizeSurfaceGrab {
     ) -> Self {
         let initial_rect = initial_window_rect;
 
-        ResizeSurfaceState::with(window.toplevel().unwrap().wl_surface(), |state| {
-            *state = ResizeSurfaceState::Resizing { edges, initial_rect };
-        });
+        // X11 windows are configured synchronously and moved right away in `motion`, only xdg
+        // toplevels have to wait for the commit with the new size.
+        if let Some(toplevel) = window.toplevel() {
+            ResizeSurfaceState::with(toplevel.wl_surface(), |state| {
+                *state = ResizeSurfaceState::Resizing { edges, initial_rect };
+            });
+        }
 
         Self {
             start_data,
@@ -92,7 +96,7 @@ impl PointerGrab<Tsuki> for ResizeSurfaceGrab {
                 delta.x = -delta.x;
             }
 
-            new_window_width = (self.initial_rect.size.w as f64 + delta.x) as i32;
+            new_window_width = (self.initial_rect.size.w as f64 + delta.x).round() as i32;
         }
 
         if self.edges.intersects(ResizeEdge::TOP | ResizeEdge::BOTTOM) {
@@ -100,15 +104,10 @@ impl PointerGrab<Tsuki> for ResizeSurfaceGrab {
                 delta.y = -delta.y;
             }
 
-            new_window_height = (self.initial_rect.size.h as f64 + delta.y) as i32;
+            new_window_height = (self.initial_rect.size.h as f64 + delta.y).round() as i32;
         }
 
-        let (min_size, max_size) =
-            compositor::with_states(self.window.toplevel().unwrap().wl_surface(), |states| {
-                let mut guard = states.cached_state.get::<SurfaceCachedState>();
-                let data = guard.current();
-                (data.min_size, data.max_size)
-            });
+        let (min_size, max_size) = size_constraints(&self.window);
 
         let min_width = min_size.w.max(1);
         let min_height = min_size.h.max(1);
@@ -121,13 +120,28 @@ impl PointerGrab<Tsuki> for ResizeSurfaceGrab {
             new_window_height.max(min_height).min(max_height),
         ));
 
-        let xdg = self.window.toplevel().unwrap();
-        xdg.with_pending_state(|state| {
-            state.states.set(xdg_toplevel::State::Resizing);
-            state.size = Some(self.last_window_size);
-        });
+        if let Some(xdg) = self.window.toplevel() {
+            xdg.with_pending_state(|state| {
+                state.states.set(xdg_toplevel::State::Resizing);
+                state.size = Some(self.last_window_size);
+            });
+
+            xdg.send_pending_configure();
+        }
 
-        xdg.send_pending_configure();
+        #[cfg(feature = "xwayland")]
+        if let Some(x11) = self.window.x11_surface() {
+            let mut location = self.initial_rect.loc;
+            if self.edges.intersects(ResizeEdge::LEFT) {
+                location.x += self.initial_rect.size.w - self.last_window_size.w;
+            }
+            if self.edges.intersects(ResizeEdge::TOP) {
+                location.y += self.initial_rect.size.h - self.last_window_size.h;
+            }
+
+            let _ = x11.configure(Rectangle::new(location, self.last_window_size));
+            data.space.map_element(self.window.clone(), location, false);
+        }
     }
 
     fn relative_motion(
@@ -156,7 +170,9 @@ impl PointerGrab<Tsuki> for ResizeSurfaceGrab {
             // No more buttons are pressed, release the grab.
             handle.unset_grab(self, data, event.serial, event.time, true);
 
-            let xdg = self.window.toplevel().unwrap();
+            let Some(xdg) = self.window.toplevel() else {
+                return;
+            };
             xdg.with_pending_state(|state| {
                 state.states.unset(xdg_toplevel::State::Resizing);
                 state.size = Some(self.last_window_size);
@@ -265,6 +281,23 @@ impl PointerGrab<Tsuki> for ResizeSurfaceGrab {
     fn unset(&mut self, _data: &mut Tsuki) {}
 }
 
+/// The minimum and maximum size the window asked for, zero when unconstrained.
+fn size_constraints(window: &Window) -> (Size<i32, Logical>, Size<i32, Logical>) {
+    #[cfg(feature = "xwayland")]
+    if let Some(x11) = window.x11_surface() {
+        return (x11.min_size().unwrap_or_default(), x11.max_size().unwrap_or_default());
+    }
+
+    let Some(toplevel) = window.toplevel() else {
+        return Default::default();
+    };
+    compositor::with_states(toplevel.wl_surface(), |states| {
+        let mut guard = states.cached_state.get::<SurfaceCachedState>();
+        let data = guard.current();
+        (data.min_size, data.max_size)
+    })
+}
+
 /// State of the resize operation.
 ///
 /// It is stored inside of WlSurface,
@@ -317,7 +350,7 @@ impl ResizeSurfaceState {
 pub fn handle_commit(space: &mut Space<Window>, surface: &WlSurface) -> Option<()> {
     let window = space
         .elements()
-        .find(|w| w.toplevel().unwrap().wl_surface() == surface)
+        .find(|w| w.wl_surface().as_deref() == Some(surface))
         .cloned()?;
 
     let mut window_loc = space.element_location(&window)?;
@@ -0,0 +1,169 @@
+use crate::Tsuki;
+use smithay::{
+    backend::input::ButtonState,
+    input::pointer::{
+        AxisFrame, ButtonEvent, GestureHoldBeginEvent, GestureHoldEndEvent, GesturePinchBeginEvent,
+        GesturePinchEndEvent, GesturePinchUpdateEvent, GestureSwipeBeginEvent, GestureSwipeEndEvent,
+        GestureSwipeUpdateEvent, GrabStartData as PointerGrabStartData, MotionEvent, PointerGrab,
+        PointerInnerHandle, RelativeMotionEvent,
+    },
+    reexports::wayland_server::protocol::wl_surface::WlSurface,
+    utils::{Logical, Point},
+};
+
+const BTN_LEFT: u32 = 0x110;
+const BTN_RIGHT: u32 = 0x111;
+
+/// Drags out the region of a screenshot: press and drag the left button to select, right
+/// button or Escape to cancel.
+pub struct ScreenshotRegionGrab {
+    pub start_data: PointerGrabStartData<Tsuki>,
+}
+
+impl PointerGrab<Tsuki> for ScreenshotRegionGrab {
+    fn motion(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        _focus: Option<(WlSurface, Point<f64, Logical>)>,
+        event: &MotionEvent,
+    ) {
+        // No client gets pointer events while selecting.
+        handle.motion(data, None, event);
+
+        if let Some(selection) = data.region_selection.as_mut() {
+            selection.current = event.location;
+            let output = selection.output.clone();
+            data.queue_redraw(&output);
+        }
+    }
+
+    fn relative_motion(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        _focus: Option<(WlSurface, Point<f64, Logical>)>,
+        event: &RelativeMotionEvent,
+    ) {
+        handle.relative_motion(data, None, event);
+    }
+
+    fn button(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &ButtonEvent,
+    ) {
+        match (event.button, event.state) {
+            (BTN_LEFT, ButtonState::Pressed) => {
+                if let Some(selection) = data.region_selection.as_mut() {
+                    selection.start = Some(handle.current_location());
+                    selection.current = handle.current_location();
+                }
+            }
+            (BTN_LEFT, ButtonState::Released)
+                if data.region_selection.as_ref().is_some_and(|selection| selection.start.is_some()) =>
+            {
+                data.finish_region_selection();
+                handle.unset_grab(self, data, event.serial, event.time, true);
+            }
+            (BTN_RIGHT, ButtonState::Pressed) => {
+                handle.unset_grab(self, data, event.serial, event.time, true);
+            }
+            _ => (),
+        }
+    }
+
+    fn axis(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        details: AxisFrame,
+    ) {
+        handle.axis(data, details)
+    }
+
+    fn frame(&mut self, data: &mut Tsuki, handle: &mut PointerInnerHandle<'_, Tsuki>) {
+        handle.frame(data);
+    }
+
+    fn gesture_swipe_begin(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GestureSwipeBeginEvent,
+    ) {
+        handle.gesture_swipe_begin(data, event)
+    }
+
+    fn gesture_swipe_update(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GestureSwipeUpdateEvent,
+    ) {
+        handle.gesture_swipe_update(data, event)
+    }
+
+    fn gesture_swipe_end(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GestureSwipeEndEvent,
+    ) {
+        handle.gesture_swipe_end(data, event)
+    }
+
+    fn gesture_pinch_begin(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GesturePinchBeginEvent,
+    ) {
+        handle.gesture_pinch_begin(data, event)
+    }
+
+    fn gesture_pinch_update(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GesturePinchUpdateEvent,
+    ) {
+        handle.gesture_pinch_update(data, event)
+    }
+
+    fn gesture_pinch_end(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GesturePinchEndEvent,
+    ) {
+        handle.gesture_pinch_end(data, event)
+    }
+
+    fn gesture_hold_begin(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GestureHoldBeginEvent,
+    ) {
+        handle.gesture_hold_begin(data, event)
+    }
+
+    fn gesture_hold_end(
+        &mut self,
+        data: &mut Tsuki,
+        handle: &mut PointerInnerHandle<'_, Tsuki>,
+        event: &GestureHoldEndEvent,
+    ) {
+        handle.gesture_hold_end(data, event)
+    }
+
+    fn start_data(&self) -> &PointerGrabStartData<Tsuki> {
+        &self.start_data
+    }
+
+    fn unset(&mut self, data: &mut Tsuki) {
+        data.cancel_region_selection();
+    }
+}
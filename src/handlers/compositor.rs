@@ -1,7 +1,12 @@
 use crate::{grabs::resize_grab, state::ClientState, Tsuki};
 use smithay::{
-    backend::renderer::utils::on_commit_buffer_handler,
-    delegate_compositor, delegate_shm,
+    backend::{
+        allocator::dmabuf::Dmabuf,
+        renderer::{utils::on_commit_buffer_handler, ImportDma},
+    },
+    delegate_compositor, delegate_dmabuf, delegate_fractional_scale, delegate_presentation, delegate_shm,
+    delegate_viewporter,
+    desktop::find_popup_root_surface,
     reexports::wayland_server::{
         protocol::{wl_buffer, wl_surface::WlSurface},
         Client,
@@ -9,12 +14,19 @@ use smithay::{
     wayland::{
         buffer::BufferHandler,
         compositor::{
-            get_parent, is_sync_subsurface, CompositorClientState, CompositorHandler, CompositorState,
+            get_parent, is_sync_subsurface, with_states, CompositorClientState, CompositorHandler,
+            CompositorState, SurfaceAttributes,
         },
+        dmabuf::{DmabufGlobal, DmabufHandler, DmabufState, ImportNotifier},
+        fractional_scale::{with_fractional_scale, FractionalScaleHandler},
+        seat::WaylandFocus,
         shm::{ShmHandler, ShmState},
     },
 };
 
+#[cfg(feature = "xwayland")]
+use smithay::xwayland::XWaylandClientData;
+
 use super::xdg_shell;
 
 impl CompositorHandler for Tsuki {
@@ -23,28 +35,52 @@ impl CompositorHandler for Tsuki {
     }
 
     fn client_compositor_state<'a>(&self, client: &'a Client) -> &'a CompositorClientState {
+        #[cfg(feature = "xwayland")]
+        if let Some(state) = client.get_data::<XWaylandClientData>() {
+            return &state.compositor_state;
+        }
         &client.get_data::<ClientState>().unwrap().compositor_state
     }
 
     fn commit(&mut self, surface: &WlSurface) {
         on_commit_buffer_handler::<Self>(surface);
+        let mut window = None;
         if !is_sync_subsurface(surface) {
             let mut root = surface.clone();
             while let Some(parent) = get_parent(&root) {
                 root = parent;
             }
-            if let Some(window) = self
-                .space
-                .elements()
-                .find(|w| w.toplevel().unwrap().wl_surface() == &root)
-            {
+            window = self
+                .toplevel_windows()
+                .find(|w| w.wl_surface().as_deref() == Some(&root))
+                .cloned();
+            if let Some(window) = &window {
                 window.on_commit();
             }
         };
 
+        if let Some(icon) = self.dnd_icon.as_mut().filter(|icon| &icon.surface == surface) {
+            let delta = with_states(surface, |states| {
+                states
+                    .cached_state
+                    .get::<SurfaceAttributes>()
+                    .current()
+                    .buffer_delta
+                    .take()
+            });
+            icon.offset += delta.unwrap_or_default();
+        }
+
         xdg_shell::handle_commit(&mut self.popups, &self.space, surface);
         resize_grab::handle_commit(&mut self.space, surface);
-        self.queue_redraw();
+        // Titles, app ids and sizes come with a commit, resizing from the top or the left
+        // moves the window too.
+        if let Some(window) = window {
+            self.update_foreign_toplevel(&window);
+        }
+        // Window captures don't depend on the window being on screen.
+        self.capture_state.check_damage();
+        self.queue_redraw_all();
     }
 }
 
@@ -58,5 +94,65 @@ impl ShmHandler for Tsuki {
     }
 }
 
+impl DmabufHandler for Tsuki {
+    fn dmabuf_state(&mut self) -> &mut DmabufState {
+        &mut self.dmabuf_state
+    }
+
+    fn dmabuf_imported(&mut self, _global: &DmabufGlobal, dmabuf: Dmabuf, notifier: ImportNotifier) {
+        // Importing up front lets the client know right away when its buffer can't be used,
+        // instead of the surface failing to render later.
+        let backend = self.backend_data.clone();
+        let mut backend = backend.borrow_mut();
+        let imported = match backend.renderer() {
+            Some(renderer) => renderer.import_dmabuf(&dmabuf, None).map_err(|err| err.to_string()),
+            None => Err("no renderer".to_owned()),
+        };
+
+        match imported {
+            Ok(_) => {
+                let _ = notifier.successful::<Tsuki>();
+            }
+            Err(err) => {
+                log::debug!("rejecting dmabuf: {err}");
+                notifier.failed();
+            }
+        }
+    }
+}
+
+impl FractionalScaleHandler for Tsuki {
+    fn new_fractional_scale(&mut self, surface: WlSurface) {
+        // Use the output of the window the surface belongs to, later changes are sent after
+        // each redraw.
+        let mut root = surface.clone();
+        while let Some(parent) = get_parent(&root) {
+            root = parent;
+        }
+        if let Some(popup) = self.popups.find_popup(&root) {
+            root = find_popup_root_surface(&popup).unwrap_or(root);
+        }
+
+        let output = self
+            .space
+            .elements()
+            .find(|w| w.wl_surface().as_deref() == Some(&root))
+            .and_then(|window| self.output_for_window(window))
+            .or_else(|| self.space.outputs().next().cloned());
+
+        if let Some(output) = output {
+            with_states(&surface, |states| {
+                with_fractional_scale(states, |fractional_scale| {
+                    fractional_scale.set_preferred_scale(output.current_scale().fractional_scale());
+                });
+            });
+        }
+    }
+}
+
 delegate_compositor!(Tsuki);
-delegate_shm!(Tsuki);
\ No newline at end of file
+delegate_shm!(Tsuki);
+delegate_dmabuf!(Tsuki);
+delegate_presentation!(Tsuki);
+delegate_fractional_scale!(Tsuki);
+delegate_viewporter!(Tsuki);
\ No newline at end of file
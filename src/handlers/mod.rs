@@ -1,24 +1,85 @@
 mod compositor;
 mod xdg_shell;
 
+use std::{os::fd::OwnedFd, time::Duration};
+
+use crate::capture::{BufferConstraints, CaptureFrame, DmabufConstraints};
+use crate::clipboard::SelectionContents;
+use crate::config::OutputConfig;
+use crate::protocols::image_copy_capture::{
+    ImageCaptureSource, ImageCopyCaptureHandler, ImageCopyCaptureState, ImageCopyFrame,
+};
+use crate::protocols::screencopy::{ScreencopyFrame, ScreencopyHandler, ScreencopyState};
+use crate::protocols::foreign_toplevel::{ForeignToplevelHandler, ForeignToplevelManagerState};
+use crate::protocols::output_power::{OutputPowerHandler, OutputPowerState};
+use crate::{
+    delegate_foreign_toplevel_management, delegate_image_copy_capture,
+    delegate_output_management, delegate_output_power, delegate_screencopy,
+};
+use crate::focus::KeyboardFocusTarget;
+use crate::protocols::output_management::{OutputManagementHandler, OutputManagementState};
+use crate::state::DndIcon;
 use crate::Tsuki;
 
 //
 // Wl Seat
 //
 
+use smithay::backend::input::TabletToolDescriptor;
+use smithay::desktop::{PopupUngrabStrategy, Window};
+use smithay::input::pointer::CursorImageStatus;
 use smithay::input::{Seat, SeatHandler, SeatState};
-use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
-use smithay::reexports::wayland_server::Resource;
+use smithay::output::Output;
+use smithay::reexports::wayland_protocols::ext::data_control::v1::server::{
+    ext_data_control_device_v1::ExtDataControlDeviceV1, ext_data_control_manager_v1::ExtDataControlManagerV1,
+    ext_data_control_source_v1::ExtDataControlSourceV1,
+};
+use smithay::reexports::wayland_protocols::wp::primary_selection::zv1::server::{
+    zwp_primary_selection_device_manager_v1::ZwpPrimarySelectionDeviceManagerV1,
+    zwp_primary_selection_device_v1::ZwpPrimarySelectionDeviceV1,
+    zwp_primary_selection_source_v1::ZwpPrimarySelectionSourceV1,
+};
+use smithay::reexports::wayland_protocols_wlr::data_control::v1::server::{
+    zwlr_data_control_device_v1::ZwlrDataControlDeviceV1, zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_source_v1::ZwlrDataControlSourceV1,
+};
+use smithay::reexports::wayland_server::backend::ClientId;
+use smithay::reexports::wayland_server::protocol::{
+    wl_data_device::WlDataDevice, wl_data_device_manager::WlDataDeviceManager, wl_data_source::WlDataSource,
+    wl_output::WlOutput, wl_surface::WlSurface,
+};
+use smithay::reexports::wayland_protocols::ext::idle_notify::v1::server::{
+    ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+    ext_idle_notifier_v1::{self, ExtIdleNotifierV1},
+};
+use smithay::reexports::wayland_server::{
+    delegate_dispatch, delegate_global_dispatch, Client, DataInit, Dispatch, DisplayHandle, Resource,
+};
+use smithay::wayland::foreign_toplevel_list::{ForeignToplevelListHandler, ForeignToplevelListState};
 use smithay::wayland::output::OutputHandler;
 use smithay::wayland::selection::data_device::{
-    set_data_device_focus, ClientDndGrabHandler, DataDeviceHandler, DataDeviceState, ServerDndGrabHandler,
+    set_data_device_focus, ClientDndGrabHandler, DataDeviceHandler, DataDeviceState, DataDeviceUserData,
+    DataSourceUserData, ServerDndGrabHandler,
+};
+use smithay::wayland::selection::primary_selection::{
+    set_primary_focus, PrimaryDeviceManagerGlobalData, PrimaryDeviceUserData, PrimarySelectionHandler,
+    PrimarySelectionState, PrimarySourceUserData,
+};
+use smithay::wayland::selection::{
+    ext_data_control, wlr_data_control, SelectionHandler, SelectionSource, SelectionTarget,
+};
+use smithay::utils::SERIAL_COUNTER;
+use smithay::wayland::idle_inhibit::IdleInhibitHandler;
+use smithay::wayland::idle_notify::{IdleNotificationUserData, IdleNotifierHandler, IdleNotifierState};
+use smithay::wayland::session_lock::{LockSurface, SessionLockHandler, SessionLockManagerState, SessionLocker};
+use smithay::wayland::tablet_manager::TabletSeatHandler;
+use smithay::{
+    delegate_cursor_shape, delegate_foreign_toplevel_list, delegate_idle_inhibit, delegate_output,
+    delegate_seat, delegate_session_lock, delegate_tablet_manager,
 };
-use smithay::wayland::selection::SelectionHandler;
-use smithay::{delegate_data_device, delegate_output, delegate_seat};
 
 impl SeatHandler for Tsuki {
-    type KeyboardFocus = WlSurface;
+    type KeyboardFocus = KeyboardFocusTarget;
     type PointerFocus = WlSurface;
     type TouchFocus = WlSurface;
 
@@ -26,23 +87,104 @@ impl SeatHandler for Tsuki {
         &mut self.seat_state
     }
 
-    fn cursor_image(&mut self, _seat: &Seat<Self>, _image: smithay::input::pointer::CursorImageStatus) {}
+    fn cursor_image(&mut self, _seat: &Seat<Self>, image: CursorImageStatus) {
+        self.cursor_manager.set_image(image);
+        self.queue_redraw_all();
+    }
+
+    fn focus_changed(&mut self, seat: &Seat<Self>, focused: Option<&KeyboardFocusTarget>) {
+        // Focus moving to another client dismisses the popups grabbing input, their grabs end
+        // with the next input event.
+        if let Some(mut grab) = self.popup_grab.take() {
+            let same_client = focused.is_some_and(|focused| {
+                grab.current_grab()
+                    .is_some_and(|current| current.surface().id().same_client_as(&focused.surface().id()))
+            });
+
+            if same_client {
+                self.popup_grab = Some(grab);
+            } else {
+                grab.ungrab(PopupUngrabStrategy::All);
+            }
+        }
 
-    fn focus_changed(&mut self, seat: &Seat<Self>, focused: Option<&WlSurface>) {
         let dh = &self.display_handle;
-        let client = focused.and_then(|s| dh.get_client(s.id()).ok());
-        set_data_device_focus(dh, seat, client);
+        let client = focused.and_then(|target| dh.get_client(target.surface().id()).ok());
+        set_data_device_focus(dh, seat, client.clone());
+        set_primary_focus(dh, seat, client);
+
+        // The keyboard is still locked here, the activated states are read from it once it's
+        // done.
+        self.event_loop
+            .insert_idle(|data| data.tsuki.update_foreign_toplevels());
+    }
+}
+
+impl TabletSeatHandler for Tsuki {
+    fn tablet_tool_image(&mut self, _tool: &TabletToolDescriptor, image: CursorImageStatus) {
+        // There is only one cursor on screen, tablet tools share it with the pointer.
+        self.cursor_manager.set_image(image);
+        self.queue_redraw_all();
     }
 }
 
 delegate_seat!(Tsuki);
+delegate_tablet_manager!(Tsuki);
+delegate_cursor_shape!(Tsuki);
 
 //
 // Wl Data Device
 //
 
+/// Like the selection delegate macros of smithay, except that requests on the device note their
+/// client first. The client owns any selection set by the request, `new_selection` doesn't say.
+macro_rules! delegate_selection {
+    ($state:ty: $manager:ty: $global_data:ty, $manager_data:ty; $device:ty: $device_data:ty; $source:ty: $source_data:ty) => {
+        delegate_global_dispatch!(Tsuki: [$manager: $global_data] => $state);
+        delegate_dispatch!(Tsuki: [$manager: $manager_data] => $state);
+        delegate_dispatch!(Tsuki: [$source: $source_data] => $state);
+
+        impl Dispatch<$device, $device_data> for Tsuki {
+            fn request(
+                state: &mut Self,
+                client: &Client,
+                resource: &$device,
+                request: <$device as Resource>::Request,
+                data: &$device_data,
+                dhandle: &DisplayHandle,
+                data_init: &mut DataInit<'_, Self>,
+            ) {
+                state.clipboard_cache.requesting_client = Some(client.id());
+                <$state as Dispatch<$device, $device_data, Self>>::request(
+                    state, client, resource, request, data, dhandle, data_init,
+                );
+                state.clipboard_cache.requesting_client = None;
+            }
+
+            fn destroyed(state: &mut Self, client: ClientId, resource: &$device, data: &$device_data) {
+                <$state as Dispatch<$device, $device_data, Self>>::destroyed(state, client, resource, data);
+            }
+        }
+    };
+}
+
 impl SelectionHandler for Tsuki {
-    type SelectionUserData = ();
+    type SelectionUserData = SelectionContents;
+
+    fn new_selection(&mut self, ty: SelectionTarget, source: Option<SelectionSource>, seat: Seat<Self>) {
+        self.cache_selection(ty, source, &seat);
+    }
+
+    fn send_selection(
+        &mut self,
+        _ty: SelectionTarget,
+        mime_type: String,
+        fd: OwnedFd,
+        _seat: Seat<Self>,
+        user_data: &SelectionContents,
+    ) {
+        self.send_cached_selection(mime_type, fd, user_data);
+    }
 }
 
 impl DataDeviceHandler for Tsuki {
@@ -51,14 +193,335 @@ impl DataDeviceHandler for Tsuki {
     }
 }
 
-impl ClientDndGrabHandler for Tsuki {}
+impl ClientDndGrabHandler for Tsuki {
+    fn started(&mut self, _source: Option<WlDataSource>, icon: Option<WlSurface>, _seat: Seat<Self>) {
+        self.dnd_icon = icon.map(|surface| DndIcon {
+            surface,
+            offset: (0, 0).into(),
+        });
+        self.queue_redraw_all();
+    }
+
+    // Called for cancelled drags too.
+    fn dropped(&mut self, _target: Option<WlSurface>, _validated: bool, _seat: Seat<Self>) {
+        self.dnd_icon = None;
+        self.queue_redraw_all();
+    }
+}
+
+// Tsuki never starts drags itself.
 impl ServerDndGrabHandler for Tsuki {}
 
-delegate_data_device!(Tsuki);
+delegate_selection!(DataDeviceState:
+    WlDataDeviceManager: (), ();
+    WlDataDevice: DataDeviceUserData;
+    WlDataSource: DataSourceUserData);
+
+//
+// Primary Selection
+//
+
+impl PrimarySelectionHandler for Tsuki {
+    fn primary_selection_state(&self) -> &PrimarySelectionState {
+        &self.primary_selection_state
+    }
+}
+
+delegate_selection!(PrimarySelectionState:
+    ZwpPrimarySelectionDeviceManagerV1: PrimaryDeviceManagerGlobalData, ();
+    ZwpPrimarySelectionDeviceV1: PrimaryDeviceUserData;
+    ZwpPrimarySelectionSourceV1: PrimarySourceUserData);
+
+//
+// Wlr & Ext Data Control
+//
+
+impl wlr_data_control::DataControlHandler for Tsuki {
+    fn data_control_state(&self) -> &wlr_data_control::DataControlState {
+        &self.wlr_data_control_state
+    }
+}
+
+impl ext_data_control::DataControlHandler for Tsuki {
+    fn data_control_state(&self) -> &ext_data_control::DataControlState {
+        &self.ext_data_control_state
+    }
+}
+
+delegate_selection!(wlr_data_control::DataControlState:
+    ZwlrDataControlManagerV1: wlr_data_control::DataControlManagerGlobalData, wlr_data_control::DataControlManagerUserData;
+    ZwlrDataControlDeviceV1: wlr_data_control::DataControlDeviceUserData;
+    ZwlrDataControlSourceV1: wlr_data_control::DataControlSourceUserData);
+delegate_selection!(ext_data_control::DataControlState:
+    ExtDataControlManagerV1: ext_data_control::ExtDataControlManagerGlobalData, ext_data_control::ExtDataControlManagerUserData;
+    ExtDataControlDeviceV1: ext_data_control::ExtDataControlDeviceUserData;
+    ExtDataControlSourceV1: ext_data_control::ExtDataControlSourceUserData);
 
 //
 // Wl Output & Xdg Output
 //
 
-impl OutputHandler for Tsuki {}
-delegate_output!(Tsuki);
\ No newline at end of file
+impl OutputHandler for Tsuki {
+    fn output_bound(&mut self, output: Output, wl_output: WlOutput) {
+        self.foreign_toplevel_manager_state.output_bound(&output, &wl_output);
+    }
+}
+delegate_output!(Tsuki);
+
+//
+// Wlr Output Management
+//
+
+impl OutputManagementHandler for Tsuki {
+    fn output_management_state(&mut self) -> &mut OutputManagementState {
+        &mut self.output_management_state
+    }
+
+    fn apply_output_configuration(&mut self, configs: Vec<OutputConfig>, test_only: bool) -> bool {
+        // Tests go through the same checks, so that a configuration that tested fine applies.
+        if let Err(err) = self.check_output_configuration(&configs) {
+            log::warn!("rejecting output configuration: {err}");
+            return false;
+        }
+
+        if test_only {
+            return true;
+        }
+
+        // Runtime changes go first so they win over entries matching by make/model/serial.
+        let previous = self.config.outputs.clone();
+        for config in configs {
+            self.config.outputs.retain(|existing| existing.name != config.name);
+            self.config.outputs.insert(0, config);
+        }
+
+        let backend = self.backend_data.clone();
+        let res = backend.borrow_mut().apply_output_config(self);
+        if let Err(err) = res {
+            log::warn!("error applying output configuration: {err:?}");
+            // The backend put back the modes, going back to the previous config undoes the
+            // rest.
+            self.config.outputs = previous;
+            if let Err(err) = backend.borrow_mut().apply_output_config(self) {
+                log::error!("error restoring the output configuration: {err:?}");
+            }
+            return false;
+        }
+        true
+    }
+}
+delegate_output_management!(Tsuki);
+//
+// Foreign toplevels
+//
+
+impl ForeignToplevelListHandler for Tsuki {
+    fn foreign_toplevel_list_state(&mut self) -> &mut ForeignToplevelListState {
+        &mut self.foreign_toplevel_list_state
+    }
+}
+delegate_foreign_toplevel_list!(Tsuki);
+
+impl ForeignToplevelHandler for Tsuki {
+    fn foreign_toplevel_manager_state(&mut self) -> &mut ForeignToplevelManagerState {
+        &mut self.foreign_toplevel_manager_state
+    }
+
+    fn activate(&mut self, window: Window) {
+        self.focus_window(&window, SERIAL_COUNTER.next_serial());
+    }
+
+    fn close(&mut self, window: Window) {
+        self.close_window(&window);
+    }
+
+    fn set_maximized(&mut self, window: Window, maximized: bool) {
+        self.set_window_maximized(&window, maximized);
+    }
+
+    fn set_minimized(&mut self, window: Window, minimized: bool) {
+        self.set_window_minimized(&window, minimized);
+    }
+
+    fn set_fullscreen(&mut self, window: Window, fullscreen: bool, output: Option<Output>) {
+        self.set_window_fullscreen(&window, fullscreen, output);
+    }
+}
+delegate_foreign_toplevel_management!(Tsuki);
+
+//
+// Session Lock
+//
+
+impl SessionLockHandler for Tsuki {
+    fn lock_state(&mut self) -> &mut SessionLockManagerState {
+        &mut self.session_lock_state
+    }
+
+    fn lock(&mut self, confirmation: SessionLocker) {
+        self.lock_session(confirmation);
+    }
+
+    fn unlock(&mut self) {
+        self.unlock_session();
+    }
+
+    fn new_surface(&mut self, surface: LockSurface, output: WlOutput) {
+        if let Some(output) = Output::from_resource(&output) {
+            self.add_lock_surface(surface, &output);
+        }
+    }
+}
+delegate_session_lock!(Tsuki);
+
+//
+// Idle Notify & Idle Inhibit
+//
+
+impl IdleNotifierHandler for Tsuki {
+    fn idle_notifier_state(&mut self) -> &mut IdleNotifierState<Self> {
+        &mut self.idle_notifier_state
+    }
+}
+
+delegate_global_dispatch!(Tsuki: [ExtIdleNotifierV1: ()] => IdleNotifierState<Tsuki>);
+
+// Not delegated, the timeouts of new notifications have to be known to wake up for them.
+impl Dispatch<ExtIdleNotifierV1, ()> for Tsuki {
+    fn request(
+        state: &mut Self,
+        client: &Client,
+        resource: &ExtIdleNotifierV1,
+        request: ext_idle_notifier_v1::Request,
+        data: &(),
+        dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let timeout = match request {
+            ext_idle_notifier_v1::Request::GetIdleNotification { timeout, .. }
+            | ext_idle_notifier_v1::Request::GetInputIdleNotification { timeout, .. } => Some(timeout),
+            _ => None,
+        };
+        <IdleNotifierState<Self> as Dispatch<ExtIdleNotifierV1, (), Self>>::request(
+            state, client, resource, request, data, dhandle, data_init,
+        );
+        if let Some(timeout) = timeout {
+            state.idle_notification_created(Duration::from_millis(timeout.into()));
+        }
+    }
+}
+
+// Not delegated either, the timeouts of destroyed notifications have to be dropped.
+impl Dispatch<ExtIdleNotificationV1, IdleNotificationUserData> for Tsuki {
+    fn request(
+        state: &mut Self,
+        client: &Client,
+        resource: &ExtIdleNotificationV1,
+        request: ext_idle_notification_v1::Request,
+        data: &IdleNotificationUserData,
+        dhandle: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        <IdleNotifierState<Self> as Dispatch<ExtIdleNotificationV1, IdleNotificationUserData, Self>>::request(
+            state, client, resource, request, data, dhandle, data_init,
+        );
+    }
+
+    fn destroyed(
+        state: &mut Self,
+        client: ClientId,
+        resource: &ExtIdleNotificationV1,
+        data: &IdleNotificationUserData,
+    ) {
+        <IdleNotifierState<Self> as Dispatch<ExtIdleNotificationV1, IdleNotificationUserData, Self>>::destroyed(
+            state, client, resource, data,
+        );
+        state.idle_notification_destroyed(data);
+    }
+}
+
+impl IdleInhibitHandler for Tsuki {
+    fn inhibit(&mut self, surface: WlSurface) {
+        self.idle_inhibitors.insert(surface);
+        self.refresh_idle_inhibit();
+    }
+
+    fn uninhibit(&mut self, surface: WlSurface) {
+        self.idle_inhibitors.remove(&surface);
+        self.refresh_idle_inhibit();
+    }
+}
+delegate_idle_inhibit!(Tsuki);
+
+//
+// Wlr Output Power Management
+//
+
+impl OutputPowerHandler for Tsuki {
+    fn output_power_state(&mut self) -> &mut OutputPowerState {
+        &mut self.output_power_state
+    }
+
+    fn output_power(&mut self, output: &Output) -> Option<bool> {
+        self.output_state.get(output).map(|state| state.powered)
+    }
+
+    fn set_output_power(&mut self, output: &Output, on: bool) {
+        Tsuki::set_output_power(self, output, on);
+    }
+}
+delegate_output_power!(Tsuki);
+
+//
+// Screencopy
+//
+
+impl ScreencopyHandler for Tsuki {
+    fn screencopy_state(&mut self) -> &mut ScreencopyState {
+        &mut self.screencopy_state
+    }
+
+    fn dmabuf_constraints(&mut self) -> Option<DmabufConstraints> {
+        self.capture_state.dmabuf.clone()
+    }
+
+    fn frame(&mut self, frame: ScreencopyFrame) {
+        self.capture_state.queue(CaptureFrame::Screencopy(frame));
+    }
+}
+delegate_screencopy!(Tsuki);
+
+impl ImageCopyCaptureHandler for Tsuki {
+    fn image_copy_capture_state(&mut self) -> &mut ImageCopyCaptureState {
+        &mut self.image_copy_capture_state
+    }
+
+    fn capture_constraints(&mut self, source: &ImageCaptureSource) -> Option<BufferConstraints> {
+        let size = match source {
+            ImageCaptureSource::Output(output) => {
+                let output = output.upgrade()?;
+                if !self.space.outputs().any(|o| o == &output) {
+                    return None;
+                }
+                let size = output.current_mode()?.size;
+                (size.w, size.h).into()
+            }
+            ImageCaptureSource::Toplevel(handle) => {
+                let handle = handle.upgrade().filter(|handle| !handle.is_closed())?;
+                let window = self.window_for_foreign_toplevel(&handle)?;
+                let size = self.window_capture_geometry(&window).0.size;
+                (size.w, size.h).into()
+            }
+        };
+
+        Some(BufferConstraints {
+            size,
+            dmabuf: self.capture_state.dmabuf.clone(),
+        })
+    }
+
+    fn frame(&mut self, frame: ImageCopyFrame) {
+        self.capture_state.queue(CaptureFrame::ImageCopy(frame));
+    }
+}
+delegate_image_copy_capture!(Tsuki);
@@ -1,6 +1,10 @@
 use smithay::{
     delegate_xdg_shell,
-    desktop::{find_popup_root_surface, get_popup_toplevel_coords, PopupKind, PopupManager, Space, Window},
+    desktop::{
+        find_popup_root_surface, get_popup_toplevel_coords, PopupKeyboardGrab, PopupKind, PopupManager,
+        PopupPointerGrab, PopupUngrabStrategy, Space, Window,
+    },
+    output::Output,
     input::{
         pointer::{Focus, GrabStartData as PointerGrabStartData},
         Seat,
@@ -8,13 +12,14 @@ use smithay::{
     reexports::{
         wayland_protocols::xdg::shell::server::xdg_toplevel,
         wayland_server::{
-            protocol::{wl_seat, wl_surface::WlSurface},
+            protocol::{wl_output, wl_seat, wl_surface::WlSurface},
             Resource,
         },
     },
     utils::{Rectangle, Serial},
     wayland::{
         compositor::with_states,
+        seat::WaylandFocus,
         shell::xdg::{
             PopupSurface, PositionerState, ToplevelSurface, XdgShellHandler, XdgShellState,
             XdgToplevelSurfaceData,
@@ -42,6 +47,36 @@ impl XdgShellHandler for Tsuki {
         let _ = self.popups.track_popup(PopupKind::Xdg(surface));
     }
 
+    fn toplevel_destroyed(&mut self, surface: ToplevelSurface) {
+        let Some(window) = self.window_for_toplevel(&surface) else {
+            return;
+        };
+
+        // Nothing commits after the window is gone, so repaint the outputs it was on here.
+        let outputs = self.space.outputs_for_element(&window);
+        self.space.unmap_elem(&window);
+        self.minimized_windows.retain(|w| w != &window);
+        self.remove_foreign_toplevel(&window);
+        for output in outputs {
+            self.queue_redraw(&output);
+        }
+    }
+
+    fn popup_destroyed(&mut self, surface: PopupSurface) {
+        let Ok(root) = find_popup_root_surface(&PopupKind::Xdg(surface)) else {
+            return;
+        };
+        let outputs: Vec<_> = self
+            .space
+            .elements()
+            .filter(|w| w.wl_surface().as_deref() == Some(&root))
+            .flat_map(|w| self.space.outputs_for_element(w))
+            .collect();
+        for output in outputs {
+            self.queue_redraw(&output);
+        }
+    }
+
     fn reposition_request(&mut self, surface: PopupSurface, positioner: PositionerState, token: u32) {
         surface.with_pending_state(|state| {
             let geometry = positioner.get_geometry();
@@ -63,7 +98,7 @@ impl XdgShellHandler for Tsuki {
             let window = self
                 .space
                 .elements()
-                .find(|w| w.toplevel().unwrap().wl_surface() == wl_surface)
+                .find(|w| w.wl_surface().as_deref() == Some(wl_surface))
                 .unwrap()
                 .clone();
             let initial_window_location = self.space.element_location(&window).unwrap();
@@ -95,7 +130,7 @@ impl XdgShellHandler for Tsuki {
             let window = self
                 .space
                 .elements()
-                .find(|w| w.toplevel().unwrap().wl_surface() == wl_surface)
+                .find(|w| w.wl_surface().as_deref() == Some(wl_surface))
                 .unwrap()
                 .clone();
             let initial_window_location = self.space.element_location(&window).unwrap();
@@ -119,11 +154,82 @@ impl XdgShellHandler for Tsuki {
     }
 
     fn maximize_request(&mut self, surface: ToplevelSurface) {
-        
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_window_maximized(&window, true);
+        }
     }
 
-    fn grab(&mut self, _surface: PopupSurface, _seat: wl_seat::WlSeat, _serial: Serial) {
-        // TODO popup grabs
+    fn unmaximize_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_window_maximized(&window, false);
+        }
+    }
+
+    fn fullscreen_request(&mut self, surface: ToplevelSurface, output: Option<wl_output::WlOutput>) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            let output = output.as_ref().and_then(Output::from_resource);
+            self.set_window_fullscreen(&window, true, output);
+        }
+    }
+
+    fn unfullscreen_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_window_fullscreen(&window, false, None);
+        }
+    }
+
+    fn minimize_request(&mut self, surface: ToplevelSurface) {
+        if let Some(window) = self.window_for_toplevel(&surface) {
+            self.set_window_minimized(&window, true);
+        }
+    }
+
+    fn grab(&mut self, surface: PopupSurface, seat: wl_seat::WlSeat, serial: Serial) {
+        if self.is_locked() {
+            return;
+        }
+
+        let seat: Seat<Tsuki> = Seat::from_resource(&seat).unwrap();
+        let kind = PopupKind::Xdg(surface);
+
+        let Some(root) = find_popup_root_surface(&kind).ok().filter(|root| {
+            self.space
+                .elements()
+                .any(|w| w.wl_surface().as_deref() == Some(root))
+        }) else {
+            return;
+        };
+
+        let Ok(mut grab) = self.popups.grab_popup(root.into(), kind, &seat, serial) else {
+            return;
+        };
+
+        // The grab is only allowed in response to an input event that the client received,
+        // or when nesting inside an already grabbed popup.
+        if let Some(keyboard) = seat.get_keyboard() {
+            if keyboard.is_grabbed()
+                && !(keyboard.has_grab(serial)
+                    || keyboard.has_grab(grab.previous_serial().unwrap_or(serial)))
+            {
+                grab.ungrab(PopupUngrabStrategy::All);
+                return;
+            }
+            keyboard.set_focus(self, grab.current_grab(), serial);
+            keyboard.set_grab(self, PopupKeyboardGrab::new(&grab), serial);
+        }
+
+        if let Some(pointer) = seat.get_pointer() {
+            if pointer.is_grabbed()
+                && !(pointer.has_grab(serial)
+                    || pointer.has_grab(grab.previous_serial().unwrap_or_else(|| grab.serial())))
+            {
+                grab.ungrab(PopupUngrabStrategy::All);
+                return;
+            }
+            pointer.set_grab(self, PopupPointerGrab::new(&grab), serial, Focus::Keep);
+        }
+
+        self.popup_grab = Some(grab);
     }
 }
 
@@ -156,9 +262,9 @@ fn check_grab(
 /// Should be called on `WlSurface::commit`
 pub fn handle_commit(popups: &mut PopupManager, space: &Space<Window>, surface: &WlSurface) {
     // Handle toplevel commits.
-    if let Some(window) = space
+    if let Some(toplevel) = space
         .elements()
-        .find(|w| w.toplevel().unwrap().wl_surface() == surface)
+        .find_map(|w| w.toplevel().filter(|toplevel| toplevel.wl_surface() == surface))
         .cloned()
     {
         let initial_configure_sent = with_states(surface, |states| {
@@ -172,7 +278,7 @@ pub fn handle_commit(popups: &mut PopupManager, space: &Space<Window>, surface:
         });
 
         if !initial_configure_sent {
-            window.toplevel().unwrap().send_configure();
+            toplevel.send_configure();
         }
     }
 
@@ -193,6 +299,12 @@ pub fn handle_commit(popups: &mut PopupManager, space: &Space<Window>, surface:
 }
 
 impl Tsuki {
+    fn window_for_toplevel(&self, surface: &ToplevelSurface) -> Option<Window> {
+        self.toplevel_windows()
+            .find(|w| w.toplevel() == Some(surface))
+            .cloned()
+    }
+
     fn unconstrain_popup(&self, popup: &PopupSurface) {
         let Ok(root) = find_popup_root_surface(&PopupKind::Xdg(popup.clone())) else {
             return;
@@ -200,18 +312,32 @@ impl Tsuki {
         let Some(window) = self
             .space
             .elements()
-            .find(|w| w.toplevel().unwrap().wl_surface() == &root)
+            .find(|w| w.wl_surface().as_deref() == Some(&root))
         else {
             return;
         };
 
-        let output = self.space.outputs().next().unwrap();
-        let output_geo = self.space.output_geometry(output).unwrap();
-        let window_geo = self.space.element_geometry(window).unwrap();
+        let Some(window_geo) = self.space.element_geometry(window) else {
+            return;
+        };
+
+        // Keep the popup within the outputs its window is on, a window spanning two outputs
+        // can open popups on either of them.
+        let Some(outputs_geo) = self
+            .space
+            .outputs_for_element(window)
+            .iter()
+            .filter_map(|output| self.space.output_geometry(output))
+            .filter(|geo| geo.overlaps(window_geo))
+            .reduce(|union, geo| union.merge(geo))
+            .or_else(|| self.space.outputs().next().and_then(|output| self.space.output_geometry(output)))
+        else {
+            return;
+        };
 
         // The target geometry for the positioner should be relative to its parent's geometry, so
         // we will compute that here.
-        let mut target = output_geo;
+        let mut target = outputs_geo;
         target.loc -= get_popup_toplevel_coords(&PopupKind::Xdg(popup.clone()));
         target.loc -= window_geo.loc;
 
@@ -0,0 +1,149 @@
+//! Idle notifications for tools like swayidle, held back by the idle inhibitors of visible
+//! surfaces.
+//!
+//! smithay's [`IdleNotifierState`] arms its timers on a loop whose data is [`Tsuki`], which gets
+//! a nested loop of its own. Timers don't wake up the loop that dispatches the nested one, so
+//! the timeouts clients asked for are tracked here to dispatch it when one of them can be due.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use smithay::{
+    desktop::utils::surface_primary_scanout_output,
+    reexports::{
+        calloop::{
+            timer::{TimeoutAction, Timer},
+            EventLoop, RegistrationToken,
+        },
+        wayland_server::{DisplayHandle, Resource},
+    },
+    wayland::idle_notify::{IdleNotificationUserData, IdleNotifierState},
+};
+
+use crate::Tsuki;
+
+pub struct IdleTimers {
+    event_loop: Rc<RefCell<EventLoop<'static, Tsuki>>>,
+    /// The timeouts of the live notifications, with how many of them use each.
+    pub(crate) timeouts: BTreeMap<Duration, usize>,
+    /// When the timers armed on the nested loop come due, at the latest.
+    deadlines: BTreeSet<Instant>,
+    token: Option<RegistrationToken>,
+}
+
+impl IdleTimers {
+    pub fn new(display: &DisplayHandle) -> (Self, IdleNotifierState<Tsuki>) {
+        let event_loop = EventLoop::try_new().unwrap();
+        let idle_notifier_state = IdleNotifierState::new(display, event_loop.handle());
+        let timers = Self {
+            event_loop: Rc::new(RefCell::new(event_loop)),
+            timeouts: BTreeMap::new(),
+            deadlines: BTreeSet::new(),
+            token: None,
+        };
+        (timers, idle_notifier_state)
+    }
+}
+
+impl Tsuki {
+    /// Should be called for every input event.
+    pub fn notify_activity(&mut self) {
+        self.idle_notifier_state.notify_activity(&self.seat);
+        self.idle_timers.deadlines.clear();
+        self.idle_timers_restarted();
+    }
+
+    /// A notification with `timeout` was created, its timer started counting.
+    pub fn idle_notification_created(&mut self, timeout: Duration) {
+        let timers = &mut self.idle_timers;
+        *timers.timeouts.entry(timeout).or_default() += 1;
+        timers.deadlines.insert(Instant::now() + timeout);
+        self.schedule_idle_timer();
+    }
+
+    /// A notification was destroyed, its timeout is forgotten once no other one uses it.
+    ///
+    /// A deadline already armed for it stays, it only dispatches the nested loop for nothing.
+    pub fn idle_notification_destroyed(&mut self, data: &IdleNotificationUserData) {
+        // smithay keeps the timeout private, its `Debug` output is the only place to read it.
+        let debug = format!("{data:?}");
+        let timeouts = &mut self.idle_timers.timeouts;
+        let Some((&timeout, count)) = timeouts
+            .iter_mut()
+            .find(|(timeout, _)| debug.contains(&format!("timeout: {timeout:?},")))
+        else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            timeouts.remove(&timeout);
+        }
+    }
+
+    /// The timers started counting again, those of input idle notifications may keep their
+    /// earlier deadlines.
+    fn idle_timers_restarted(&mut self) {
+        let timers = &mut self.idle_timers;
+        let now = Instant::now();
+        timers.deadlines.extend(timers.timeouts.keys().map(|timeout| now + *timeout));
+        // A pending timer comes early now, it schedules the next one when it fires.
+        if timers.token.is_none() {
+            self.schedule_idle_timer();
+        }
+    }
+
+    /// Arms a timer for the first deadline, which dispatches the nested loop.
+    fn schedule_idle_timer(&mut self) {
+        if let Some(token) = self.idle_timers.token.take() {
+            self.event_loop.remove(token);
+        }
+
+        let Some(deadline) = self.idle_timers.deadlines.first().copied() else {
+            return;
+        };
+        let token = self
+            .event_loop
+            .insert_source(Timer::from_deadline(deadline), |_, _, data| {
+                let tsuki = &mut data.tsuki;
+                tsuki.idle_timers.token = None;
+
+                let event_loop = tsuki.idle_timers.event_loop.clone();
+                if let Err(err) = event_loop.borrow_mut().dispatch(Some(Duration::ZERO), tsuki) {
+                    log::warn!("error dispatching idle timers: {err}");
+                }
+
+                let now = Instant::now();
+                tsuki.idle_timers.deadlines.retain(|deadline| *deadline > now);
+                tsuki.schedule_idle_timer();
+                TimeoutAction::Drop
+            })
+            .unwrap();
+        self.idle_timers.token = Some(token);
+    }
+
+    /// Idle inhibitors only count while their surface is shown on some output.
+    pub fn refresh_idle_inhibit(&mut self) {
+        self.idle_inhibitors.retain(|surface| surface.is_alive());
+
+        let inhibited = !self.idle_inhibitors.is_empty()
+            && self.space.elements().any(|window| {
+                let mut visible = false;
+                window.with_surfaces(|surface, states| {
+                    visible |= self.idle_inhibitors.contains(surface)
+                        && surface_primary_scanout_output(surface, states).is_some();
+                });
+                visible
+            });
+
+        if inhibited != self.idle_notifier_state.is_inhibited() {
+            self.idle_notifier_state.set_is_inhibited(inhibited);
+            if !inhibited {
+                self.idle_timers_restarted();
+            }
+        }
+    }
+}
@@ -1,74 +1,133 @@
 
 use smithay::{
     backend::input::{
-        AbsolutePositionEvent, Axis, AxisSource, ButtonState, Event, GesturePinchUpdateEvent, InputBackend, InputEvent, KeyboardKeyEvent, PointerAxisEvent, PointerButtonEvent
+        AbsolutePositionEvent, Axis, AxisSource, ButtonState, Device, DeviceCapability, Event, GesturePinchUpdateEvent, InputBackend, InputEvent, KeyState, KeyboardKeyEvent, PointerAxisEvent, PointerButtonEvent,
+        ProximityState, TabletToolButtonEvent, TabletToolEvent, TabletToolProximityEvent, TabletToolTipEvent, TabletToolTipState,
     },
     input::{
-        keyboard::{keysyms, FilterResult, Keysym},
+        keyboard::{keysyms, FilterResult, Keycode, Keysym},
         pointer::{AxisFrame, ButtonEvent, MotionEvent, RelativeMotionEvent},
     },
-    reexports::wayland_server::protocol::wl_surface::WlSurface,
-    utils::SERIAL_COUNTER,
+    utils::{Logical, Point, Serial, SERIAL_COUNTER},
+    wayland::tablet_manager::{TabletDescriptor, TabletSeatTrait},
 };
 
-use crate::{backend::{Backend, Tty}, state::Tsuki};
+use crate::{backend::{Backend, Tty}, config::Keybinding, screenshot::ScreenshotTarget, state::Tsuki};
 use std::{cell::RefCell, mem, rc::Rc};
 use smithay::backend::input::PointerMotionEvent;
 
 enum TsukiInputAction {
     Quit,
-    ChangeVirtTerminal(i32)
+    ChangeVirtTerminal(i32),
+    Screenshot(ScreenshotTarget),
+    CancelRegionSelection,
+    ToggleOutputsPower,
+}
+
+impl TsukiInputAction {
+    fn keybinding(&self) -> Keybinding {
+        match self {
+            TsukiInputAction::Quit => Keybinding::Quit,
+            TsukiInputAction::ChangeVirtTerminal(_) => Keybinding::ChangeVt,
+            TsukiInputAction::Screenshot(_) | TsukiInputAction::CancelRegionSelection => Keybinding::Screenshot,
+            TsukiInputAction::ToggleOutputsPower => Keybinding::OutputsPower,
+        }
+    }
 }
 
 impl Tsuki {
-    pub fn process_input_event<I: InputBackend>(&mut self, event: InputEvent<I>) {
-        match event {
-            InputEvent::Keyboard { event, .. } => {
-                let serial = SERIAL_COUNTER.next_serial();
-                let time = Event::time_msec(&event);
+    /// Runs the compositor's key bindings on presses, forwards everything else to the focused
+    /// client.
+    pub fn handle_key(&mut self, keycode: Keycode, state: KeyState, time: u32) {
+        let serial = SERIAL_COUNTER.next_serial();
 
-                let action = self.seat.get_keyboard().unwrap().input::<TsukiInputAction, _>(
-                    self,
-                    event.key_code(),
-                    event.state(),
-                    serial,
-                    time,
-                    |_, modifier_state, keysym| match keysym.modified_sym() {
-                        Keysym::Q if modifier_state.ctrl && modifier_state.shift => {
-                            FilterResult::Intercept(TsukiInputAction::Quit)
-                        },
-                        keysym if (u32::from(Keysym::XF86_Switch_VT_1)..=u32::from(Keysym::XF86_Switch_VT_12)).contains(&(keysym.raw()))=> {
-                            let vt = (keysym.raw() - u32::from(Keysym::XF86_Switch_VT_1) + 1) as i32;
-                            FilterResult::Intercept(TsukiInputAction::ChangeVirtTerminal(vt))
-                        }
-                        _ => FilterResult::Forward,
-                    } 
-                );
+        let action = self.seat.get_keyboard().unwrap().input::<Option<TsukiInputAction>, _>(
+            self,
+            keycode,
+            state,
+            serial,
+            time,
+            |tsuki, modifier_state, keysym| {
+                // The filter also runs on releases, they only end presses we kept to ourselves.
+                if state == KeyState::Released {
+                    return if tsuki.suppressed_keys.remove(&keycode) {
+                        FilterResult::Intercept(None)
+                    } else {
+                        FilterResult::Forward
+                    };
+                }
 
-                if let Some(action) = action {
-                    match action {
-                        TsukiInputAction::Quit => {
-                            self.loop_signal.stop()
-                        },
-                        TsukiInputAction::ChangeVirtTerminal(vt) => {
-                            self.backend_data.clone().borrow_mut().as_any().downcast_mut::<Tty>().unwrap().change_virt_term(vt);
-                        }
+                let action = match keysym.modified_sym() {
+                    Keysym::Q if modifier_state.ctrl && modifier_state.shift => Some(TsukiInputAction::Quit),
+                    Keysym::P if modifier_state.ctrl && modifier_state.shift => {
+                        Some(TsukiInputAction::ToggleOutputsPower)
                     }
+                    Keysym::Escape if tsuki.region_selection.is_some() => {
+                        Some(TsukiInputAction::CancelRegionSelection)
+                    }
+                    Keysym::Print if modifier_state.ctrl => {
+                        Some(TsukiInputAction::Screenshot(ScreenshotTarget::Output))
+                    }
+                    Keysym::Print if modifier_state.shift => {
+                        Some(TsukiInputAction::Screenshot(ScreenshotTarget::Window))
+                    }
+                    Keysym::Print => Some(TsukiInputAction::Screenshot(ScreenshotTarget::Region)),
+                    keysym if (u32::from(Keysym::XF86_Switch_VT_1)..=u32::from(Keysym::XF86_Switch_VT_12)).contains(&(keysym.raw())) => {
+                        let vt = (keysym.raw() - u32::from(Keysym::XF86_Switch_VT_1) + 1) as i32;
+                        Some(TsukiInputAction::ChangeVirtTerminal(vt))
+                    }
+                    _ => None,
+                };
+
+                // While locked, keys not on the allow-list go to the lock screen.
+                match action {
+                    Some(action) if !tsuki.is_locked() || tsuki.config.lock.allows(action.keybinding()) => {
+                        tsuki.suppressed_keys.insert(keycode);
+                        FilterResult::Intercept(Some(action))
+                    }
+                    _ => FilterResult::Forward,
                 }
             }
+        );
+
+        if let Some(action) = action.flatten() {
+            match action {
+                TsukiInputAction::Quit => {
+                    self.loop_signal.stop()
+                },
+                TsukiInputAction::ChangeVirtTerminal(vt) => {
+                    self.backend_data.clone().borrow_mut().as_any().downcast_mut::<Tty>().unwrap().change_virt_term(vt);
+                }
+                TsukiInputAction::Screenshot(target) => self.screenshot(target),
+                TsukiInputAction::ToggleOutputsPower => self.toggle_outputs_power(),
+                TsukiInputAction::CancelRegionSelection => {
+                    let pointer = self.seat.get_pointer().unwrap();
+                    pointer.unset_grab(self, serial, time);
+                }
+            }
+        }
+    }
+
+    pub fn process_input_event<I: InputBackend>(&mut self, event: InputEvent<I>) {
+        // Plugging devices in and out isn't user activity.
+        if !matches!(event, InputEvent::DeviceAdded { .. } | InputEvent::DeviceRemoved { .. }) {
+            self.notify_activity();
+        }
+
+        match event {
+            InputEvent::Keyboard { event, .. } => {
+                self.handle_key(event.key_code(), event.state(), Event::time_msec(&event));
+            }
             InputEvent::PointerMotion { event, .. } => {
                 let serial = SERIAL_COUNTER.next_serial();
 
                 let pointer = self.seat.get_pointer().unwrap();
-                let mut pointer_location = pointer.current_location();
+                let previous = pointer.current_location();
+                let mut pointer_location = previous;
 
                 pointer_location += event.delta();
 
-                let output = self.space.outputs().next().unwrap();
-                let output_geo = self.space.output_geometry(output).unwrap();
-
-                pointer_location.x = pointer_location.x.clamp(0., output_geo.size.w as f64);
-                pointer_location.y = pointer_location.y.clamp(0., output_geo.size.h as f64);
+                pointer_location = self.clamp_to_outputs(previous, pointer_location);
 
                 let under = self.surface_under(pointer_location);
                 pointer.motion(
@@ -84,7 +143,7 @@ impl Tsuki {
                     delta_unaccel: event.delta_unaccel(),
                     utime: event.time()
                 });
-                self.queue_redraw();
+                self.queue_redraw_after_motion(previous);
 
             }
             InputEvent::PointerMotionAbsolute { event, .. } => {
@@ -97,6 +156,7 @@ impl Tsuki {
                 let serial = SERIAL_COUNTER.next_serial();
 
                 let pointer = self.seat.get_pointer().unwrap();
+                let previous = pointer.current_location();
 
                 let under = self.surface_under(pos);
 
@@ -110,11 +170,10 @@ impl Tsuki {
                     },
                 );
                 pointer.frame(self);
-                self.queue_redraw();
+                self.queue_redraw_after_motion(previous);
             }
             InputEvent::PointerButton { event, .. } => {
                 let pointer = self.seat.get_pointer().unwrap();
-                let keyboard = self.seat.get_keyboard().unwrap();
 
                 let serial = SERIAL_COUNTER.next_serial();
 
@@ -122,28 +181,13 @@ impl Tsuki {
 
                 let button_state = event.state();
 
+                // Escape or the client destroying its popups end the grab without a click.
+                if self.popup_grab.as_ref().is_some_and(|grab| grab.has_ended()) {
+                    self.popup_grab = None;
+                }
+
                 if ButtonState::Pressed == button_state && !pointer.is_grabbed() {
-                    if let Some((window, _loc)) = self
-                        .space
-                        .element_under(pointer.current_location())
-                        .map(|(w, l)| (w.clone(), l))
-                    {
-                        self.space.raise_element(&window, true);
-                        keyboard.set_focus(
-                            self,
-                            Some(window.toplevel().unwrap().wl_surface().clone()),
-                            serial,
-                        );
-                        self.space.elements().for_each(|window| {
-                            window.toplevel().unwrap().send_pending_configure();
-                        });
-                    } else {
-                        self.space.elements().for_each(|window| {
-                            window.set_activated(false);
-                            window.toplevel().unwrap().send_pending_configure();
-                        });
-                        keyboard.set_focus(self, Option::<WlSurface>::None, serial);
-                    }
+                    self.focus_window_under(pointer.current_location(), serial);
                 };
 
                 pointer.button(
@@ -156,6 +200,13 @@ impl Tsuki {
                     },
                 );
                 pointer.frame(self);
+
+                // A click outside of the popups ends the popup grab, the click then focuses
+                // whatever is under it like any other click.
+                if ButtonState::Pressed == button_state && self.popup_grab.as_ref().is_some_and(|grab| grab.has_ended()) {
+                    self.popup_grab = None;
+                    self.focus_window_under(pointer.current_location(), serial);
+                }
             }
             InputEvent::PointerAxis { event, .. } => {
                 let source = event.source();
@@ -196,7 +247,180 @@ impl Tsuki {
                 pointer.axis(self, frame);
                 pointer.frame(self);
             },
+            InputEvent::DeviceAdded { device } if device.has_capability(DeviceCapability::TabletTool) => {
+                self.seat
+                    .tablet_seat()
+                    .add_tablet::<Self>(&self.display_handle, &TabletDescriptor::from(&device));
+            }
+            InputEvent::DeviceRemoved { device } if device.has_capability(DeviceCapability::TabletTool) => {
+                let tablet_seat = self.seat.tablet_seat();
+                tablet_seat.remove_tablet(&TabletDescriptor::from(&device));
+
+                // Tools are not tied to a tablet, drop them once the last tablet is gone.
+                if tablet_seat.count_tablets() == 0 {
+                    tablet_seat.clear_tools();
+                }
+            }
+            InputEvent::TabletToolAxis { event, .. } => {
+                let Some(pos) = self.tablet_position(&event) else {
+                    return;
+                };
+
+                // Tablet tools move the pointer too, so that the cursor follows the pen.
+                let pointer = self.seat.get_pointer().unwrap();
+                let previous = pointer.current_location();
+                let under = self.surface_under(pos);
+                pointer.motion(
+                    self,
+                    under.clone(),
+                    &MotionEvent { location: pos, serial: SERIAL_COUNTER.next_serial(), time: event.time_msec() },
+                );
+
+                let tablet_seat = self.seat.tablet_seat();
+                let tablet = tablet_seat.get_tablet(&TabletDescriptor::from(&event.device()));
+                let tool = tablet_seat.get_tool(&event.tool());
+
+                if let (Some(tablet), Some(tool)) = (tablet, tool) {
+                    if event.pressure_has_changed() {
+                        tool.pressure(event.pressure());
+                    }
+                    if event.distance_has_changed() {
+                        tool.distance(event.distance());
+                    }
+                    if event.tilt_has_changed() {
+                        tool.tilt(event.tilt());
+                    }
+                    if event.slider_has_changed() {
+                        tool.slider_position(event.slider_position());
+                    }
+                    if event.rotation_has_changed() {
+                        tool.rotation(event.rotation());
+                    }
+                    if event.wheel_has_changed() {
+                        tool.wheel(event.wheel_delta(), event.wheel_delta_discrete());
+                    }
+
+                    tool.motion(pos, under, &tablet, SERIAL_COUNTER.next_serial(), event.time_msec());
+                }
+
+                pointer.frame(self);
+                self.queue_redraw_after_motion(previous);
+            }
+            InputEvent::TabletToolProximity { event, .. } => {
+                let Some(pos) = self.tablet_position(&event) else {
+                    return;
+                };
+
+                let tablet_seat = self.seat.tablet_seat();
+                let dh = self.display_handle.clone();
+                let tool = tablet_seat.add_tool::<Self>(self, &dh, &event.tool());
+
+                let pointer = self.seat.get_pointer().unwrap();
+                let previous = pointer.current_location();
+                let under = self.surface_under(pos);
+                pointer.motion(
+                    self,
+                    under.clone(),
+                    &MotionEvent { location: pos, serial: SERIAL_COUNTER.next_serial(), time: event.time_msec() },
+                );
+                pointer.frame(self);
+
+                let tablet = tablet_seat.get_tablet(&TabletDescriptor::from(&event.device()));
+                match event.state() {
+                    ProximityState::In => {
+                        if let (Some(under), Some(tablet)) = (under, tablet) {
+                            tool.proximity_in(pos, under, &tablet, SERIAL_COUNTER.next_serial(), event.time_msec());
+                        }
+                    }
+                    ProximityState::Out => tool.proximity_out(event.time_msec()),
+                }
+                self.queue_redraw_after_motion(previous);
+            }
+            InputEvent::TabletToolTip { event, .. } => {
+                if let Some(tool) = self.seat.tablet_seat().get_tool(&event.tool()) {
+                    match event.tip_state() {
+                        TabletToolTipState::Down => tool.tip_down(SERIAL_COUNTER.next_serial(), event.time_msec()),
+                        TabletToolTipState::Up => tool.tip_up(event.time_msec()),
+                    }
+                }
+            }
+            InputEvent::TabletToolButton { event, .. } => {
+                if let Some(tool) = self.seat.tablet_seat().get_tool(&event.tool()) {
+                    tool.button(
+                        event.button(),
+                        event.button_state(),
+                        SERIAL_COUNTER.next_serial(),
+                        event.time_msec(),
+                    );
+                }
+            }
             _ => {}
         }
     }
+
+    fn focus_window_under(&mut self, location: Point<f64, Logical>, serial: Serial) {
+        if self.is_locked() {
+            self.focus_lock_surface_under(location, serial);
+            return;
+        }
+
+        let keyboard = self.seat.get_keyboard().unwrap();
+
+        if let Some(window) = self.window_under(location) {
+            self.focus_window(&window, serial);
+        } else {
+            self.space.elements().for_each(|window| {
+                window.set_activated(false);
+                if let Some(toplevel) = window.toplevel() {
+                    toplevel.send_pending_configure();
+                }
+            });
+            keyboard.set_focus(self, None, serial);
+        }
+    }
+
+    /// Grabs may move windows around, otherwise only the cursor needs repainting.
+    fn queue_redraw_after_motion(&mut self, previous: Point<f64, Logical>) {
+        let pointer = self.seat.get_pointer().unwrap();
+        if pointer.is_grabbed() {
+            self.queue_redraw_all();
+        } else {
+            self.queue_redraw_cursor(previous, pointer.current_location());
+        }
+    }
+
+    /// Maps a tablet event onto the first output, like absolute pointer motion.
+    fn tablet_position<I: InputBackend, E: AbsolutePositionEvent<I>>(&self, event: &E) -> Option<Point<f64, Logical>> {
+        let output = self.space.outputs().next()?;
+        let output_geo = self.space.output_geometry(output)?;
+        Some(event.position_transformed(output_geo.size) + output_geo.loc.to_f64())
+    }
+
+    /// Keeps the pointer inside the output layout. When `pos` falls outside of every output,
+    /// it's clamped to the output the pointer is currently on.
+    fn clamp_to_outputs(&self, current: Point<f64, Logical>, pos: Point<f64, Logical>) -> Point<f64, Logical> {
+        let geometries: Vec<_> = self
+            .space
+            .outputs()
+            .filter_map(|output| self.space.output_geometry(output))
+            .collect();
+
+        if geometries.iter().any(|geo| geo.to_f64().contains(pos)) {
+            return pos;
+        }
+
+        let Some(geo) = geometries
+            .iter()
+            .find(|geo| geo.to_f64().contains(current))
+            .or(geometries.first())
+        else {
+            return pos;
+        };
+
+        (
+            pos.x.clamp(geo.loc.x as f64, (geo.loc.x + geo.size.w - 1) as f64),
+            pos.y.clamp(geo.loc.y as f64, (geo.loc.y + geo.size.h - 1) as f64),
+        )
+            .into()
+    }
 }
\ No newline at end of file
@@ -0,0 +1,250 @@
+//! ext-session-lock: while the session is locked, only the lock surfaces are drawn and get
+//! input. The session stays locked when the locker goes away without unlocking, outputs fall
+//! back to a solid color then and another locker can take over.
+
+use std::{mem, time::Duration};
+
+use smithay::{
+    backend::renderer::{
+        element::{solid::SolidColorRenderElement, surface::render_elements_from_surface_tree, Kind},
+        gles::GlesRenderer,
+    },
+    desktop::{utils::under_from_surface_tree, PopupUngrabStrategy, WindowSurfaceType},
+    input::pointer::MotionEvent,
+    output::Output,
+    reexports::{
+        wayland_protocols::ext::session_lock::v1::server::ext_session_lock_v1::ExtSessionLockV1,
+        wayland_server::{protocol::wl_surface::WlSurface, Resource},
+    },
+    utils::{Logical, Point, Serial, SERIAL_COUNTER},
+    wayland::{
+        compositor::{send_surface_state, with_states},
+        fractional_scale::with_fractional_scale,
+        session_lock::{LockSurface, SessionLocker},
+    },
+};
+
+use crate::{focus::KeyboardFocusTarget, state::OutputRenderElements, Tsuki};
+
+/// Drawn below the lock surfaces, and instead of them on outputs that have none.
+pub const LOCK_BACKGROUND_COLOR: [f32; 4] = [0.1, 0.1, 0.1, 1.0];
+
+pub enum LockState {
+    Unlocked,
+    /// Waiting for every output to show a locked frame before confirming the lock.
+    Locking(SessionLocker),
+    Locked(ExtSessionLockV1),
+}
+
+impl Tsuki {
+    pub fn is_locked(&self) -> bool {
+        !matches!(self.lock_state, LockState::Unlocked)
+    }
+
+    pub fn lock_session(&mut self, locker: SessionLocker) {
+        // Only one locker at a time, unless the current one died. Dropping the new one tells
+        // it that locking failed.
+        let current_alive = match &self.lock_state {
+            LockState::Unlocked => false,
+            LockState::Locking(current) => current.ext_session_lock().is_alive(),
+            LockState::Locked(lock) => lock.is_alive(),
+        };
+        if current_alive {
+            return;
+        }
+
+        // Nothing started before locking may keep going behind the lock screen.
+        let serial = SERIAL_COUNTER.next_serial();
+        let time = Duration::from(self.clock.now()).as_millis() as u32;
+        if let Some(mut grab) = self.popup_grab.take() {
+            grab.ungrab(PopupUngrabStrategy::All);
+        }
+        let pointer = self.seat.get_pointer().unwrap();
+        pointer.unset_grab(self, serial, time);
+        let keyboard = self.seat.get_keyboard().unwrap();
+        keyboard.unset_grab(self);
+        keyboard.set_focus(self, None, serial);
+
+        self.lock_state = LockState::Locking(locker);
+        for state in self.output_state.values_mut() {
+            state.lock_rendered = false;
+        }
+        self.confirm_lock_if_ready();
+
+        self.refresh_pointer_focus(serial);
+        self.queue_redraw_all();
+    }
+
+    pub fn unlock_session(&mut self) {
+        self.lock_state = LockState::Unlocked;
+        for state in self.output_state.values_mut() {
+            state.lock_surface = None;
+        }
+
+        let serial = SERIAL_COUNTER.next_serial();
+        self.seat.get_keyboard().unwrap().set_focus(self, None, serial);
+        if let Some(window) = self.space.elements().last().cloned() {
+            self.focus_window(&window, serial);
+        }
+        self.refresh_pointer_focus(serial);
+        self.queue_redraw_all();
+    }
+
+    pub fn add_lock_surface(&mut self, surface: LockSurface, output: &Output) {
+        // Lockers that were turned away get to create surfaces too, they aren't shown.
+        let lock = match &self.lock_state {
+            LockState::Unlocked => return,
+            LockState::Locking(locker) => locker.ext_session_lock(),
+            LockState::Locked(lock) => lock,
+        };
+        if lock.client() != surface.wl_surface().client() {
+            return;
+        }
+
+        let Some(state) = self.output_state.get_mut(output) else {
+            return;
+        };
+        state.lock_surface = Some(surface.clone());
+        self.configure_lock_surface(output);
+
+        // The first lock surface gets the keyboard, a click moves it to another one.
+        let keyboard = self.seat.get_keyboard().unwrap();
+        let serial = SERIAL_COUNTER.next_serial();
+        if keyboard.current_focus().is_none_or(|focus| !focus.surface().is_alive()) {
+            keyboard.set_focus(self, Some(KeyboardFocusTarget::Surface(surface.wl_surface().clone())), serial);
+        }
+        self.refresh_pointer_focus(serial);
+        self.queue_redraw(output);
+    }
+
+    /// Sizes the lock surface of `output` to cover it. Needs to be called again when the
+    /// output changes.
+    pub fn configure_lock_surface(&self, output: &Output) {
+        let Some(surface) = self.output_state.get(output).and_then(|state| state.lock_surface.as_ref()) else {
+            return;
+        };
+        let Some(output_geo) = self.space.output_geometry(output) else {
+            return;
+        };
+
+        surface.with_pending_state(|state| {
+            state.size = Some((output_geo.size.w as u32, output_geo.size.h as u32).into());
+        });
+        surface.send_configure();
+
+        let scale = output.current_scale();
+        let wl_surface = surface.wl_surface();
+        with_states(wl_surface, |states| {
+            send_surface_state(wl_surface, states, scale.integer_scale(), output.current_transform());
+            with_fractional_scale(states, |fractional_scale| {
+                fractional_scale.set_preferred_scale(scale.fractional_scale());
+            });
+        });
+    }
+
+    /// The lock surface of `output` above the background color, relative to the output.
+    pub fn lock_render_elements(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        output: &Output,
+    ) -> Vec<OutputRenderElements<GlesRenderer>> {
+        let Some(output_geo) = self.space.output_geometry(output) else {
+            return Vec::new();
+        };
+        let Some(state) = self.output_state.get_mut(output) else {
+            return Vec::new();
+        };
+        let scale = output.current_scale().fractional_scale();
+
+        let mut elements = Vec::new();
+        if let Some(surface) = state.lock_surface.as_ref().filter(|surface| surface.alive()) {
+            elements.extend(render_elements_from_surface_tree(
+                renderer,
+                surface.wl_surface(),
+                (0, 0),
+                scale,
+                1.0,
+                Kind::Unspecified,
+            ));
+        }
+
+        state.lock_background.resize(output_geo.size);
+        elements.push(OutputRenderElements::Solid(SolidColorRenderElement::from_buffer(
+            &state.lock_background,
+            (0, 0),
+            scale,
+            1.0,
+            Kind::Unspecified,
+        )));
+        elements
+    }
+
+    /// The live lock surface of `output`, if it has one.
+    pub fn lock_surface(&self, output: &Output) -> Option<&WlSurface> {
+        self.output_state
+            .get(output)?
+            .lock_surface
+            .as_ref()
+            .filter(|surface| surface.alive())
+            .map(|surface| surface.wl_surface())
+    }
+
+    pub fn lock_surface_under(&self, pos: Point<f64, Logical>) -> Option<(WlSurface, Point<f64, Logical>)> {
+        let output = self.space.output_under(pos).next()?;
+        let output_geo = self.space.output_geometry(output)?;
+        let surface = self.lock_surface(output)?;
+
+        under_from_surface_tree(surface, pos - output_geo.loc.to_f64(), (0, 0), WindowSurfaceType::ALL)
+            .map(|(surface, location)| (surface, (location + output_geo.loc).to_f64()))
+    }
+
+    /// Moves the keyboard to the lock surface of the output under `location`.
+    pub fn focus_lock_surface_under(&mut self, location: Point<f64, Logical>, serial: Serial) {
+        let Some(output) = self.space.output_under(location).next() else {
+            return;
+        };
+        if let Some(surface) = self.lock_surface(output).cloned() {
+            let keyboard = self.seat.get_keyboard().unwrap();
+            keyboard.set_focus(self, Some(KeyboardFocusTarget::Surface(surface)), serial);
+        }
+    }
+
+    /// Called once a frame showing the lock screen was presented on `output`, the lock is
+    /// confirmed when every output that is on shows it.
+    pub fn lock_frame_rendered(&mut self, output: &Output) {
+        if !matches!(self.lock_state, LockState::Locking(_)) {
+            return;
+        }
+        if let Some(state) = self.output_state.get_mut(output) {
+            state.lock_rendered = true;
+        }
+        self.confirm_lock_if_ready();
+    }
+
+    fn confirm_lock_if_ready(&mut self) {
+        // Outputs that are off don't show anything in the first place.
+        if !self.output_state.values().all(|state| state.lock_rendered || !state.powered) {
+            return;
+        }
+
+        self.lock_state = match mem::replace(&mut self.lock_state, LockState::Unlocked) {
+            LockState::Locking(locker) => {
+                let lock = locker.ext_session_lock().clone();
+                locker.lock();
+                LockState::Locked(lock)
+            }
+            other => other,
+        };
+    }
+
+    /// Pointer focus only changes with motion, so locking and unlocking has to look at what's
+    /// under the pointer again.
+    fn refresh_pointer_focus(&mut self, serial: Serial) {
+        let pointer = self.seat.get_pointer().unwrap();
+        let location = pointer.current_location();
+        let under = self.surface_under(location);
+        let time = Duration::from(self.clock.now()).as_millis() as u32;
+        pointer.motion(self, under, &MotionEvent { location, serial, time });
+        pointer.frame(self);
+    }
+}
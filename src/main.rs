@@ -1,14 +1,31 @@
 #![allow(irrefutable_let_patterns)]
 mod handlers;
 
+mod frame_clock;
 mod grabs;
+mod idle;
 mod input;
+mod lock;
+mod protocols;
+mod screenshot;
 mod state;
+mod window;
 mod backend;
+mod config;
+mod cursor;
+mod capture;
+mod clipboard;
+mod focus;
+mod foreign_toplevel;
+#[cfg(feature = "xwayland")]
+mod xwayland;
+#[cfg(test)]
+mod tests;
 
 use std::{cell::RefCell, env, rc::Rc};
 
-use backend::{Backend, Tty, Winit};
+use backend::{Backend, Headless, Tty, Winit};
+use config::Config;
 use smithay::reexports::{
     calloop::EventLoop,
     wayland_server::{Display, DisplayHandle},
@@ -33,22 +50,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("has display: {}", has_display);
 
     let mut event_loop: EventLoop<CalloopData> = EventLoop::try_new()?;
-    let mut winit: Option<Rc<RefCell<dyn Backend>>> = None;
-    let mut tty:  Option<Rc<RefCell<dyn Backend>>> = None;
-    let backend: Rc<RefCell<dyn Backend>> = if has_display {
-        winit = Some(Rc::new(RefCell::new(Winit::new(event_loop.handle()))));
-        winit.unwrap()
-    } else {
-        log::info!("running on tty");
-        tty = Some(Rc::new(RefCell::new(Tty::new(event_loop.handle()))));
-        tty.unwrap()
+    // TSUKI_BACKEND picks a backend explicitly, headless is only reachable this way.
+    let backend: Rc<RefCell<dyn Backend>> = match env::var("TSUKI_BACKEND").as_deref() {
+        Ok("headless") => {
+            log::info!("running headless");
+            Rc::new(RefCell::new(Headless::new()))
+        }
+        Ok("winit") => Rc::new(RefCell::new(Winit::new(event_loop.handle()))),
+        Ok("tty") => Rc::new(RefCell::new(Tty::new(event_loop.handle()))),
+        _ if has_display => Rc::new(RefCell::new(Winit::new(event_loop.handle()))),
+        _ => {
+            log::info!("running on tty");
+            Rc::new(RefCell::new(Tty::new(event_loop.handle())))
+        }
     };
 
 
-
     let display = Display::new().unwrap();
     let display_handle = display.handle();
-    let state = Tsuki::new(event_loop.handle(), event_loop.get_signal(), display, backend.clone());
+    let state = Tsuki::new(event_loop.handle(), event_loop.get_signal(), display, backend.clone(), Config::load());
 
 
     let mut data = CalloopData {
@@ -62,7 +82,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut args = std::env::args().skip(1);
     let flag = args.next();
     let arg = args.next();
-    std::env::set_var("WAYLAND_DISPLAY", &data.tsuki.socket_name);
+    let socket_name = data.tsuki.add_listening_socket();
+    std::env::set_var("WAYLAND_DISPLAY", &socket_name);
+
+    #[cfg(feature = "xwayland")]
+    if let Some(display_number) = data.tsuki.setup_xwayland() {
+        std::env::set_var("DISPLAY", format!(":{display_number}"));
+    }
 
 
     match (flag.as_deref(), arg) {
@@ -76,8 +102,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     event_loop.run(None, &mut data, move |data| {
         // Tsuki is running
-        data.display_handle.flush_clients().unwrap();
+        data.refresh_and_flush();
     })?;
 
     Ok(())
+}
+
+impl CalloopData {
+    /// Runs after every event loop dispatch.
+    fn refresh_and_flush(&mut self) {
+        let backend = self.backend.clone();
+        self.tsuki.persist_selections();
+        self.tsuki.redraw_queued_outputs(&mut *backend.borrow_mut());
+        self.tsuki.refresh_idle_inhibit();
+        self.tsuki.process_captures(&mut *backend.borrow_mut());
+        self.display_handle.flush_clients().unwrap();
+    }
 }
\ No newline at end of file
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+
+use smithay::{
+    desktop::Window,
+    output::Output,
+    reexports::{
+        wayland_protocols_wlr::foreign_toplevel::v1::server::{
+            zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+            zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+        },
+        wayland_server::{
+            backend::ClientId, protocol::wl_output::WlOutput, Client, DataInit, Dispatch, DisplayHandle,
+            GlobalDispatch, New, Resource,
+        },
+    },
+};
+
+const VERSION: u32 = 3;
+
+pub trait ForeignToplevelHandler {
+    fn foreign_toplevel_manager_state(&mut self) -> &mut ForeignToplevelManagerState;
+    fn activate(&mut self, window: Window);
+    fn close(&mut self, window: Window);
+    fn set_maximized(&mut self, window: Window, maximized: bool);
+    fn set_minimized(&mut self, window: Window, minimized: bool);
+    /// `output` is the taskbar's preference, it's `None` when unfullscreening.
+    fn set_fullscreen(&mut self, window: Window, fullscreen: bool, output: Option<Output>);
+}
+
+/// What taskbars are told about a window.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToplevelInfo {
+    pub title: String,
+    pub app_id: String,
+    pub activated: bool,
+    pub maximized: bool,
+    pub minimized: bool,
+    pub fullscreen: bool,
+    pub outputs: Vec<Output>,
+}
+
+impl ToplevelInfo {
+    fn states(&self) -> Vec<u8> {
+        let states = [
+            (self.maximized, zwlr_foreign_toplevel_handle_v1::State::Maximized),
+            (self.minimized, zwlr_foreign_toplevel_handle_v1::State::Minimized),
+            (self.activated, zwlr_foreign_toplevel_handle_v1::State::Activated),
+            (self.fullscreen, zwlr_foreign_toplevel_handle_v1::State::Fullscreen),
+        ];
+        states
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .flat_map(|(_, state)| (state as u32).to_ne_bytes())
+            .collect()
+    }
+}
+
+pub struct ForeignToplevelManagerState {
+    display: DisplayHandle,
+    instances: Vec<ZwlrForeignToplevelManagerV1>,
+    toplevels: HashMap<Window, ToplevelData>,
+}
+
+struct ToplevelData {
+    info: ToplevelInfo,
+    handles: Vec<ZwlrForeignToplevelHandleV1>,
+}
+
+impl ForeignToplevelManagerState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ZwlrForeignToplevelManagerV1, ()>,
+        D: Dispatch<ZwlrForeignToplevelManagerV1, ()>,
+        D: 'static,
+    {
+        display.create_global::<D, ZwlrForeignToplevelManagerV1, _>(VERSION, ());
+        Self {
+            display: display.clone(),
+            instances: Vec::new(),
+            toplevels: HashMap::new(),
+        }
+    }
+
+    /// Announces the window if it's new, otherwise sends what changed since the last update.
+    pub fn update_toplevel<D>(&mut self, window: &Window, info: ToplevelInfo)
+    where
+        D: Dispatch<ZwlrForeignToplevelHandleV1, Window>,
+        D: 'static,
+    {
+        match self.toplevels.get_mut(window) {
+            Some(data) => {
+                if data.info == info {
+                    return;
+                }
+                for handle in &data.handles {
+                    send_changes(&self.display, handle, &data.info, &info);
+                }
+                data.info = info;
+            }
+            None => {
+                let mut data = ToplevelData {
+                    info,
+                    handles: Vec::new(),
+                };
+                for manager in &self.instances {
+                    create_handle::<D>(&self.display, manager, window, &mut data);
+                }
+                self.toplevels.insert(window.clone(), data);
+            }
+        }
+    }
+
+    pub fn remove_toplevel(&mut self, window: &Window) {
+        if let Some(data) = self.toplevels.remove(window) {
+            for handle in data.handles {
+                handle.closed();
+            }
+        }
+    }
+
+    pub fn has_toplevel(&self, window: &Window) -> bool {
+        self.toplevels.contains_key(window)
+    }
+
+    /// Clients bind outputs after the handles were created, tell them which toplevels are on it.
+    pub fn output_bound(&mut self, output: &Output, wl_output: &WlOutput) {
+        for data in self.toplevels.values() {
+            if !data.info.outputs.contains(output) {
+                continue;
+            }
+            for handle in &data.handles {
+                if handle.id().same_client_as(&wl_output.id()) {
+                    handle.output_enter(wl_output);
+                    handle.done();
+                }
+            }
+        }
+    }
+}
+
+fn client_outputs(display: &DisplayHandle, handle: &ZwlrForeignToplevelHandleV1, output: &Output) -> Vec<WlOutput> {
+    display
+        .get_client(handle.id())
+        .map(|client| output.client_outputs(&client))
+        .unwrap_or_default()
+}
+
+fn create_handle<D>(
+    display: &DisplayHandle,
+    manager: &ZwlrForeignToplevelManagerV1,
+    window: &Window,
+    data: &mut ToplevelData,
+) where
+    D: Dispatch<ZwlrForeignToplevelHandleV1, Window>,
+    D: 'static,
+{
+    let Ok(client) = display.get_client(manager.id()) else {
+        return;
+    };
+    let Ok(handle) =
+        client.create_resource::<ZwlrForeignToplevelHandleV1, _, D>(display, manager.version(), window.clone())
+    else {
+        return;
+    };
+    manager.toplevel(&handle);
+
+    let info = &data.info;
+    handle.title(info.title.clone());
+    handle.app_id(info.app_id.clone());
+    for output in &info.outputs {
+        for wl_output in output.client_outputs(&client) {
+            handle.output_enter(&wl_output);
+        }
+    }
+    handle.state(info.states());
+    handle.done();
+
+    data.handles.push(handle);
+}
+
+fn send_changes(display: &DisplayHandle, handle: &ZwlrForeignToplevelHandleV1, old: &ToplevelInfo, new: &ToplevelInfo) {
+    if old.title != new.title {
+        handle.title(new.title.clone());
+    }
+    if old.app_id != new.app_id {
+        handle.app_id(new.app_id.clone());
+    }
+
+    for output in old.outputs.iter().filter(|output| !new.outputs.contains(output)) {
+        for wl_output in client_outputs(display, handle, output) {
+            handle.output_leave(&wl_output);
+        }
+    }
+    for output in new.outputs.iter().filter(|output| !old.outputs.contains(output)) {
+        for wl_output in client_outputs(display, handle, output) {
+            handle.output_enter(&wl_output);
+        }
+    }
+
+    let states = new.states();
+    if old.states() != states {
+        handle.state(states);
+    }
+    handle.done();
+}
+
+impl<D> GlobalDispatch<ZwlrForeignToplevelManagerV1, (), D> for ForeignToplevelManagerState
+where
+    D: GlobalDispatch<ZwlrForeignToplevelManagerV1, ()>,
+    D: Dispatch<ZwlrForeignToplevelManagerV1, ()>,
+    D: Dispatch<ZwlrForeignToplevelHandleV1, Window>,
+    D: ForeignToplevelHandler,
+    D: 'static,
+{
+    fn bind(
+        state: &mut D,
+        display: &DisplayHandle,
+        _client: &Client,
+        manager: New<ZwlrForeignToplevelManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        let manager = data_init.init(manager, ());
+
+        let state = state.foreign_toplevel_manager_state();
+        for (window, data) in &mut state.toplevels {
+            create_handle::<D>(display, &manager, window, data);
+        }
+        state.instances.push(manager);
+    }
+}
+
+impl<D> Dispatch<ZwlrForeignToplevelManagerV1, (), D> for ForeignToplevelManagerState
+where
+    D: Dispatch<ZwlrForeignToplevelManagerV1, ()>,
+    D: ForeignToplevelHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        manager: &ZwlrForeignToplevelManagerV1,
+        request: zwlr_foreign_toplevel_manager_v1::Request,
+        _data: &(),
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_foreign_toplevel_manager_v1::Request::Stop => {
+                state
+                    .foreign_toplevel_manager_state()
+                    .instances
+                    .retain(|instance| instance != manager);
+                manager.finished();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(state: &mut D, _client: ClientId, manager: &ZwlrForeignToplevelManagerV1, _data: &()) {
+        state
+            .foreign_toplevel_manager_state()
+            .instances
+            .retain(|instance| instance != manager);
+    }
+}
+
+impl<D> Dispatch<ZwlrForeignToplevelHandleV1, Window, D> for ForeignToplevelManagerState
+where
+    D: Dispatch<ZwlrForeignToplevelHandleV1, Window>,
+    D: ForeignToplevelHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        _handle: &ZwlrForeignToplevelHandleV1,
+        request: zwlr_foreign_toplevel_handle_v1::Request,
+        window: &Window,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        // Handles of closed toplevels are inert.
+        if !state.foreign_toplevel_manager_state().has_toplevel(window) {
+            return;
+        }
+        let window = window.clone();
+
+        match request {
+            zwlr_foreign_toplevel_handle_v1::Request::SetMaximized => state.set_maximized(window, true),
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetMaximized => state.set_maximized(window, false),
+            zwlr_foreign_toplevel_handle_v1::Request::SetMinimized => state.set_minimized(window, true),
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetMinimized => state.set_minimized(window, false),
+            zwlr_foreign_toplevel_handle_v1::Request::Activate { .. } => state.activate(window),
+            zwlr_foreign_toplevel_handle_v1::Request::Close => state.close(window),
+            zwlr_foreign_toplevel_handle_v1::Request::SetFullscreen { output } => {
+                let output = output.as_ref().and_then(Output::from_resource);
+                state.set_fullscreen(window, true, output);
+            }
+            zwlr_foreign_toplevel_handle_v1::Request::UnsetFullscreen => state.set_fullscreen(window, false, None),
+            // Minimize animations aren't a thing here.
+            zwlr_foreign_toplevel_handle_v1::Request::SetRectangle { .. } => (),
+            zwlr_foreign_toplevel_handle_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(state: &mut D, _client: ClientId, handle: &ZwlrForeignToplevelHandleV1, window: &Window) {
+        if let Some(data) = state.foreign_toplevel_manager_state().toplevels.get_mut(window) {
+            data.handles.retain(|h| h != handle);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_foreign_toplevel_management {
+    ($ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::foreign_toplevel::v1::server::zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1: ()
+        ] => $crate::protocols::foreign_toplevel::ForeignToplevelManagerState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::foreign_toplevel::v1::server::zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1: ()
+        ] => $crate::protocols::foreign_toplevel::ForeignToplevelManagerState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::foreign_toplevel::v1::server::zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1: smithay::desktop::Window
+        ] => $crate::protocols::foreign_toplevel::ForeignToplevelManagerState);
+    };
+}
@@ -0,0 +1,600 @@
+use std::{sync::Mutex, time::Duration};
+
+use smithay::{
+    output::{Output, WeakOutput},
+    reexports::{
+        wayland_protocols::ext::{
+            image_capture_source::v1::server::{
+                ext_foreign_toplevel_image_capture_source_manager_v1::{
+                    self, ExtForeignToplevelImageCaptureSourceManagerV1,
+                },
+                ext_image_capture_source_v1::{self, ExtImageCaptureSourceV1},
+                ext_output_image_capture_source_manager_v1::{self, ExtOutputImageCaptureSourceManagerV1},
+            },
+            image_copy_capture::v1::server::{
+                ext_image_copy_capture_cursor_session_v1::{self, ExtImageCopyCaptureCursorSessionV1},
+                ext_image_copy_capture_frame_v1::{self, ExtImageCopyCaptureFrameV1, FailureReason},
+                ext_image_copy_capture_manager_v1::{self, ExtImageCopyCaptureManagerV1},
+                ext_image_copy_capture_session_v1::{self, ExtImageCopyCaptureSessionV1},
+            },
+        },
+        wayland_server::{
+            backend::{ClientId, ObjectId},
+            protocol::wl_buffer::WlBuffer,
+            Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+        },
+    },
+    utils::{Buffer as BufferCoords, Rectangle, Transform},
+    wayland::foreign_toplevel_list::{ForeignToplevelHandle, ForeignToplevelWeakHandle},
+};
+
+use crate::capture::{BufferConstraints, SHM_FORMATS};
+
+const VERSION: u32 = 1;
+
+/// What an `ext_image_capture_source_v1` captures.
+#[derive(Debug, Clone)]
+pub enum ImageCaptureSource {
+    Output(WeakOutput),
+    /// A window with its popups, from an ext-foreign-toplevel-list handle.
+    Toplevel(ForeignToplevelWeakHandle),
+}
+
+pub trait ImageCopyCaptureHandler {
+    fn image_copy_capture_state(&mut self) -> &mut ImageCopyCaptureState;
+
+    /// Buffers the source can be captured into, `None` once the source is gone.
+    fn capture_constraints(&mut self, source: &ImageCaptureSource) -> Option<BufferConstraints>;
+
+    /// A client asked for a frame to be captured into `frame.buffer`.
+    fn frame(&mut self, frame: ImageCopyFrame);
+}
+
+pub struct ImageCopyCaptureState;
+
+pub struct ImageCopySessionData {
+    source: ImageCaptureSource,
+    paint_cursors: bool,
+    inner: Mutex<SessionInner>,
+}
+
+#[derive(Default)]
+struct SessionInner {
+    /// The constraints last sent to the client.
+    constraints: Option<BufferConstraints>,
+    has_frame: bool,
+    captured: bool,
+    stopped: bool,
+}
+
+pub struct ImageCopyFrameData {
+    session: ExtImageCopyCaptureSessionV1,
+    inner: Mutex<FrameInner>,
+}
+
+#[derive(Default)]
+struct FrameInner {
+    buffer: Option<WlBuffer>,
+    captured: bool,
+}
+
+/// A capture waiting to be rendered. Fails the frame when dropped without `success`.
+pub struct ImageCopyFrame {
+    frame: ExtImageCopyCaptureFrameV1,
+    session: ExtImageCopyCaptureSessionV1,
+    pub source: ImageCaptureSource,
+    pub paint_cursors: bool,
+    pub buffer: WlBuffer,
+    /// Only the first frame of a session is captured right away, the others wait until the
+    /// source changed.
+    pub wait_for_damage: bool,
+    done: bool,
+}
+
+impl ImageCopyFrame {
+    /// Damage is tracked per session.
+    pub fn session_id(&self) -> ObjectId {
+        self.session.id()
+    }
+
+    pub fn success(mut self, transform: Transform, damage: &[Rectangle<i32, BufferCoords>], time: Duration) {
+        self.done = true;
+
+        if let Some(data) = self.session.data::<ImageCopySessionData>() {
+            data.inner.lock().unwrap().captured = true;
+        }
+
+        self.frame.transform(transform.into());
+        for rect in damage {
+            self.frame.damage(rect.loc.x, rect.loc.y, rect.size.w, rect.size.h);
+        }
+        let secs = time.as_secs();
+        self.frame.presentation_time((secs >> 32) as u32, secs as u32, time.subsec_nanos());
+        self.frame.ready();
+    }
+
+    pub fn failed(mut self, reason: FailureReason) {
+        self.done = true;
+        self.frame.failed(reason);
+    }
+}
+
+impl Drop for ImageCopyFrame {
+    fn drop(&mut self) {
+        if !self.done {
+            self.frame.failed(FailureReason::Unknown);
+        }
+    }
+}
+
+impl ImageCopyCaptureState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ExtImageCopyCaptureManagerV1, ()>,
+        D: GlobalDispatch<ExtOutputImageCaptureSourceManagerV1, ()>,
+        D: GlobalDispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()>,
+        D: Dispatch<ExtImageCopyCaptureManagerV1, ()>,
+        D: Dispatch<ExtOutputImageCaptureSourceManagerV1, ()>,
+        D: Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()>,
+        D: 'static,
+    {
+        display.create_global::<D, ExtImageCopyCaptureManagerV1, _>(VERSION, ());
+        display.create_global::<D, ExtOutputImageCaptureSourceManagerV1, _>(VERSION, ());
+        display.create_global::<D, ExtForeignToplevelImageCaptureSourceManagerV1, _>(VERSION, ());
+        Self
+    }
+}
+
+fn send_constraints(session: &ExtImageCopyCaptureSessionV1, constraints: &BufferConstraints) {
+    session.buffer_size(constraints.size.w as u32, constraints.size.h as u32);
+    for format in SHM_FORMATS {
+        session.shm_format(format);
+    }
+
+    if let Some(dmabuf) = &constraints.dmabuf {
+        session.dmabuf_device(dmabuf.device.to_ne_bytes().to_vec());
+
+        let mut codes: Vec<_> = dmabuf.formats.iter().map(|format| format.code).collect();
+        codes.dedup();
+        for code in codes {
+            let modifiers = dmabuf
+                .formats
+                .iter()
+                .filter(|format| format.code == code)
+                .flat_map(|format| u64::from(format.modifier).to_ne_bytes())
+                .collect();
+            session.dmabuf_format(code as u32, modifiers);
+        }
+    }
+
+    session.done();
+}
+
+fn stop_session(session: &ExtImageCopyCaptureSessionV1, inner: &mut SessionInner) {
+    if !inner.stopped {
+        inner.stopped = true;
+        session.stopped();
+    }
+}
+
+impl<D> GlobalDispatch<ExtOutputImageCaptureSourceManagerV1, (), D> for ImageCopyCaptureState
+where
+    D: GlobalDispatch<ExtOutputImageCaptureSourceManagerV1, ()>,
+    D: Dispatch<ExtOutputImageCaptureSourceManagerV1, ()>,
+    D: 'static,
+{
+    fn bind(
+        _state: &mut D,
+        _display: &DisplayHandle,
+        _client: &Client,
+        manager: New<ExtOutputImageCaptureSourceManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        data_init.init(manager, ());
+    }
+}
+
+impl<D> Dispatch<ExtOutputImageCaptureSourceManagerV1, (), D> for ImageCopyCaptureState
+where
+    D: Dispatch<ExtOutputImageCaptureSourceManagerV1, ()>,
+    D: Dispatch<ExtImageCaptureSourceV1, ImageCaptureSource>,
+    D: 'static,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _manager: &ExtOutputImageCaptureSourceManagerV1,
+        request: ext_output_image_capture_source_manager_v1::Request,
+        _data: &(),
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            ext_output_image_capture_source_manager_v1::Request::CreateSource { source, output } => {
+                // Sources for outputs that are already gone are valid, their sessions stop
+                // right away.
+                let output = Output::from_resource(&output)
+                    .map(|output| output.downgrade())
+                    .unwrap_or_default();
+                data_init.init(source, ImageCaptureSource::Output(output));
+            }
+            ext_output_image_capture_source_manager_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> GlobalDispatch<ExtForeignToplevelImageCaptureSourceManagerV1, (), D> for ImageCopyCaptureState
+where
+    D: GlobalDispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()>,
+    D: Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()>,
+    D: 'static,
+{
+    fn bind(
+        _state: &mut D,
+        _display: &DisplayHandle,
+        _client: &Client,
+        manager: New<ExtForeignToplevelImageCaptureSourceManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        data_init.init(manager, ());
+    }
+}
+
+impl<D> Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, (), D> for ImageCopyCaptureState
+where
+    D: Dispatch<ExtForeignToplevelImageCaptureSourceManagerV1, ()>,
+    D: Dispatch<ExtImageCaptureSourceV1, ImageCaptureSource>,
+    D: 'static,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _manager: &ExtForeignToplevelImageCaptureSourceManagerV1,
+        request: ext_foreign_toplevel_image_capture_source_manager_v1::Request,
+        _data: &(),
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            ext_foreign_toplevel_image_capture_source_manager_v1::Request::CreateSource {
+                source,
+                toplevel_handle,
+            } => {
+                // Handles of closed toplevels still carry their data, sessions on them stop
+                // right away.
+                let handle = ForeignToplevelHandle::from_resource(&toplevel_handle).unwrap();
+                data_init.init(source, ImageCaptureSource::Toplevel(handle.downgrade()));
+            }
+            ext_foreign_toplevel_image_capture_source_manager_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ExtImageCaptureSourceV1, ImageCaptureSource, D> for ImageCopyCaptureState
+where
+    D: Dispatch<ExtImageCaptureSourceV1, ImageCaptureSource>,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _source: &ExtImageCaptureSourceV1,
+        request: ext_image_capture_source_v1::Request,
+        _data: &ImageCaptureSource,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            ext_image_capture_source_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> GlobalDispatch<ExtImageCopyCaptureManagerV1, (), D> for ImageCopyCaptureState
+where
+    D: GlobalDispatch<ExtImageCopyCaptureManagerV1, ()>,
+    D: Dispatch<ExtImageCopyCaptureManagerV1, ()>,
+    D: 'static,
+{
+    fn bind(
+        _state: &mut D,
+        _display: &DisplayHandle,
+        _client: &Client,
+        manager: New<ExtImageCopyCaptureManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        data_init.init(manager, ());
+    }
+}
+
+impl<D> Dispatch<ExtImageCopyCaptureManagerV1, (), D> for ImageCopyCaptureState
+where
+    D: Dispatch<ExtImageCopyCaptureManagerV1, ()>,
+    D: Dispatch<ExtImageCopyCaptureSessionV1, ImageCopySessionData>,
+    D: Dispatch<ExtImageCopyCaptureCursorSessionV1, ImageCaptureSource>,
+    D: ImageCopyCaptureHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        manager: &ExtImageCopyCaptureManagerV1,
+        request: ext_image_copy_capture_manager_v1::Request,
+        _data: &(),
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            ext_image_copy_capture_manager_v1::Request::CreateSession { session, source, options } => {
+                let source = source.data::<ImageCaptureSource>().unwrap().clone();
+                let paint_cursors = match options {
+                    WEnum::Value(options) => {
+                        options.contains(ext_image_copy_capture_manager_v1::Options::PaintCursors)
+                    }
+                    WEnum::Unknown(_) => {
+                        manager.post_error(
+                            ext_image_copy_capture_manager_v1::Error::InvalidOption,
+                            "unknown capture option",
+                        );
+                        return;
+                    }
+                };
+
+                let constraints = state.capture_constraints(&source);
+                let session = data_init.init(
+                    session,
+                    ImageCopySessionData {
+                        source,
+                        paint_cursors,
+                        inner: Mutex::new(SessionInner::default()),
+                    },
+                );
+
+                let mut inner = session.data::<ImageCopySessionData>().unwrap().inner.lock().unwrap();
+                match constraints {
+                    Some(constraints) => {
+                        send_constraints(&session, &constraints);
+                        inner.constraints = Some(constraints);
+                    }
+                    None => stop_session(&session, &mut inner),
+                }
+            }
+            ext_image_copy_capture_manager_v1::Request::CreatePointerCursorSession { session, source, .. } => {
+                let source = source.data::<ImageCaptureSource>().unwrap().clone();
+                data_init.init(session, source);
+            }
+            ext_image_copy_capture_manager_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ExtImageCopyCaptureCursorSessionV1, ImageCaptureSource, D> for ImageCopyCaptureState
+where
+    D: Dispatch<ExtImageCopyCaptureCursorSessionV1, ImageCaptureSource>,
+    D: Dispatch<ExtImageCopyCaptureSessionV1, ImageCopySessionData>,
+    D: 'static,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _cursor_session: &ExtImageCopyCaptureCursorSessionV1,
+        request: ext_image_copy_capture_cursor_session_v1::Request,
+        source: &ImageCaptureSource,
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            // Capturing the cursor on its own isn't supported, the session stops right away
+            // and clients fall back to painting the cursor into the main capture.
+            ext_image_copy_capture_cursor_session_v1::Request::GetCaptureSession { session } => {
+                let session = data_init.init(
+                    session,
+                    ImageCopySessionData {
+                        source: source.clone(),
+                        paint_cursors: false,
+                        inner: Mutex::new(SessionInner::default()),
+                    },
+                );
+                let mut inner = session.data::<ImageCopySessionData>().unwrap().inner.lock().unwrap();
+                stop_session(&session, &mut inner);
+            }
+            ext_image_copy_capture_cursor_session_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ExtImageCopyCaptureSessionV1, ImageCopySessionData, D> for ImageCopyCaptureState
+where
+    D: Dispatch<ExtImageCopyCaptureSessionV1, ImageCopySessionData>,
+    D: Dispatch<ExtImageCopyCaptureFrameV1, ImageCopyFrameData>,
+    D: 'static,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        session: &ExtImageCopyCaptureSessionV1,
+        request: ext_image_copy_capture_session_v1::Request,
+        data: &ImageCopySessionData,
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            ext_image_copy_capture_session_v1::Request::CreateFrame { frame } => {
+                data_init.init(
+                    frame,
+                    ImageCopyFrameData {
+                        session: session.clone(),
+                        inner: Mutex::new(FrameInner::default()),
+                    },
+                );
+
+                let mut inner = data.inner.lock().unwrap();
+                if inner.has_frame {
+                    session.post_error(
+                        ext_image_copy_capture_session_v1::Error::DuplicateFrame,
+                        "session already has a frame",
+                    );
+                    return;
+                }
+                inner.has_frame = true;
+            }
+            ext_image_copy_capture_session_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ExtImageCopyCaptureFrameV1, ImageCopyFrameData, D> for ImageCopyCaptureState
+where
+    D: Dispatch<ExtImageCopyCaptureFrameV1, ImageCopyFrameData>,
+    D: ImageCopyCaptureHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        frame: &ExtImageCopyCaptureFrameV1,
+        request: ext_image_copy_capture_frame_v1::Request,
+        data: &ImageCopyFrameData,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        let mut inner = data.inner.lock().unwrap();
+
+        match request {
+            ext_image_copy_capture_frame_v1::Request::AttachBuffer { buffer } => {
+                if inner.captured {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::AlreadyCaptured,
+                        "frame was already captured",
+                    );
+                    return;
+                }
+                inner.buffer = Some(buffer);
+            }
+            ext_image_copy_capture_frame_v1::Request::DamageBuffer { x, y, width, height } => {
+                if inner.captured {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::AlreadyCaptured,
+                        "frame was already captured",
+                    );
+                    return;
+                }
+                if x < 0 || y < 0 || width <= 0 || height <= 0 {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::InvalidBufferDamage,
+                        "invalid buffer damage",
+                    );
+                }
+                // Every capture redraws the whole buffer, so the damage isn't needed.
+            }
+            ext_image_copy_capture_frame_v1::Request::Capture => {
+                if inner.captured {
+                    frame.post_error(
+                        ext_image_copy_capture_frame_v1::Error::AlreadyCaptured,
+                        "frame was already captured",
+                    );
+                    return;
+                }
+                let Some(buffer) = inner.buffer.clone() else {
+                    frame.post_error(ext_image_copy_capture_frame_v1::Error::NoBuffer, "no buffer attached");
+                    return;
+                };
+                inner.captured = true;
+
+                let session = &data.session;
+                let Some(session_data) = session.data::<ImageCopySessionData>() else {
+                    frame.failed(FailureReason::Stopped);
+                    return;
+                };
+                let mut session_inner = session_data.inner.lock().unwrap();
+                if session_inner.stopped {
+                    frame.failed(FailureReason::Stopped);
+                    return;
+                }
+
+                let Some(constraints) = state.capture_constraints(&session_data.source) else {
+                    stop_session(session, &mut session_inner);
+                    frame.failed(FailureReason::Stopped);
+                    return;
+                };
+
+                // The source was resized, tell the client about the new buffer size.
+                if session_inner.constraints.as_ref() != Some(&constraints) {
+                    send_constraints(session, &constraints);
+                    session_inner.constraints = Some(constraints);
+                    frame.failed(FailureReason::BufferConstraints);
+                    return;
+                }
+                if !constraints.check_buffer(&buffer) {
+                    frame.failed(FailureReason::BufferConstraints);
+                    return;
+                }
+
+                let wait_for_damage = session_inner.captured;
+                drop(session_inner);
+
+                state.frame(ImageCopyFrame {
+                    frame: frame.clone(),
+                    session: session.clone(),
+                    source: session_data.source.clone(),
+                    paint_cursors: session_data.paint_cursors,
+                    buffer,
+                    wait_for_damage,
+                    done: false,
+                });
+            }
+            ext_image_copy_capture_frame_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(_state: &mut D, _client: ClientId, _frame: &ExtImageCopyCaptureFrameV1, data: &ImageCopyFrameData) {
+        if let Some(session_data) = data.session.data::<ImageCopySessionData>() {
+            session_data.inner.lock().unwrap().has_frame = false;
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_image_copy_capture {
+    ($ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1: ()
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_global_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_capture_source::v1::server::ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1: ()
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_manager_v1::ExtImageCopyCaptureManagerV1: ()
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_capture_source::v1::server::ext_output_image_capture_source_manager_v1::ExtOutputImageCaptureSourceManagerV1: ()
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_global_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_capture_source::v1::server::ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1: ()
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_capture_source::v1::server::ext_foreign_toplevel_image_capture_source_manager_v1::ExtForeignToplevelImageCaptureSourceManagerV1: ()
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_capture_source::v1::server::ext_image_capture_source_v1::ExtImageCaptureSourceV1: $crate::protocols::image_copy_capture::ImageCaptureSource
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_session_v1::ExtImageCopyCaptureSessionV1: $crate::protocols::image_copy_capture::ImageCopySessionData
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_cursor_session_v1::ExtImageCopyCaptureCursorSessionV1: $crate::protocols::image_copy_capture::ImageCaptureSource
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols::ext::image_copy_capture::v1::server::ext_image_copy_capture_frame_v1::ExtImageCopyCaptureFrameV1: $crate::protocols::image_copy_capture::ImageCopyFrameData
+        ] => $crate::protocols::image_copy_capture::ImageCopyCaptureState);
+    };
+}
@@ -0,0 +1,5 @@
+pub mod foreign_toplevel;
+pub mod image_copy_capture;
+pub mod output_management;
+pub mod output_power;
+pub mod screencopy;
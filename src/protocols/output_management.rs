@@ -0,0 +1,666 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use smithay::{
+    output::{Mode, Output},
+    reexports::{
+        wayland_protocols_wlr::output_management::v1::server::{
+            zwlr_output_configuration_head_v1::{self, ZwlrOutputConfigurationHeadV1},
+            zwlr_output_configuration_v1::{self, ZwlrOutputConfigurationV1},
+            zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+            zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+            zwlr_output_mode_v1::{self, ZwlrOutputModeV1},
+        },
+        wayland_server::{
+            backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New,
+            Resource, WEnum,
+        },
+    },
+    utils::{Logical, Point, Transform},
+};
+
+use crate::config::{ModeConfig, OutputConfig, PositionConfig, ScaleConfig, TransformConfig};
+
+const VERSION: u32 = 4;
+
+/// Everything `zwlr_output_head_v1` advertises about a connected output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputHead {
+    pub name: String,
+    pub description: String,
+    pub make: String,
+    pub model: String,
+    pub serial: String,
+    /// Physical size in millimeters.
+    pub physical_size: (i32, i32),
+    pub modes: Vec<Mode>,
+    pub preferred_mode: Option<Mode>,
+    /// Whether modes outside of `modes` can be set.
+    pub custom_modes: bool,
+    /// `None` when the head is disabled.
+    pub state: Option<HeadState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadState {
+    pub mode: Option<Mode>,
+    pub position: Point<i32, Logical>,
+    pub transform: Transform,
+    pub scale: f64,
+}
+
+impl OutputHead {
+    pub fn from_output(output: &Output) -> Self {
+        let physical = output.physical_properties();
+        Self {
+            name: output.name(),
+            description: output.description(),
+            make: physical.make,
+            model: physical.model,
+            serial: String::new(),
+            physical_size: physical.size.into(),
+            modes: output.modes(),
+            preferred_mode: output.preferred_mode(),
+            custom_modes: false,
+            state: Some(HeadState {
+                mode: output.current_mode(),
+                position: output.current_location(),
+                transform: output.current_transform(),
+                scale: output.current_scale().fractional_scale(),
+            }),
+        }
+    }
+}
+
+pub trait OutputManagementHandler {
+    fn output_management_state(&mut self) -> &mut OutputManagementState;
+
+    /// Applies (or only validates when `test_only` is set) a full configuration coming from a
+    /// client. Returns whether it succeeded.
+    fn apply_output_configuration(&mut self, configs: Vec<OutputConfig>, test_only: bool) -> bool;
+}
+
+pub struct OutputManagementState {
+    display: DisplayHandle,
+    serial: u32,
+    heads: HashMap<String, OutputHead>,
+    /// Keyed by manager, a client can bind the global more than once.
+    managers: HashMap<ZwlrOutputManagerV1, ClientHeads>,
+}
+
+struct ClientHeads {
+    heads: HashMap<String, (ZwlrOutputHeadV1, Vec<ZwlrOutputModeV1>)>,
+}
+
+pub struct OutputConfigurationData {
+    serial: u32,
+    inner: Mutex<ConfigurationInner>,
+}
+
+#[derive(Default)]
+struct ConfigurationInner {
+    used: bool,
+    /// `None` for disabled heads.
+    heads: Vec<(String, Option<ZwlrOutputConfigurationHeadV1>)>,
+}
+
+#[derive(Default)]
+pub struct PendingHead {
+    name: String,
+    mode: Option<ModeChoice>,
+    position: Option<Point<i32, Logical>>,
+    transform: Option<Transform>,
+    scale: Option<f64>,
+}
+
+#[derive(Clone, Copy)]
+enum ModeChoice {
+    Advertised(Mode),
+    /// Refresh of 0 means any refresh rate.
+    Custom { width: i32, height: i32, refresh: i32 },
+}
+
+impl OutputManagementState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ZwlrOutputManagerV1, ()>,
+        D: Dispatch<ZwlrOutputManagerV1, ()>,
+        D: 'static,
+    {
+        display.create_global::<D, ZwlrOutputManagerV1, _>(VERSION, ());
+
+        Self {
+            display: display.clone(),
+            serial: 0,
+            heads: HashMap::new(),
+            managers: HashMap::new(),
+        }
+    }
+
+    /// Updates the heads and notifies clients if anything changed. Backends call this whenever
+    /// outputs are connected, disconnected or reconfigured.
+    pub fn notify_changes<D>(&mut self, heads: Vec<OutputHead>)
+    where
+        D: Dispatch<ZwlrOutputHeadV1, String>,
+        D: Dispatch<ZwlrOutputModeV1, Mode>,
+        D: 'static,
+    {
+        let heads: HashMap<_, _> = heads.into_iter().map(|head| (head.name.clone(), head)).collect();
+        if heads == self.heads {
+            return;
+        }
+
+        self.serial = self.serial.wrapping_add(1);
+
+        for (manager, client) in &mut self.managers {
+            client.heads.retain(|name, (wl_head, wl_modes)| {
+                let keep = match (self.heads.get(name), heads.get(name)) {
+                    // Mode objects can't be updated, so heads with new modes are recreated.
+                    (Some(old), Some(new)) => old.modes == new.modes,
+                    _ => false,
+                };
+
+                if !keep {
+                    for wl_mode in wl_modes.drain(..) {
+                        wl_mode.finished();
+                    }
+                    wl_head.finished();
+                }
+
+                keep
+            });
+
+            for head in heads.values() {
+                match client.heads.get(&head.name) {
+                    Some((wl_head, wl_modes)) => {
+                        if self.heads.get(&head.name) != Some(head) {
+                            send_head_state(wl_head, wl_modes, head);
+                        }
+                    }
+                    None => create_head::<D>(&self.display, manager, client, head),
+                }
+            }
+
+            manager.done(self.serial);
+        }
+
+        self.heads = heads;
+    }
+}
+
+fn create_head<D>(
+    display: &DisplayHandle,
+    manager: &ZwlrOutputManagerV1,
+    client_heads: &mut ClientHeads,
+    head: &OutputHead,
+) where
+    D: Dispatch<ZwlrOutputHeadV1, String>,
+    D: Dispatch<ZwlrOutputModeV1, Mode>,
+    D: 'static,
+{
+    let Ok(client) = display.get_client(manager.id()) else {
+        return;
+    };
+
+    let Ok(wl_head) =
+        client.create_resource::<ZwlrOutputHeadV1, _, D>(display, manager.version(), head.name.clone())
+    else {
+        return;
+    };
+    manager.head(&wl_head);
+
+    wl_head.name(head.name.clone());
+    wl_head.description(head.description.clone());
+    wl_head.physical_size(head.physical_size.0, head.physical_size.1);
+
+    let mut wl_modes = Vec::with_capacity(head.modes.len());
+    for mode in &head.modes {
+        let Ok(wl_mode) = client.create_resource::<ZwlrOutputModeV1, _, D>(display, wl_head.version(), *mode)
+        else {
+            continue;
+        };
+        wl_head.mode(&wl_mode);
+        wl_mode.size(mode.size.w, mode.size.h);
+        wl_mode.refresh(mode.refresh);
+        if head.preferred_mode == Some(*mode) {
+            wl_mode.preferred();
+        }
+        wl_modes.push(wl_mode);
+    }
+
+    if wl_head.version() >= zwlr_output_head_v1::EVT_MAKE_SINCE {
+        wl_head.make(head.make.clone());
+        wl_head.model(head.model.clone());
+        if !head.serial.is_empty() {
+            wl_head.serial_number(head.serial.clone());
+        }
+    }
+
+    send_head_state(&wl_head, &wl_modes, head);
+
+    client_heads.heads.insert(head.name.clone(), (wl_head, wl_modes));
+}
+
+fn send_head_state(wl_head: &ZwlrOutputHeadV1, wl_modes: &[ZwlrOutputModeV1], head: &OutputHead) {
+    let Some(state) = head.state else {
+        wl_head.enabled(0);
+        return;
+    };
+
+    wl_head.enabled(1);
+    if let Some(wl_mode) = state
+        .mode
+        .and_then(|mode| wl_modes.iter().find(|wl_mode| wl_mode.data::<Mode>() == Some(&mode)))
+    {
+        wl_head.current_mode(wl_mode);
+    }
+    wl_head.position(state.position.x, state.position.y);
+    wl_head.transform(state.transform.into());
+    wl_head.scale(state.scale);
+}
+
+impl<D> GlobalDispatch<ZwlrOutputManagerV1, (), D> for OutputManagementState
+where
+    D: GlobalDispatch<ZwlrOutputManagerV1, ()>,
+    D: Dispatch<ZwlrOutputManagerV1, ()>,
+    D: Dispatch<ZwlrOutputHeadV1, String>,
+    D: Dispatch<ZwlrOutputModeV1, Mode>,
+    D: OutputManagementHandler,
+    D: 'static,
+{
+    fn bind(
+        state: &mut D,
+        display: &DisplayHandle,
+        _client: &Client,
+        manager: New<ZwlrOutputManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        let manager = data_init.init(manager, ());
+        let state = state.output_management_state();
+
+        let mut client_heads = ClientHeads {
+            heads: HashMap::new(),
+        };
+        for head in state.heads.values() {
+            create_head::<D>(display, &manager, &mut client_heads, head);
+        }
+        manager.done(state.serial);
+
+        state.managers.insert(manager, client_heads);
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputManagerV1, (), D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputManagerV1, ()>,
+    D: Dispatch<ZwlrOutputConfigurationV1, OutputConfigurationData>,
+    D: OutputManagementHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        manager: &ZwlrOutputManagerV1,
+        request: zwlr_output_manager_v1::Request,
+        _data: &(),
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_output_manager_v1::Request::CreateConfiguration { id, serial } => {
+                data_init.init(
+                    id,
+                    OutputConfigurationData {
+                        serial,
+                        inner: Mutex::new(ConfigurationInner::default()),
+                    },
+                );
+            }
+            zwlr_output_manager_v1::Request::Stop => {
+                state.output_management_state().managers.remove(manager);
+                manager.finished();
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(state: &mut D, _client: ClientId, manager: &ZwlrOutputManagerV1, _data: &()) {
+        state.output_management_state().managers.remove(manager);
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputHeadV1, String, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputHeadV1, String>,
+    D: OutputManagementHandler,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _head: &ZwlrOutputHeadV1,
+        request: zwlr_output_head_v1::Request,
+        _data: &String,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_output_head_v1::Request::Release => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputModeV1, Mode, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputModeV1, Mode>,
+    D: OutputManagementHandler,
+{
+    fn request(
+        _state: &mut D,
+        _client: &Client,
+        _mode: &ZwlrOutputModeV1,
+        request: zwlr_output_mode_v1::Request,
+        _data: &Mode,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_output_mode_v1::Request::Release => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputConfigurationV1, OutputConfigurationData, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputConfigurationV1, OutputConfigurationData>,
+    D: Dispatch<ZwlrOutputConfigurationHeadV1, Mutex<PendingHead>>,
+    D: OutputManagementHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        configuration: &ZwlrOutputConfigurationV1,
+        request: zwlr_output_configuration_v1::Request,
+        data: &OutputConfigurationData,
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        let mut inner = data.inner.lock().unwrap();
+
+        let head_name = |head: &ZwlrOutputHeadV1| head.data::<String>().cloned().unwrap_or_default();
+
+        match request {
+            zwlr_output_configuration_v1::Request::EnableHead { id, head } => {
+                let name = head_name(&head);
+                let pending = data_init.init(
+                    id,
+                    Mutex::new(PendingHead {
+                        name: name.clone(),
+                        ..Default::default()
+                    }),
+                );
+
+                if inner.used {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::AlreadyUsed,
+                        "configuration already applied or tested",
+                    );
+                } else if inner.heads.iter().any(|(configured, _)| *configured == name) {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::AlreadyConfiguredHead,
+                        "head configured twice",
+                    );
+                } else {
+                    inner.heads.push((name, Some(pending)));
+                }
+            }
+            zwlr_output_configuration_v1::Request::DisableHead { head } => {
+                let name = head_name(&head);
+
+                if inner.used {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::AlreadyUsed,
+                        "configuration already applied or tested",
+                    );
+                } else if inner.heads.iter().any(|(configured, _)| *configured == name) {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::AlreadyConfiguredHead,
+                        "head configured twice",
+                    );
+                } else {
+                    inner.heads.push((name, None));
+                }
+            }
+            zwlr_output_configuration_v1::Request::Apply | zwlr_output_configuration_v1::Request::Test => {
+                let test_only = matches!(request, zwlr_output_configuration_v1::Request::Test);
+
+                if inner.used {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::AlreadyUsed,
+                        "configuration already applied or tested",
+                    );
+                    return;
+                }
+                inner.used = true;
+
+                let management_state = state.output_management_state();
+                if data.serial != management_state.serial {
+                    configuration.cancelled();
+                    return;
+                }
+
+                if let Some(missing) = management_state
+                    .heads
+                    .keys()
+                    .find(|name| !inner.heads.iter().any(|(configured, _)| configured == *name))
+                {
+                    configuration.post_error(
+                        zwlr_output_configuration_v1::Error::UnconfiguredHead,
+                        format!("head {missing} is missing from the configuration"),
+                    );
+                    return;
+                }
+
+                let configs: Option<Vec<_>> = inner
+                    .heads
+                    .iter()
+                    .map(|(name, pending)| {
+                        let head = management_state.heads.get(name)?;
+                        match pending {
+                            None => Some(OutputConfig {
+                                name: name.clone(),
+                                enabled: false,
+                                mode: None,
+                                position: None,
+                                scale: None,
+                                transform: None,
+                            }),
+                            Some(pending) => pending_to_config(head, &pending.data::<Mutex<PendingHead>>()?.lock().unwrap()),
+                        }
+                    })
+                    .collect();
+
+                let Some(configs) = configs else {
+                    configuration.failed();
+                    return;
+                };
+
+                if state.apply_output_configuration(configs, test_only) {
+                    configuration.succeeded();
+                } else {
+                    configuration.failed();
+                }
+            }
+            zwlr_output_configuration_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Fills in everything the client didn't set from the current state of the head.
+fn pending_to_config(head: &OutputHead, pending: &PendingHead) -> Option<OutputConfig> {
+    let current = head.state;
+
+    let mode = match pending.mode {
+        Some(ModeChoice::Advertised(mode)) => Some(mode),
+        Some(ModeChoice::Custom { width, height, refresh }) => {
+            let matching = head
+                .modes
+                .iter()
+                .filter(|mode| mode.size == (width, height).into())
+                .min_by_key(|mode| if refresh == 0 { 0 } else { (mode.refresh - refresh).abs() })
+                .copied();
+
+            match matching {
+                Some(mode) => Some(mode),
+                None if head.custom_modes => Some(Mode {
+                    size: (width, height).into(),
+                    refresh: if refresh == 0 { 60_000 } else { refresh },
+                }),
+                None => return None,
+            }
+        }
+        None => current.and_then(|state| state.mode).or(head.preferred_mode),
+    };
+
+    let position = pending.position.or(current.map(|state| state.position));
+    let transform = pending.transform.or(current.map(|state| state.transform));
+    let scale = pending.scale.or(current.map(|state| state.scale));
+
+    Some(OutputConfig {
+        name: head.name.clone(),
+        enabled: true,
+        mode: mode.map(|mode| ModeConfig {
+            width: mode.size.w as u16,
+            height: mode.size.h as u16,
+            refresh: Some(mode.refresh as f64 / 1000.),
+        }),
+        position: position.map(|position| PositionConfig {
+            x: position.x,
+            y: position.y,
+        }),
+        scale: scale.map(ScaleConfig),
+        transform: transform.map(TransformConfig),
+    })
+}
+
+impl<D> Dispatch<ZwlrOutputConfigurationHeadV1, Mutex<PendingHead>, D> for OutputManagementState
+where
+    D: Dispatch<ZwlrOutputConfigurationHeadV1, Mutex<PendingHead>>,
+    D: OutputManagementHandler,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        configuration_head: &ZwlrOutputConfigurationHeadV1,
+        request: zwlr_output_configuration_head_v1::Request,
+        data: &Mutex<PendingHead>,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        let mut pending = data.lock().unwrap();
+
+        let already_set = || {
+            configuration_head.post_error(
+                zwlr_output_configuration_head_v1::Error::AlreadySet,
+                "property already set",
+            )
+        };
+
+        match request {
+            zwlr_output_configuration_head_v1::Request::SetMode { mode } => {
+                if pending.mode.is_some() {
+                    return already_set();
+                }
+
+                let mode = mode.data::<Mode>().copied();
+                let head = state.output_management_state().heads.get(&pending.name);
+                match (mode, head) {
+                    (Some(mode), Some(head)) if head.modes.contains(&mode) => {
+                        pending.mode = Some(ModeChoice::Advertised(mode));
+                    }
+                    _ => configuration_head.post_error(
+                        zwlr_output_configuration_head_v1::Error::InvalidMode,
+                        "mode doesn't belong to head",
+                    ),
+                }
+            }
+            zwlr_output_configuration_head_v1::Request::SetCustomMode { width, height, refresh } => {
+                if pending.mode.is_some() {
+                    return already_set();
+                }
+
+                if width <= 0 || height <= 0 || refresh < 0 {
+                    configuration_head.post_error(
+                        zwlr_output_configuration_head_v1::Error::InvalidCustomMode,
+                        "invalid custom mode",
+                    );
+                    return;
+                }
+
+                pending.mode = Some(ModeChoice::Custom { width, height, refresh });
+            }
+            zwlr_output_configuration_head_v1::Request::SetPosition { x, y } => {
+                if pending.position.is_some() {
+                    return already_set();
+                }
+                pending.position = Some((x, y).into());
+            }
+            zwlr_output_configuration_head_v1::Request::SetTransform { transform } => {
+                if pending.transform.is_some() {
+                    return already_set();
+                }
+
+                match transform {
+                    WEnum::Value(transform) => pending.transform = Some(transform.into()),
+                    WEnum::Unknown(_) => configuration_head.post_error(
+                        zwlr_output_configuration_head_v1::Error::InvalidTransform,
+                        "unknown transform",
+                    ),
+                }
+            }
+            zwlr_output_configuration_head_v1::Request::SetScale { scale } => {
+                if pending.scale.is_some() {
+                    return already_set();
+                }
+
+                match ScaleConfig::try_from(scale) {
+                    Ok(scale) => pending.scale = Some(scale.0),
+                    Err(err) => configuration_head.post_error(
+                        zwlr_output_configuration_head_v1::Error::InvalidScale,
+                        err.to_string(),
+                    ),
+                }
+            }
+            // Adaptive sync isn't supported, requests for it are ignored.
+            zwlr_output_configuration_head_v1::Request::SetAdaptiveSync { .. } => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_output_management {
+    ($ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_manager_v1::ZwlrOutputManagerV1: ()
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_manager_v1::ZwlrOutputManagerV1: ()
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_head_v1::ZwlrOutputHeadV1: String
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_mode_v1::ZwlrOutputModeV1: smithay::output::Mode
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_configuration_v1::ZwlrOutputConfigurationV1: $crate::protocols::output_management::OutputConfigurationData
+        ] => $crate::protocols::output_management::OutputManagementState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_management::v1::server::zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1: std::sync::Mutex<$crate::protocols::output_management::PendingHead>
+        ] => $crate::protocols::output_management::OutputManagementState);
+    };
+}
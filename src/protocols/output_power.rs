@@ -0,0 +1,182 @@
+use smithay::{
+    output::{Output, WeakOutput},
+    reexports::{
+        wayland_protocols_wlr::output_power_management::v1::server::{
+            zwlr_output_power_manager_v1::{self, ZwlrOutputPowerManagerV1},
+            zwlr_output_power_v1::{self, Mode, ZwlrOutputPowerV1},
+        },
+        wayland_server::{
+            backend::ClientId, Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+        },
+    },
+};
+
+const VERSION: u32 = 1;
+
+pub trait OutputPowerHandler {
+    fn output_power_state(&mut self) -> &mut OutputPowerState;
+    /// Whether the output is on, `None` when it isn't a mapped output.
+    fn output_power(&mut self, output: &Output) -> Option<bool>;
+    fn set_output_power(&mut self, output: &Output, on: bool);
+}
+
+pub struct OutputPowerState {
+    controls: Vec<ZwlrOutputPowerV1>,
+}
+
+impl OutputPowerState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ZwlrOutputPowerManagerV1, ()>,
+        D: Dispatch<ZwlrOutputPowerManagerV1, ()>,
+        D: 'static,
+    {
+        display.create_global::<D, ZwlrOutputPowerManagerV1, _>(VERSION, ());
+        Self { controls: Vec::new() }
+    }
+
+    /// Tells the clients controlling `output` about its new mode.
+    pub fn output_power_changed(&self, output: &Output, on: bool) {
+        for control in self.controls_for(output) {
+            control.mode(mode(on));
+        }
+    }
+
+    /// Controls of outputs that went away fail, clients have to get new ones.
+    pub fn output_removed(&mut self, output: &Output) {
+        for control in self.controls_for(output) {
+            control.failed();
+        }
+        self.controls
+            .retain(|control| !control.data::<WeakOutput>().is_some_and(|o| o == output));
+    }
+
+    fn controls_for<'a>(&'a self, output: &'a Output) -> impl Iterator<Item = &'a ZwlrOutputPowerV1> {
+        self.controls
+            .iter()
+            .filter(move |control| control.data::<WeakOutput>().is_some_and(|o| o == output))
+    }
+}
+
+fn mode(on: bool) -> Mode {
+    if on {
+        Mode::On
+    } else {
+        Mode::Off
+    }
+}
+
+impl<D> GlobalDispatch<ZwlrOutputPowerManagerV1, (), D> for OutputPowerState
+where
+    D: GlobalDispatch<ZwlrOutputPowerManagerV1, ()>,
+    D: Dispatch<ZwlrOutputPowerManagerV1, ()>,
+    D: Dispatch<ZwlrOutputPowerV1, WeakOutput>,
+    D: OutputPowerHandler,
+    D: 'static,
+{
+    fn bind(
+        _state: &mut D,
+        _display: &DisplayHandle,
+        _client: &Client,
+        manager: New<ZwlrOutputPowerManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        data_init.init(manager, ());
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputPowerManagerV1, (), D> for OutputPowerState
+where
+    D: Dispatch<ZwlrOutputPowerManagerV1, ()>,
+    D: Dispatch<ZwlrOutputPowerV1, WeakOutput>,
+    D: OutputPowerHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        _manager: &ZwlrOutputPowerManagerV1,
+        request: zwlr_output_power_manager_v1::Request,
+        _data: &(),
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_output_power_manager_v1::Request::GetOutputPower { id, output } => {
+                let output = Output::from_resource(&output);
+                let weak = output.as_ref().map(Output::downgrade).unwrap_or_default();
+                let control = data_init.init(id, weak);
+
+                match output.and_then(|output| state.output_power(&output)) {
+                    Some(on) => {
+                        control.mode(mode(on));
+                        state.output_power_state().controls.push(control);
+                    }
+                    None => control.failed(),
+                }
+            }
+            zwlr_output_power_manager_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<D> Dispatch<ZwlrOutputPowerV1, WeakOutput, D> for OutputPowerState
+where
+    D: Dispatch<ZwlrOutputPowerV1, WeakOutput>,
+    D: OutputPowerHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        control: &ZwlrOutputPowerV1,
+        request: zwlr_output_power_v1::Request,
+        output: &WeakOutput,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        match request {
+            zwlr_output_power_v1::Request::SetMode { mode } => {
+                // Failed controls stay around until the client destroys them, but are inert.
+                if !state.output_power_state().controls.contains(control) {
+                    return;
+                }
+                let Some(output) = output.upgrade() else {
+                    return;
+                };
+
+                match mode {
+                    WEnum::Value(Mode::On) => state.set_output_power(&output, true),
+                    WEnum::Value(Mode::Off) => state.set_output_power(&output, false),
+                    _ => control.post_error(zwlr_output_power_v1::Error::InvalidMode, "unknown power mode"),
+                }
+            }
+            zwlr_output_power_v1::Request::Destroy => (),
+            _ => unreachable!(),
+        }
+    }
+
+    fn destroyed(state: &mut D, _client: ClientId, control: &ZwlrOutputPowerV1, _data: &WeakOutput) {
+        state
+            .output_power_state()
+            .controls
+            .retain(|c| c != control);
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_output_power {
+    ($ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_power_management::v1::server::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1: ()
+        ] => $crate::protocols::output_power::OutputPowerState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_power_management::v1::server::zwlr_output_power_manager_v1::ZwlrOutputPowerManagerV1: ()
+        ] => $crate::protocols::output_power::OutputPowerState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::output_power_management::v1::server::zwlr_output_power_v1::ZwlrOutputPowerV1: smithay::output::WeakOutput
+        ] => $crate::protocols::output_power::OutputPowerState);
+    };
+}
@@ -0,0 +1,278 @@
+use std::{sync::atomic::{AtomicBool, Ordering}, time::Duration};
+
+use smithay::{
+    backend::allocator::Fourcc,
+    output::Output,
+    reexports::{
+        wayland_protocols_wlr::screencopy::v1::server::{
+            zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+            zwlr_screencopy_manager_v1::{self, ZwlrScreencopyManagerV1},
+        },
+        wayland_server::{
+            backend::ObjectId,
+            protocol::{wl_buffer::WlBuffer, wl_shm},
+            Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+        },
+    },
+    utils::{Buffer as BufferCoords, Physical, Rectangle, Size},
+};
+
+use crate::capture::{BufferConstraints, DmabufConstraints};
+
+const VERSION: u32 = 3;
+
+pub trait ScreencopyHandler {
+    fn screencopy_state(&mut self) -> &mut ScreencopyState;
+
+    /// Dmabufs captures can be copied into, `None` when only shm buffers are supported.
+    fn dmabuf_constraints(&mut self) -> Option<DmabufConstraints>;
+
+    /// A client asked for a frame to be copied into `frame.buffer`.
+    fn frame(&mut self, frame: ScreencopyFrame);
+}
+
+pub struct ScreencopyState;
+
+pub struct ScreencopyFrameData {
+    /// `None` for frames that failed right away.
+    target: Option<FrameTarget>,
+    used: AtomicBool,
+}
+
+struct FrameTarget {
+    output: Output,
+    region: Rectangle<i32, Physical>,
+    overlay_cursor: bool,
+    constraints: BufferConstraints,
+    manager: ObjectId,
+}
+
+/// A copy request waiting to be rendered. Fails the frame when dropped without `success`.
+pub struct ScreencopyFrame {
+    frame: ZwlrScreencopyFrameV1,
+    pub output: Output,
+    /// The captured area in physical output coordinates.
+    pub region: Rectangle<i32, Physical>,
+    pub overlay_cursor: bool,
+    /// Set for `copy_with_damage`, the copy then waits until the region changed.
+    pub with_damage: bool,
+    pub buffer: WlBuffer,
+    /// Captures by the same manager share damage tracking.
+    pub manager: ObjectId,
+    done: bool,
+}
+
+impl ScreencopyFrame {
+    /// Sends the damage since the previous capture, followed by the presentation time.
+    pub fn success(mut self, damage: &[Rectangle<i32, BufferCoords>], time: Duration) {
+        self.done = true;
+
+        self.frame.flags(zwlr_screencopy_frame_v1::Flags::empty());
+        if self.with_damage {
+            for rect in damage {
+                self.frame.damage(rect.loc.x as u32, rect.loc.y as u32, rect.size.w as u32, rect.size.h as u32);
+            }
+        }
+
+        let secs = time.as_secs();
+        self.frame.ready((secs >> 32) as u32, secs as u32, time.subsec_nanos());
+    }
+
+    pub fn failed(mut self) {
+        self.done = true;
+        self.frame.failed();
+    }
+}
+
+impl Drop for ScreencopyFrame {
+    fn drop(&mut self) {
+        if !self.done {
+            self.frame.failed();
+        }
+    }
+}
+
+impl ScreencopyState {
+    pub fn new<D>(display: &DisplayHandle) -> Self
+    where
+        D: GlobalDispatch<ZwlrScreencopyManagerV1, ()>,
+        D: Dispatch<ZwlrScreencopyManagerV1, ()>,
+        D: 'static,
+    {
+        display.create_global::<D, ZwlrScreencopyManagerV1, _>(VERSION, ());
+        Self
+    }
+}
+
+impl<D> GlobalDispatch<ZwlrScreencopyManagerV1, (), D> for ScreencopyState
+where
+    D: GlobalDispatch<ZwlrScreencopyManagerV1, ()>,
+    D: Dispatch<ZwlrScreencopyManagerV1, ()>,
+    D: ScreencopyHandler,
+    D: 'static,
+{
+    fn bind(
+        _state: &mut D,
+        _display: &DisplayHandle,
+        _client: &Client,
+        manager: New<ZwlrScreencopyManagerV1>,
+        _global_data: &(),
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        data_init.init(manager, ());
+    }
+}
+
+impl<D> Dispatch<ZwlrScreencopyManagerV1, (), D> for ScreencopyState
+where
+    D: Dispatch<ZwlrScreencopyManagerV1, ()>,
+    D: Dispatch<ZwlrScreencopyFrameV1, ScreencopyFrameData>,
+    D: ScreencopyHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        manager: &ZwlrScreencopyManagerV1,
+        request: zwlr_screencopy_manager_v1::Request,
+        _data: &(),
+        _display: &DisplayHandle,
+        data_init: &mut DataInit<'_, D>,
+    ) {
+        let (frame, overlay_cursor, output, region) = match request {
+            zwlr_screencopy_manager_v1::Request::CaptureOutput { frame, overlay_cursor, output } => {
+                (frame, overlay_cursor, output, None)
+            }
+            zwlr_screencopy_manager_v1::Request::CaptureOutputRegion {
+                frame,
+                overlay_cursor,
+                output,
+                x,
+                y,
+                width,
+                height,
+            } => (frame, overlay_cursor, output, Some(Rectangle::new((x, y).into(), (width, height).into()))),
+            zwlr_screencopy_manager_v1::Request::Destroy => return,
+            _ => unreachable!(),
+        };
+
+        let target = Output::from_resource(&output).and_then(|output| {
+            let mode = output.current_mode()?;
+            let transform = output.current_transform();
+            let output_rect = Rectangle::from_size(transform.transform_size(mode.size));
+
+            // Regions are in logical output coordinates.
+            let region = match region {
+                Some(region) => region
+                    .to_f64()
+                    .to_physical_precise_round(output.current_scale().fractional_scale())
+                    .intersection(output_rect)?,
+                None => output_rect,
+            };
+            if region.is_empty() {
+                return None;
+            }
+
+            let size = transform.transform_size(region.size);
+            let constraints = BufferConstraints {
+                size: Size::from((size.w, size.h)),
+                dmabuf: state.dmabuf_constraints(),
+            };
+
+            Some(FrameTarget {
+                output,
+                region,
+                overlay_cursor: overlay_cursor != 0,
+                constraints,
+                manager: manager.id(),
+            })
+        });
+
+        let frame = data_init.init(
+            frame,
+            ScreencopyFrameData {
+                target,
+                used: AtomicBool::new(false),
+            },
+        );
+
+        let Some(target) = &frame.data::<ScreencopyFrameData>().unwrap().target else {
+            frame.failed();
+            return;
+        };
+
+        let size = target.constraints.size;
+        frame.buffer(wl_shm::Format::Xrgb8888, size.w as u32, size.h as u32, size.w as u32 * 4);
+        if frame.version() >= 3 {
+            if target.constraints.dmabuf.is_some() {
+                frame.linux_dmabuf(Fourcc::Xrgb8888 as u32, size.w as u32, size.h as u32);
+            }
+            frame.buffer_done();
+        }
+    }
+}
+
+impl<D> Dispatch<ZwlrScreencopyFrameV1, ScreencopyFrameData, D> for ScreencopyState
+where
+    D: Dispatch<ZwlrScreencopyFrameV1, ScreencopyFrameData>,
+    D: ScreencopyHandler,
+    D: 'static,
+{
+    fn request(
+        state: &mut D,
+        _client: &Client,
+        frame: &ZwlrScreencopyFrameV1,
+        request: zwlr_screencopy_frame_v1::Request,
+        data: &ScreencopyFrameData,
+        _display: &DisplayHandle,
+        _data_init: &mut DataInit<'_, D>,
+    ) {
+        let (buffer, with_damage) = match request {
+            zwlr_screencopy_frame_v1::Request::Copy { buffer } => (buffer, false),
+            zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => (buffer, true),
+            zwlr_screencopy_frame_v1::Request::Destroy => return,
+            _ => unreachable!(),
+        };
+
+        // Frames that already failed are inert.
+        let Some(target) = &data.target else {
+            return;
+        };
+
+        if data.used.swap(true, Ordering::Relaxed) {
+            frame.post_error(zwlr_screencopy_frame_v1::Error::AlreadyUsed, "frame was already copied");
+            return;
+        }
+
+        if !target.constraints.check_buffer(&buffer) {
+            frame.post_error(zwlr_screencopy_frame_v1::Error::InvalidBuffer, "buffer doesn't match the constraints");
+            return;
+        }
+
+        state.frame(ScreencopyFrame {
+            frame: frame.clone(),
+            output: target.output.clone(),
+            region: target.region,
+            overlay_cursor: target.overlay_cursor,
+            with_damage,
+            buffer,
+            manager: target.manager.clone(),
+            done: false,
+        });
+    }
+}
+
+#[macro_export]
+macro_rules! delegate_screencopy {
+    ($ty: ty) => {
+        smithay::reexports::wayland_server::delegate_global_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::screencopy::v1::server::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1: ()
+        ] => $crate::protocols::screencopy::ScreencopyState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::screencopy::v1::server::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1: ()
+        ] => $crate::protocols::screencopy::ScreencopyState);
+        smithay::reexports::wayland_server::delegate_dispatch!($ty: [
+            smithay::reexports::wayland_protocols_wlr::screencopy::v1::server::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1: $crate::protocols::screencopy::ScreencopyFrameData
+        ] => $crate::protocols::screencopy::ScreencopyState);
+    };
+}
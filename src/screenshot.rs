@@ -0,0 +1,281 @@
+//! Screenshots taken by the compositor itself: Ctrl+Print captures the output under the pointer,
+//! Shift+Print the window under the pointer and Print a region selected with the pointer.
+
+use std::{
+    collections::HashMap,
+    fs,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use smithay::{
+    backend::{
+        allocator::Fourcc,
+        renderer::{
+            element::{
+                solid::{SolidColorBuffer, SolidColorRenderElement},
+                utils::{Relocate, RelocateRenderElement},
+                Kind,
+            },
+            gles::GlesRenderer,
+        },
+    },
+    desktop::Window,
+    input::pointer::{Focus, GrabStartData as PointerGrabStartData},
+    output::Output,
+    reexports::calloop::channel,
+    utils::{Logical, Physical, Point, Rectangle, Size, Transform, SERIAL_COUNTER},
+};
+
+use crate::{capture::render_to_memory, grabs::ScreenshotRegionGrab, state::OutputRenderElements, Tsuki};
+
+const SELECTION_BORDER: i32 = 2;
+const SELECTION_BORDER_COLOR: [f32; 4] = [0.9, 0.9, 0.9, 1.0];
+const SELECTION_FILL_COLOR: [f32; 4] = [0.1, 0.1, 0.1, 0.2];
+
+#[derive(Debug, Clone, Copy)]
+pub enum ScreenshotTarget {
+    Output,
+    Window,
+    Region,
+}
+
+/// The rectangle being dragged out for a region screenshot. Selections stay on the output they
+/// started on.
+pub struct RegionSelection {
+    pub output: Output,
+    pub start: Option<Point<f64, Logical>>,
+    pub current: Point<f64, Logical>,
+    fill: SolidColorBuffer,
+    borders: [SolidColorBuffer; 4],
+}
+
+impl RegionSelection {
+    /// The selected area in global coordinates, `None` until the first button press.
+    pub fn rect(&self) -> Option<Rectangle<i32, Logical>> {
+        let start = self.start?;
+        let loc = Point::from((start.x.min(self.current.x), start.y.min(self.current.y)));
+        let end = Point::from((start.x.max(self.current.x), start.y.max(self.current.y)));
+        let rect = Rectangle::from_extremities(loc.to_i32_round(), end.to_i32_round());
+        (!rect.is_empty()).then_some(rect)
+    }
+}
+
+impl Tsuki {
+    pub fn screenshot(&mut self, target: ScreenshotTarget) {
+        let pointer = self.seat.get_pointer().unwrap();
+        let location = pointer.current_location();
+
+        match target {
+            ScreenshotTarget::Output => {
+                let Some(output) = self.space.output_under(location).next().cloned() else {
+                    return;
+                };
+                let geo = self.space.output_geometry(&output).unwrap();
+                self.screenshot_output_region(&output, Rectangle::from_size(geo.size));
+            }
+            ScreenshotTarget::Window => {
+                if let Some(window) = self.window_under(location) {
+                    self.screenshot_window(&window);
+                }
+            }
+            ScreenshotTarget::Region => {
+                if pointer.is_grabbed() {
+                    return;
+                }
+                let Some(output) = self.space.output_under(location).next().cloned() else {
+                    return;
+                };
+
+                self.region_selection = Some(RegionSelection {
+                    output,
+                    start: None,
+                    current: location,
+                    fill: SolidColorBuffer::new((0, 0), SELECTION_FILL_COLOR),
+                    borders: std::array::from_fn(|_| SolidColorBuffer::new((0, 0), SELECTION_BORDER_COLOR)),
+                });
+                let start_data = PointerGrabStartData {
+                    focus: None,
+                    button: 0,
+                    location,
+                };
+                pointer.set_grab(self, ScreenshotRegionGrab { start_data }, SERIAL_COUNTER.next_serial(), Focus::Clear);
+            }
+        }
+    }
+
+    /// Takes the screenshot of a finished selection.
+    pub fn finish_region_selection(&mut self) {
+        let Some(selection) = self.region_selection.take() else {
+            return;
+        };
+        self.queue_redraw(&selection.output);
+
+        let Some(output_geo) = self.space.output_geometry(&selection.output) else {
+            return;
+        };
+        let Some(rect) = selection.rect().and_then(|rect| rect.intersection(output_geo)) else {
+            return;
+        };
+        self.screenshot_output_region(&selection.output, Rectangle::new(rect.loc - output_geo.loc, rect.size));
+    }
+
+    pub fn cancel_region_selection(&mut self) {
+        if let Some(selection) = self.region_selection.take() {
+            self.queue_redraw(&selection.output);
+        }
+    }
+
+    /// The selection rectangle drawn above everything but the cursor, relative to `output`.
+    pub fn region_selection_elements(&mut self, output: &Output) -> Vec<OutputRenderElements<GlesRenderer>> {
+        let Some(output_geo) = self.space.output_geometry(output) else {
+            return Vec::new();
+        };
+        let Some(selection) = self.region_selection.as_mut().filter(|selection| &selection.output == output) else {
+            return Vec::new();
+        };
+        let Some(rect) = selection.rect().and_then(|rect| rect.intersection(output_geo)) else {
+            return Vec::new();
+        };
+
+        let rect = Rectangle::new(rect.loc - output_geo.loc, rect.size);
+        let (w, h) = (rect.size.w, rect.size.h);
+        let border_rects = [
+            Rectangle::new(rect.loc, (w, SELECTION_BORDER).into()),
+            Rectangle::new(rect.loc + Point::from((0, h - SELECTION_BORDER)), (w, SELECTION_BORDER).into()),
+            Rectangle::new(rect.loc, (SELECTION_BORDER, h).into()),
+            Rectangle::new(rect.loc + Point::from((w - SELECTION_BORDER, 0)), (SELECTION_BORDER, h).into()),
+        ];
+
+        let scale = output.current_scale().fractional_scale();
+        let mut elements = Vec::new();
+        for (buffer, border) in selection.borders.iter_mut().zip(border_rects) {
+            buffer.resize(border.size);
+            elements.push(OutputRenderElements::Solid(SolidColorRenderElement::from_buffer(
+                buffer,
+                border.loc.to_physical_precise_round(scale),
+                scale,
+                1.0,
+                Kind::Unspecified,
+            )));
+        }
+        selection.fill.resize(rect.size);
+        elements.push(OutputRenderElements::Solid(SolidColorRenderElement::from_buffer(
+            &selection.fill,
+            rect.loc.to_physical_precise_round(scale),
+            scale,
+            1.0,
+            Kind::Unspecified,
+        )));
+        elements
+    }
+
+    /// `region` is relative to the output, the image comes out upright at the output's scale.
+    fn screenshot_output_region(&mut self, output: &Output, region: Rectangle<i32, Logical>) {
+        let scale = output.current_scale().fractional_scale();
+        let region = region.to_f64().to_physical_precise_round(scale);
+
+        let backend = self.backend_data.clone();
+        let mut backend = backend.borrow_mut();
+        let Some(renderer) = backend.renderer() else {
+            return;
+        };
+
+        let elements = self.output_elements(renderer, output);
+        self.save_screenshot(renderer, elements, region, scale);
+    }
+
+    fn screenshot_window(&mut self, window: &Window) {
+        let (bbox, scale) = self.window_capture_geometry(window);
+
+        let backend = self.backend_data.clone();
+        let mut backend = backend.borrow_mut();
+        let Some(renderer) = backend.renderer() else {
+            return;
+        };
+
+        let (mut elements, window_elements) = self.window_elements(renderer, window, Point::default(), scale);
+        elements.extend(window_elements);
+        self.save_screenshot(renderer, elements, bbox, scale);
+    }
+
+    fn save_screenshot(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        elements: Vec<OutputRenderElements<GlesRenderer>>,
+        area: Rectangle<i32, Physical>,
+        scale: f64,
+    ) {
+        if area.is_empty() {
+            return;
+        }
+
+        let elements: Vec<_> = elements
+            .into_iter()
+            .map(|element| RelocateRenderElement::from_element(element, area.loc.upscale(-1), Relocate::Relative))
+            .collect();
+        // Abgr8888 reads back in the byte order PNG wants.
+        let pixels = match render_to_memory(renderer, area.size, scale.into(), Transform::Normal, &elements, Fourcc::Abgr8888) {
+            Ok(pixels) => pixels,
+            Err(err) => {
+                log::warn!("error rendering screenshot: {err:?}");
+                return;
+            }
+        };
+        let clipboard = self.config.screenshot.clipboard.then(|| {
+            let (sender, channel) = channel::channel();
+            let res = self.event_loop.insert_source(channel, |event, _, data| {
+                if let channel::Event::Msg(png) = event {
+                    data.tsuki.set_clipboard(HashMap::from([("image/png".to_owned(), png)]));
+                }
+            });
+            if let Err(err) = res {
+                log::warn!("error inserting screenshot channel: {err}");
+            }
+            sender
+        });
+
+        let directory = self.config.screenshot.directory();
+        if directory.is_none() {
+            log::warn!("no screenshot directory, HOME is not set");
+        }
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        // Encoding a large image takes a while and writing can block on slow disks, keep both
+        // off the event loop. The clipboard is set once the PNG is ready.
+        let size = area.size;
+        std::thread::spawn(move || {
+            let png = match encode_png(size, &pixels) {
+                Ok(png) => png,
+                Err(err) => {
+                    log::warn!("error encoding screenshot: {err}");
+                    return;
+                }
+            };
+
+            if let Some(directory) = directory {
+                let path = directory.join(format!("screenshot-{}.png", timestamp.as_millis()));
+                match fs::create_dir_all(&directory).and_then(|()| fs::write(&path, &png)) {
+                    Ok(()) => log::info!("saved screenshot to {path:?}"),
+                    Err(err) => log::warn!("error saving screenshot to {path:?}: {err}"),
+                }
+            }
+
+            if let Some(sender) = clipboard {
+                let _ = sender.send(png);
+            }
+        });
+    }
+}
+
+/// Encodes RGBA pixels as a PNG.
+pub fn encode_png(size: Size<i32, Physical>, rgba: &[u8]) -> Result<Vec<u8>, png::EncodingError> {
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, size.w as u32, size.h as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    writer.finish()?;
+    Ok(png)
+}
@@ -1,28 +1,120 @@
-use std::{cell::{RefCell, RefMut}, ffi::OsString, os::fd::AsFd, rc::Rc, sync::Arc, time::Duration};
+use std::{cell::RefCell, collections::{HashMap, HashSet}, ffi::OsString, mem, rc::Rc, sync::Arc, time::Duration};
 
 use smithay::{
-    backend::{self, drm::output::DrmOutputRenderElements, renderer::{element::{solid::SolidColorRenderElement, Kind}, utils::CommitCounter, ImportAll}}, desktop::{space::{space_render_elements, SpaceRenderElements}, PopupManager, Space, Window, WindowSurfaceType}, input::{Seat, SeatState}, output::Output, reexports::{
-        calloop::{generic::Generic, timer::Timer, EventLoop, Interest, LoopHandle, LoopSignal, Mode, PostAction},
+    backend::{self, allocator::Fourcc, drm::output::DrmOutputRenderElements, egl::EGLDevice, renderer::{element::{default_primary_scanout_output_compare, memory::MemoryRenderBufferRenderElement, solid::{SolidColorBuffer, SolidColorRenderElement}, RenderElementStates, surface::{render_elements_from_surface_tree, WaylandSurfaceRenderElement}, Kind}, gles::GlesRenderer, ImportAll, ImportDma, ImportMem}}, desktop::{utils::{bbox_from_surface_tree, send_frames_surface_tree, surface_presentation_feedback_flags_from_states, surface_primary_scanout_output, update_surface_primary_scanout_output, OutputPresentationFeedback}, PopupGrab, PopupManager, Space, Window, WindowSurfaceType}, input::{keyboard::Keycode, pointer::CursorImageStatus, Seat, SeatState}, output::Output, reexports::{
+        wayland_protocols::wp::presentation_time::server::wp_presentation_feedback,
+        calloop::{generic::Generic, timer::{TimeoutAction, Timer}, Interest, LoopHandle, LoopSignal, Mode, PostAction, RegistrationToken},
         wayland_server::{
             backend::{ClientData, ClientId, DisconnectReason},
             protocol::wl_surface::WlSurface,
-            Display, DisplayHandle,
-        }, x11rb::protocol::shape::Op,
-    }, render_elements, utils::{Logical, Point}, wayland::{
-        compositor::{CompositorClientState, CompositorState},
+            Display, DisplayHandle, Resource,
+        },
+    }, render_elements, utils::{Clock, Logical, Monotonic, Point, Rectangle}, wayland::{
+        compositor::{send_surface_state, CompositorClientState, CompositorState},
+        cursor_shape::CursorShapeManagerState,
+        dmabuf::{DmabufFeedback, DmabufFeedbackBuilder, DmabufGlobal, DmabufState},
+        foreign_toplevel_list::{ForeignToplevelHandle, ForeignToplevelListState},
+        fractional_scale::{with_fractional_scale, FractionalScaleManagerState},
+        idle_inhibit::IdleInhibitManagerState,
+        idle_notify::IdleNotifierState,
         output::OutputManagerState,
-        selection::data_device::DataDeviceState,
+        presentation::{PresentationState, Refresh},
+        session_lock::{LockSurface, SessionLockManagerState},
+        seat::WaylandFocus,
+        selection::{
+            data_device::DataDeviceState, ext_data_control, primary_selection::PrimarySelectionState,
+            wlr_data_control,
+        },
         shell::xdg::XdgShellState,
         shm::ShmState,
         socket::ListeningSocketSource,
+        tablet_manager::TabletManagerState,
+        viewporter::ViewporterState,
     }
 };
 
-use crate::{backend::Backend, CalloopData};
+#[cfg(feature = "xwayland")]
+use smithay::{wayland::xwayland_shell::XWaylandShellState, xwayland::X11Wm};
+#[cfg(feature = "xwayland")]
+use crate::xwayland::X11Sockets;
+
+use crate::{
+    backend::{Backend, RenderResult},
+    capture::{CaptureState, DmabufConstraints},
+    clipboard::ClipboardCache,
+    config::{Config, OutputConfig},
+    cursor::{CursorManager, RenderCursor},
+    frame_clock::{FrameClock, DEFAULT_REFRESH_INTERVAL},
+    idle::IdleTimers,
+    lock::{LockState, LOCK_BACKGROUND_COLOR},
+    screenshot::RegionSelection,
+    protocols::{
+        foreign_toplevel::ForeignToplevelManagerState,
+        image_copy_capture::ImageCopyCaptureState, output_power::OutputPowerState, output_management::OutputManagementState,
+        screencopy::ScreencopyState,
+    },
+    CalloopData,
+};
+
+const FRAME_CALLBACK_THROTTLE: Duration = Duration::from_secs(1);
+
+pub enum RedrawState {
+    /// Nothing to do for this output.
+    Idle,
+    /// A redraw will happen on the next event loop iteration.
+    Queued,
+    /// Waiting for the render delay timer before queueing the redraw.
+    Delayed(RegistrationToken),
+    /// A frame was submitted and we are waiting for its vblank.
+    WaitingForVBlank { redraw_needed: bool },
+    /// Nothing was submitted, the timer fires at the estimated vblank to send frame callbacks.
+    WaitingForEstimatedVBlank(RegistrationToken),
+    /// Same as above, but a redraw was requested in the meantime.
+    WaitingForEstimatedVBlankAndQueued(RegistrationToken),
+}
+
+pub struct OutputState {
+    pub redraw_state: RedrawState,
+    pub frame_clock: FrameClock,
+    /// Presentation feedback of the surfaces in the frame waiting for its vblank.
+    pub presentation_feedback: Option<OutputPresentationFeedback>,
+    pub lock_surface: Option<LockSurface>,
+    pub lock_background: SolidColorBuffer,
+    /// Whether a frame without any window made it to the output since locking started.
+    pub lock_rendered: bool,
+    /// Whether the frame waiting for its vblank shows the lock screen.
+    pub lock_frame_pending: bool,
+    /// Outputs that are off aren't rendered, the backend blanks them.
+    pub powered: bool,
+}
+
+impl OutputState {
+    /// Drops the frame in flight and the timers waiting on it, no vblank is coming for it.
+    fn cancel_frame(&mut self, event_loop: &LoopHandle<'static, CalloopData>) {
+        if let Some(mut feedback) = self.presentation_feedback.take() {
+            feedback.discarded();
+        }
+        self.lock_frame_pending = false;
+
+        match mem::replace(&mut self.redraw_state, RedrawState::Idle) {
+            RedrawState::Delayed(token)
+            | RedrawState::WaitingForEstimatedVBlank(token)
+            | RedrawState::WaitingForEstimatedVBlankAndQueued(token) => event_loop.remove(token),
+            _ => (),
+        }
+    }
+}
+
+/// The icon a client drags along with the pointer during drag-and-drop.
+pub struct DndIcon {
+    pub surface: WlSurface,
+    /// Sum of the offsets the client attached buffers with, relative to the pointer.
+    pub offset: Point<i32, Logical>,
+}
 
 pub struct Tsuki {
     pub start_time: std::time::Instant,
-    pub socket_name: OsString,
+    pub clock: Clock<Monotonic>,
     pub display_handle: DisplayHandle,
     pub event_loop: LoopHandle<'static, CalloopData>,
 
@@ -33,20 +125,64 @@ pub struct Tsuki {
     pub compositor_state: CompositorState,
     pub xdg_shell_state: XdgShellState,
     pub shm_state: ShmState,
+    pub dmabuf_state: DmabufState,
     pub output_manager_state: OutputManagerState,
     pub seat_state: SeatState<Tsuki>,
     pub data_device_state: DataDeviceState,
+    pub primary_selection_state: PrimarySelectionState,
+    pub wlr_data_control_state: wlr_data_control::DataControlState,
+    pub ext_data_control_state: ext_data_control::DataControlState,
+    pub output_management_state: OutputManagementState,
+    pub screencopy_state: ScreencopyState,
+    pub image_copy_capture_state: ImageCopyCaptureState,
+    pub capture_state: CaptureState,
+    pub foreign_toplevel_list_state: ForeignToplevelListState,
+    /// Handles announced over ext-foreign-toplevel-list, by window.
+    pub foreign_toplevels: HashMap<Window, ForeignToplevelHandle>,
+    pub foreign_toplevel_manager_state: ForeignToplevelManagerState,
+    pub session_lock_state: SessionLockManagerState,
+    pub idle_notifier_state: IdleNotifierState<Tsuki>,
+    pub idle_timers: IdleTimers,
+    pub idle_inhibit_manager_state: IdleInhibitManagerState,
+    pub output_power_state: OutputPowerState,
+    pub fractional_scale_manager_state: FractionalScaleManagerState,
+    pub viewporter_state: ViewporterState,
+    pub presentation_state: PresentationState,
+    pub tablet_manager_state: TabletManagerState,
+    pub cursor_shape_manager_state: CursorShapeManagerState,
     pub popups: PopupManager,
+    pub popup_grab: Option<PopupGrab<Tsuki>>,
+    #[cfg(feature = "xwayland")]
+    pub xwayland_shell_state: XWaylandShellState,
+    #[cfg(feature = "xwayland")]
+    pub xwm: Option<X11Wm>,
+    /// The display reserved for Xwayland, until an X client connects and it starts.
+    #[cfg(feature = "xwayland")]
+    pub x11_sockets: Option<X11Sockets>,
     pub backend_data: Rc<RefCell<dyn Backend>>,
 
     pub seat: Seat<Self>,
-    pub output: Option<Output>,
-    pub redraw_queued: bool,
-    pub waiting_for_vblank: bool
+    pub config: Config,
+    pub output_state: HashMap<Output, OutputState>,
+    pub cursor_manager: CursorManager,
+    pub dnd_icon: Option<DndIcon>,
+    pub clipboard_cache: ClipboardCache,
+    pub region_selection: Option<RegionSelection>,
+    /// Minimized windows are out of the space until they're activated again.
+    pub minimized_windows: Vec<Window>,
+    pub lock_state: LockState,
+    /// Surfaces with an idle inhibitor, whether they are visible or not.
+    pub idle_inhibitors: HashSet<WlSurface>,
+    /// Keys whose press started an action, clients don't get their release either.
+    pub suppressed_keys: HashSet<Keycode>,
+
+    // Render this long before the estimated vblank instead of right after the previous one,
+    // so that the frame contains the most recent client updates.
+    pub render_delay: Option<Duration>,
 }
 
 impl Tsuki {
-    pub fn new(event_loop: LoopHandle<'static, CalloopData>, loop_signal: LoopSignal, display: Display<Self>, backend: Rc<RefCell<dyn Backend>>) -> Self {
+    pub fn new(event_loop: LoopHandle<'static, CalloopData>, loop_signal: LoopSignal, display: Display<Self>, backend: Rc<RefCell<dyn Backend>>, config: Config) -> Self {
         let start_time = std::time::Instant::now();
 
         let dh = display.handle();
@@ -57,7 +193,30 @@ impl Tsuki {
         let output_manager_state = OutputManagerState::new_with_xdg_output::<Self>(&dh);
         let mut seat_state = SeatState::new();
         let data_device_state = DataDeviceState::new::<Self>(&dh);
+        let primary_selection_state = PrimarySelectionState::new::<Self>(&dh);
+        // Clipboard managers need to follow both selections without having keyboard focus.
+        let wlr_data_control_state =
+            wlr_data_control::DataControlState::new::<Self, _>(&dh, Some(&primary_selection_state), |_| true);
+        let ext_data_control_state =
+            ext_data_control::DataControlState::new::<Self, _>(&dh, Some(&primary_selection_state), |_| true);
+        let output_management_state = OutputManagementState::new::<Self>(&dh);
+        let screencopy_state = ScreencopyState::new::<Self>(&dh);
+        let image_copy_capture_state = ImageCopyCaptureState::new::<Self>(&dh);
+        let foreign_toplevel_list_state = ForeignToplevelListState::new::<Self>(&dh);
+        let foreign_toplevel_manager_state = ForeignToplevelManagerState::new::<Self>(&dh);
+        let session_lock_state = SessionLockManagerState::new::<Self, _>(&dh, |_| true);
+        let (idle_timers, idle_notifier_state) = IdleTimers::new(&dh);
+        let idle_inhibit_manager_state = IdleInhibitManagerState::new::<Self>(&dh);
+        let output_power_state = OutputPowerState::new::<Self>(&dh);
+        let fractional_scale_manager_state = FractionalScaleManagerState::new::<Self>(&dh);
+        let viewporter_state = ViewporterState::new::<Self>(&dh);
+        let clock = Clock::<Monotonic>::new();
+        let presentation_state = PresentationState::new::<Self>(&dh, clock.id() as u32);
+        let tablet_manager_state = TabletManagerState::new::<Self>(&dh);
+        let cursor_shape_manager_state = CursorShapeManagerState::new::<Self>(&dh);
         let popups = PopupManager::default();
+        #[cfg(feature = "xwayland")]
+        let xwayland_shell_state = XWaylandShellState::new::<Self>(&dh);
 
         // A seat is a group of keyboards, pointer and touch devices.
         // A seat typically has a pointer and maintains a keyboard focus and a pointer focus.
@@ -77,36 +236,75 @@ impl Tsuki {
         // Outputs become views of a part of the Space and can be rendered via Space::render_output.
         let space = Space::default();
 
-        let socket_name = Self::init_wayland_listener(display, event_loop.clone());
-        
+        Self::insert_display_source(display, &event_loop);
+        Self::insert_frame_callback_timer(&event_loop);
+
+        let render_delay = std::env::var("TSUKI_RENDER_DELAY_MS")
+            .ok()
+            .and_then(|ms| ms.parse().ok())
+            .map(Duration::from_millis);
 
         Self {
             start_time,
+            clock,
             display_handle: dh,
             event_loop,
 
             space,
             loop_signal,
-            socket_name,
             backend_data: backend,
             compositor_state,
             xdg_shell_state,
             shm_state,
+            dmabuf_state: DmabufState::new(),
             output_manager_state,
             seat_state,
             data_device_state,
+            primary_selection_state,
+            wlr_data_control_state,
+            ext_data_control_state,
+            output_management_state,
+            screencopy_state,
+            image_copy_capture_state,
+            capture_state: CaptureState::default(),
+            foreign_toplevel_list_state,
+            foreign_toplevels: HashMap::new(),
+            foreign_toplevel_manager_state,
+            session_lock_state,
+            idle_notifier_state,
+            idle_timers,
+            idle_inhibit_manager_state,
+            output_power_state,
+            fractional_scale_manager_state,
+            viewporter_state,
+            presentation_state,
+            tablet_manager_state,
+            cursor_shape_manager_state,
             popups,
+            popup_grab: None,
+            #[cfg(feature = "xwayland")]
+            xwayland_shell_state,
+            #[cfg(feature = "xwayland")]
+            xwm: None,
+            #[cfg(feature = "xwayland")]
+            x11_sockets: None,
             seat,
-            output: None,
-            redraw_queued: false,
-            waiting_for_vblank: false
+            config,
+            output_state: HashMap::new(),
+            cursor_manager: CursorManager::new(),
+            dnd_icon: None,
+            clipboard_cache: ClipboardCache::default(),
+            region_selection: None,
+            minimized_windows: Vec::new(),
+            lock_state: LockState::Unlocked,
+            idle_inhibitors: HashSet::new(),
+            suppressed_keys: HashSet::new(),
+            render_delay,
         }
     }
 
-    fn init_wayland_listener(
-        display: Display<Tsuki>,
-        loop_handle: LoopHandle<CalloopData>,
-    ) -> OsString {
+    /// Opens the socket clients connect to and returns its name.
+    pub fn add_listening_socket(&self) -> OsString {
         // Creates a new listening socket, automatically choosing the next available `wayland` socket name.
         let listening_socket = ListeningSocketSource::with_name("wayland-1").unwrap();
         println!("{:?}", listening_socket.socket_name());
@@ -115,7 +313,7 @@ impl Tsuki {
         // Clients will connect to this socket.
         let socket_name = listening_socket.socket_name().to_os_string();
 
-        loop_handle
+        self.event_loop
             .insert_source(listening_socket, move |client_stream, _, state| {
                 // Inside the callback, you should insert the client into the display.
                 //
@@ -127,6 +325,10 @@ impl Tsuki {
             })
             .expect("Failed to init the wayland event source.");
 
+        socket_name
+    }
+
+    fn insert_display_source(display: Display<Tsuki>, loop_handle: &LoopHandle<CalloopData>) {
         // You also need to add the display itself to the event loop, so that client events will be processed by wayland-server.
         loop_handle
             .insert_source(
@@ -139,92 +341,743 @@ impl Tsuki {
                 },
             )
             .unwrap();
+    }
 
-        socket_name
+    /// Outputs that are idle or off send no frame callbacks, this timer makes sure the clients
+    /// of surfaces waiting on one still get it every `FRAME_CALLBACK_THROTTLE`.
+    fn insert_frame_callback_timer(loop_handle: &LoopHandle<CalloopData>) {
+        loop_handle
+            .insert_source(Timer::from_duration(FRAME_CALLBACK_THROTTLE), |_, _, data| {
+                data.tsuki.send_throttled_frame_callbacks();
+                TimeoutAction::ToDuration(FRAME_CALLBACK_THROTTLE)
+            })
+            .unwrap();
+    }
+
+    /// Maps a new output into the space, to the right of the existing ones unless a position
+    /// is given.
+    pub fn add_output(
+        &mut self,
+        output: Output,
+        refresh_interval: Option<Duration>,
+        position: Option<Point<i32, Logical>>,
+    ) {
+        let position = position.unwrap_or_else(|| {
+            let x = self
+                .space
+                .outputs()
+                .filter_map(|output| self.space.output_geometry(output))
+                .map(|geo| geo.loc.x + geo.size.w)
+                .max()
+                .unwrap_or(0);
+            (x, 0).into()
+        });
+
+        output.change_current_state(None, None, None, Some(position));
+        self.space.map_output(&output, position);
+        self.output_state.insert(
+            output.clone(),
+            OutputState {
+                redraw_state: RedrawState::Queued,
+                frame_clock: FrameClock::new(refresh_interval),
+                presentation_feedback: None,
+                lock_surface: None,
+                lock_background: SolidColorBuffer::new((0, 0), LOCK_BACKGROUND_COLOR),
+                lock_rendered: false,
+                lock_frame_pending: false,
+                powered: true,
+            },
+        );
+        self.update_foreign_toplevels();
+    }
+
+    /// Checks a configuration from wlr-output-management before it is tested or applied.
+    pub fn check_output_configuration(&self, configs: &[OutputConfig]) -> anyhow::Result<()> {
+        if configs.iter().all(|config| !config.enabled) {
+            return Err(anyhow::anyhow!("refusing to disable every output"));
+        }
+        self.backend_data.borrow().check_output_config(configs)
+    }
+
+    /// Applies the logical part of an output config: scale, transform and position. The mode
+    /// is up to the backend.
+    pub fn reconfigure_output(&mut self, output: &Output, config: &OutputConfig) {
+        output.change_current_state(None, config.transform(), config.scale(), None);
+
+        if let Some(position) = config.position() {
+            output.change_current_state(None, None, None, Some(position));
+            self.space.map_output(output, position);
+            self.update_foreign_toplevels();
+        }
+
+        self.configure_lock_surface(output);
+        self.queue_redraw(output);
+    }
+
+    pub fn set_refresh_interval(&mut self, output: &Output, refresh_interval: Option<Duration>) {
+        if let Some(state) = self.output_state.get_mut(output) {
+            state.frame_clock = FrameClock::new(refresh_interval);
+        }
+    }
+
+    pub fn remove_output(&mut self, output: &Output) {
+        if let Some(mut state) = self.output_state.remove(output) {
+            state.cancel_frame(&self.event_loop);
+        }
+
+        self.output_power_state.output_removed(output);
+        self.space.unmap_output(output);
+        self.update_foreign_toplevels();
+    }
+
+    /// Turns the output off or back on. While it's off nothing is rendered to it and the
+    /// backend blanks it, it picks up with a full frame once it's back on.
+    pub fn set_output_power(&mut self, output: &Output, on: bool) {
+        let Some(state) = self.output_state.get_mut(output) else {
+            return;
+        };
+        if state.powered == on {
+            return;
+        }
+        state.powered = on;
+
+        if !on {
+            state.cancel_frame(&self.event_loop);
+        }
+
+        log::info!("turning {} {}", output.name(), if on { "on" } else { "off" });
+        self.backend_data.clone().borrow_mut().set_output_power(output, on);
+        self.output_power_state.output_power_changed(output, on);
+
+        if on {
+            self.queue_redraw(output);
+        }
+    }
+
+    /// Turns every output off, or back on when they all are off already.
+    pub fn toggle_outputs_power(&mut self) {
+        let on = self.output_state.values().all(|state| !state.powered);
+        let outputs: Vec<_> = self.space.outputs().cloned().collect();
+        for output in outputs {
+            self.set_output_power(&output, on);
+        }
+    }
+
+    /// Creates the linux-dmabuf global for the formats the renderer can import. The default
+    /// feedback points clients at the render node, without one (e.g. software rendering)
+    /// only the version 3 global without feedback can be offered.
+    pub fn create_dmabuf_global(&mut self, renderer: &GlesRenderer) -> DmabufGlobal {
+        let formats = renderer.dmabuf_formats();
+        let render_node = EGLDevice::device_for_display(renderer.egl_context().display())
+            .and_then(|device| device.try_get_render_node());
+
+        let feedback = match render_node {
+            Ok(Some(node)) => {
+                // Captures into dmabufs are rendered with the same renderer.
+                let capture_formats = renderer
+                    .egl_context()
+                    .dmabuf_render_formats()
+                    .iter()
+                    .filter(|format| matches!(format.code, Fourcc::Xrgb8888 | Fourcc::Argb8888))
+                    .copied()
+                    .collect();
+                self.capture_state.dmabuf = Some(DmabufConstraints {
+                    device: node.dev_id(),
+                    formats: capture_formats,
+                });
+
+                match DmabufFeedbackBuilder::new(node.dev_id(), formats.clone()).build() {
+                    Ok(feedback) => Some(feedback),
+                    Err(err) => {
+                        log::warn!("error building dmabuf feedback: {err}");
+                        None
+                    }
+                }
+            }
+            Ok(None) => {
+                log::info!("renderer has no render node, dmabuf feedback is disabled");
+                None
+            }
+            Err(err) => {
+                log::warn!("error getting the render node: {err}");
+                None
+            }
+        };
+
+        match feedback {
+            Some(feedback) => self
+                .dmabuf_state
+                .create_global_with_default_feedback::<Self>(&self.display_handle, &feedback),
+            None => self.dmabuf_state.create_global::<Self>(&self.display_handle, formats),
+        }
+    }
+
+    pub fn queue_redraw_all(&mut self) {
+        let outputs: Vec<_> = self.output_state.keys().cloned().collect();
+        for output in outputs {
+            self.queue_redraw(&output);
+        }
+    }
+
+    pub fn queue_redraw(&mut self, output: &Output) {
+        let Some(state) = self.output_state.get_mut(output).filter(|state| state.powered) else {
+            return;
+        };
+
+        match &mut state.redraw_state {
+            RedrawState::Idle => self.schedule_render(output),
+            RedrawState::WaitingForVBlank { redraw_needed } => *redraw_needed = true,
+            RedrawState::WaitingForEstimatedVBlank(token) => {
+                state.redraw_state = RedrawState::WaitingForEstimatedVBlankAndQueued(*token);
+            }
+            RedrawState::Queued
+            | RedrawState::Delayed(_)
+            | RedrawState::WaitingForEstimatedVBlankAndQueued(_) => (),
+        }
+    }
+
+    /// Queues redraws for the outputs the cursor was or is now on, pointer motion alone doesn't
+    /// need to repaint the others.
+    pub fn queue_redraw_cursor(&mut self, previous: Point<f64, Logical>, current: Point<f64, Logical>) {
+        let outputs: Vec<_> = self.space.outputs().cloned().collect();
+        for output in outputs {
+            let Some(output_geo) = self.space.output_geometry(&output) else {
+                continue;
+            };
+            let shown = [previous, current].into_iter().any(|location| {
+                self.cursor_geometry(&output, location)
+                    .is_some_and(|geo| output_geo.to_f64().overlaps(geo))
+            });
+            if shown {
+                self.queue_redraw(&output);
+            }
+        }
+    }
+
+    /// Where the cursor is drawn on `output` with the pointer at `location`, the same way
+    /// `pointer_render_elements` places it.
+    fn cursor_geometry(&mut self, output: &Output, location: Point<f64, Logical>) -> Option<Rectangle<f64, Logical>> {
+        match self.cursor_manager.render_cursor(output.current_scale().integer_scale()) {
+            RenderCursor::Hidden => None,
+            RenderCursor::Surface { surface, hotspot } => {
+                let bbox = bbox_from_surface_tree(&surface, (0, 0)).to_f64();
+                Some(Rectangle::new(location - hotspot.to_f64() + bbox.loc, bbox.size))
+            }
+            RenderCursor::Named { cursor, scale } => {
+                let geometry = cursor
+                    .geometry(self.start_time.elapsed().as_millis() as u32)
+                    .to_f64()
+                    .to_logical(scale as f64);
+                Some(Rectangle::new(location + geometry.loc, geometry.size))
+            }
+        }
+    }
+
+    /// Moves the output into `Queued`, possibly after waiting out the render delay.
+    fn schedule_render(&mut self, output: &Output) {
+        let now: Duration = self.clock.now().into();
+        let state = self.output_state.get_mut(output).unwrap();
+
+        let target = self.render_delay.and_then(|delay| {
+            state.frame_clock.refresh_interval()?;
+            let target = state.frame_clock.next_presentation_time(now).checked_sub(delay)?;
+            (target > now).then_some(target)
+        });
+
+        let Some(target) = target else {
+            state.redraw_state = RedrawState::Queued;
+            return;
+        };
+
+        let output = output.clone();
+        let token = self
+            .event_loop
+            .insert_source(Timer::from_duration(target - now), move |_, _, data| {
+                if let Some(state) = data.tsuki.output_state.get_mut(&output) {
+                    state.redraw_state = RedrawState::Queued;
+                }
+                TimeoutAction::Drop
+            })
+            .unwrap();
+        state.redraw_state = RedrawState::Delayed(token);
+    }
+
+    pub fn redraw_queued_outputs(&mut self, backend: &mut dyn Backend) {
+        while let Some(output) = self
+            .output_state
+            .iter()
+            .find(|(_, state)| matches!(state.redraw_state, RedrawState::Queued))
+            .map(|(output, _)| output.clone())
+        {
+            self.redraw(backend, &output);
+        }
     }
 
-    pub fn queue_redraw(&mut self) {
-        if self.redraw_queued || self.waiting_for_vblank {
+    pub fn redraw(&mut self, backend: &mut dyn Backend, output: &Output) {
+        let state = self.output_state.get_mut(output).unwrap();
+        assert!(matches!(state.redraw_state, RedrawState::Queued));
+
+        let Some(renderer) = backend.renderer() else {
+            state.redraw_state = RedrawState::Idle;
             return;
+        };
+
+        // Windows committed since the last frame need to know their outputs to be drawn.
+        self.space.refresh();
+        let mut elements = self.pointer_render_elements(renderer, output);
+        elements.extend(self.region_selection_elements(output));
+        elements.extend(self.output_elements(renderer, output));
+
+        let res = backend.render(self, output, &elements);
+
+        let locking = matches!(self.lock_state, LockState::Locking(_));
+        let no_damage = matches!(res, RenderResult::NoDamage);
+        let state = self.output_state.get_mut(output).unwrap();
+        match res {
+            RenderResult::Submitted(states) => {
+                let redraw_needed = self
+                    .cursor_manager
+                    .is_animated(output.current_scale().integer_scale());
+                state.redraw_state = RedrawState::WaitingForVBlank { redraw_needed };
+                // The lock is only confirmed once this frame is actually on screen.
+                state.lock_frame_pending = locking;
+
+                self.update_primary_scanout_outputs(output, &states);
+                let feedback = self.take_presentation_feedback(output, &states);
+                self.output_state.get_mut(output).unwrap().presentation_feedback = Some(feedback);
+                self.send_frame_callbacks(output);
+                self.capture_state.check_damage();
+            }
+            RenderResult::NoDamage => {
+                // Nothing changed on screen, but clients still expect their frame callbacks
+                // roughly once per refresh cycle. Never right away, or clients committing
+                // without damage every frame would spin the event loop.
+                let now: Duration = self.clock.now().into();
+                let timer_output = output.clone();
+                let token = self
+                    .event_loop
+                    .insert_source(
+                        Timer::from_duration(state.frame_clock.time_to_next_presentation(now)),
+                        move |_, _, data| {
+                            data.tsuki.on_estimated_vblank_timer(&timer_output);
+                            TimeoutAction::Drop
+                        },
+                    )
+                    .unwrap();
+                state.redraw_state = RedrawState::WaitingForEstimatedVBlank(token);
+            }
+            RenderResult::Skipped => {
+                state.redraw_state = RedrawState::Idle;
+            }
+        }
+
+        // Content committed since the last frame didn't change what's on screen, it won't be
+        // presented.
+        if no_damage {
+            self.take_presentation_feedback(output, &RenderElementStates::default())
+                .discarded();
         }
+        self.update_surface_scales();
+    }
 
-        self.redraw_queued = true;
+    /// Tells every surface the scale and transform of the output its window is mostly on, so
+    /// that clients render their buffers at the right resolution.
+    fn update_surface_scales(&self) {
+        for window in self.space.elements() {
+            let Some(output) = self.output_for_window(window) else {
+                continue;
+            };
 
+            let scale = output.current_scale();
+            let transform = output.current_transform();
+            window.with_surfaces(|surface, states| {
+                send_surface_state(surface, states, scale.integer_scale(), transform);
+                with_fractional_scale(states, |fractional_scale| {
+                    fractional_scale.set_preferred_scale(scale.fractional_scale());
+                });
+            });
+        }
+    }
+
+    /// The output covering the largest part of the window.
+    pub fn output_for_window(&self, window: &Window) -> Option<Output> {
+        let window_geo = self.space.element_geometry(window)?;
+        self.space
+            .outputs_for_element(window)
+            .into_iter()
+            .max_by_key(|output| {
+                self.space
+                    .output_geometry(output)
+                    .and_then(|geo| geo.intersection(window_geo))
+                    .map_or(0, |overlap| overlap.size.w * overlap.size.h)
+            })
+    }
+
+    /// Remembers for every surface the output it is most visible on, frame callbacks and
+    /// presentation feedback only go out with the frames of that output.
+    fn update_primary_scanout_outputs(&self, output: &Output, states: &RenderElementStates) {
+        for window in self.space.elements() {
+            window.with_surfaces(|surface, surface_data| {
+                update_surface_primary_scanout_output(
+                    surface,
+                    output,
+                    surface_data,
+                    states,
+                    default_primary_scanout_output_compare,
+                );
+            });
+        }
+    }
+
+    fn take_presentation_feedback(&self, output: &Output, states: &RenderElementStates) -> OutputPresentationFeedback {
+        let mut feedback = OutputPresentationFeedback::new(output);
+        for window in self.space.elements() {
+            if !self.space.outputs_for_element(window).contains(output) {
+                continue;
+            }
+
+            window.take_presentation_feedback(&mut feedback, surface_primary_scanout_output, |surface, _| {
+                surface_presentation_feedback_flags_from_states(surface, states)
+            });
+        }
+        feedback
+    }
+
+    /// Should be called by the backend once a submitted frame was presented. `flags` say how
+    /// precise `presentation_time` is.
+    pub fn on_vblank(
+        &mut self,
+        output: &Output,
+        presentation_time: Duration,
+        sequence: u64,
+        flags: wp_presentation_feedback::Kind,
+    ) {
+        let Some(state) = self.output_state.get_mut(output) else {
+            return;
+        };
+
+        state.frame_clock.presented(presentation_time);
+
+        if let Some(mut feedback) = state.presentation_feedback.take() {
+            let refresh = state
+                .frame_clock
+                .refresh_interval()
+                .map_or(Refresh::Unknown, Refresh::Fixed);
+            feedback.presented::<_, Monotonic>(presentation_time, refresh, sequence, flags);
+        }
+
+        let lock_frame = mem::take(&mut state.lock_frame_pending);
+        match mem::replace(&mut state.redraw_state, RedrawState::Idle) {
+            RedrawState::WaitingForVBlank { redraw_needed } => {
+                if redraw_needed {
+                    self.schedule_render(output);
+                }
+            }
+            other => {
+                log::warn!("unexpected vblank for {}", output.name());
+                state.redraw_state = other;
+            }
+        }
+
+        if lock_frame {
+            self.lock_frame_rendered(output);
+        }
+    }
+
+    /// For backends without vblank events: pretends the frame is presented one refresh cycle
+    /// after it was submitted. The presentation time is only an estimate, so no flags are set.
+    pub fn simulate_vblank(&mut self, output: &Output) {
+        let refresh_interval = self
+            .output_state
+            .get(output)
+            .and_then(|state| state.frame_clock.refresh_interval())
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+
+        let output = output.clone();
         self.event_loop
-            .insert_source(Timer::immediate(), |_, _, data| {
-                let binding = data.backend.clone();
-                let mut backend: RefMut<dyn Backend> = binding.borrow_mut();
-                data.tsuki.redraw(&mut *backend);
-                smithay::reexports::calloop::timer::TimeoutAction::Drop
+            .insert_source(Timer::from_duration(refresh_interval), move |_, _, data| {
+                let now = data.tsuki.clock.now().into();
+                data.tsuki.on_vblank(&output, now, 0, wp_presentation_feedback::Kind::empty());
+                TimeoutAction::Drop
             })
             .unwrap();
     }
 
-    pub fn redraw(&mut self, backend: &mut dyn Backend) {
-        assert!(self.redraw_queued);
-        assert!(!self.waiting_for_vblank);
-        self.redraw_queued = false;
-        
-        if let Some(renderer) = backend.renderer() {
-            let elements = space_render_elements(
-                renderer, 
-                [&self.space], 
-                self.output.as_ref().unwrap(), 
-            1.0
-            ).unwrap();
-
-            let mut elements: Vec<_> = elements
-                .into_iter()
-                .map(OutputRenderElements::from)
-                .collect();
-
-            elements.insert(
-                0, 
-                OutputRenderElements::Pointer(SolidColorRenderElement::new(
-                    smithay::backend::renderer::element::Id::new(),
-                    smithay::utils::Rectangle {
-                        loc: self
-                            .seat
-                            .get_pointer()
-                            .unwrap()
-                            .current_location()
-                            .to_physical_precise_round(1.),
-                        size: (16, 16).into()
-                    }, 
-                    CommitCounter::default(), 
-                    [1., 0.5, 0., 1.],
-                    Kind::Cursor
-                ))
+    fn on_estimated_vblank_timer(&mut self, output: &Output) {
+        let Some(state) = self.output_state.get_mut(output) else {
+            return;
+        };
+
+        match mem::replace(&mut state.redraw_state, RedrawState::Idle) {
+            RedrawState::WaitingForEstimatedVBlank(_) => (),
+            RedrawState::WaitingForEstimatedVBlankAndQueued(_) => self.schedule_render(output),
+            other => {
+                state.redraw_state = other;
+                return;
+            }
+        }
+
+        self.send_frame_callbacks(output);
+    }
+
+    /// Sends dmabuf feedback to the surfaces of the windows on the output, `select` picks it
+    /// per surface. Smithay only sends it again when it changed, so this is cheap to call after
+    /// every frame.
+    pub fn send_dmabuf_feedback<'a>(&self, output: &Output, select: impl Fn(&WlSurface) -> &'a DmabufFeedback) {
+        for window in self.space.elements() {
+            if self.output_for_window(window).as_ref() != Some(output) {
+                continue;
+            }
+
+            window.send_dmabuf_feedback(output, |_, _| Some(output.clone()), |surface, _| select(surface));
+        }
+    }
+
+    /// Frame callbacks for the surfaces visible on `output`, the others get theirs from
+    /// [`Self::send_throttled_frame_callbacks`].
+    pub fn send_frame_callbacks(&self, output: &Output) {
+        for window in self.space.elements() {
+            window.send_frame(
+                output,
+                self.start_time.elapsed(),
+                Some(FRAME_CALLBACK_THROTTLE),
+                surface_primary_scanout_output,
+            );
+        }
+
+        let cursor_surface = match self.cursor_manager.image() {
+            CursorImageStatus::Surface(surface) => Some(surface),
+            _ => None,
+        };
+        let dnd_surface = self.dnd_icon.as_ref().map(|icon| &icon.surface);
+        let lock_surface = self.lock_surface(output);
+        for surface in cursor_surface.into_iter().chain(dnd_surface).chain(lock_surface) {
+            send_frames_surface_tree(
+                surface,
+                output,
+                self.start_time.elapsed(),
+                Some(Duration::ZERO),
+                |_, _| Some(output.clone()),
             );
-    
-            backend.render(self, &elements);
-    
-            let output = self.output.as_ref().unwrap();
-            self.space.elements().for_each(|window| {
-                window.send_frame(
-                    output, 
-                    self.start_time.elapsed(),
-                    Some(Duration::ZERO),
-                    |_, _| Some(output.clone()));
-            });
-    
-            self.space.refresh();
         }
     }
 
+    /// Frame callbacks for the windows that didn't get one for `FRAME_CALLBACK_THROTTLE`:
+    /// hidden and minimized ones, and those on outputs that are off or have nothing to draw.
+    fn send_throttled_frame_callbacks(&self) {
+        // The output only matters for surfaces primarily shown on it, no surface is here.
+        let Some(output) = self.space.outputs().next() else {
+            return;
+        };
+        for window in self.toplevel_windows() {
+            window.send_frame(
+                output,
+                self.start_time.elapsed(),
+                Some(FRAME_CALLBACK_THROTTLE),
+                |_, _| None,
+            );
+        }
+    }
+
+    /// Everything on the output below the cursor: the lock screen while the session is locked,
+    /// the windows otherwise.
+    pub fn output_elements(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        output: &Output,
+    ) -> Vec<OutputRenderElements<GlesRenderer>> {
+        if self.is_locked() {
+            return self.lock_render_elements(renderer, output);
+        }
+
+        let mut elements = self.dnd_icon_render_elements(renderer, output);
+        elements.extend(self.window_render_elements(renderer, output));
+        elements
+    }
+
+    /// Window contents front to back, with the popups of every window above all toplevels so
+    /// that menus never end up below a neighbouring window.
+    pub fn window_render_elements(
+        &self,
+        renderer: &mut GlesRenderer,
+        output: &Output,
+    ) -> Vec<OutputRenderElements<GlesRenderer>> {
+        let output_geo = self.space.output_geometry(output).unwrap();
+        let scale = output.current_scale().fractional_scale();
+
+        let mut popups = Vec::new();
+        let mut toplevels = Vec::new();
+        for window in self.space.elements().rev() {
+            if !self.space.outputs_for_element(window).contains(output) {
+                continue;
+            }
+
+            let Some(location) = self.space.element_location(window) else {
+                continue;
+            };
+            let render_location = location - output_geo.loc - window.geometry().loc;
+            let (window_popups, window_elements) = self.window_elements(renderer, window, render_location, scale);
+            popups.extend(window_popups);
+            toplevels.extend(window_elements);
+        }
+
+        popups.extend(toplevels);
+        popups
+    }
+
+    /// The popups and the surfaces of one window drawn at `render_location`, front to back.
+    pub fn window_elements(
+        &self,
+        renderer: &mut GlesRenderer,
+        window: &Window,
+        render_location: Point<i32, Logical>,
+        scale: f64,
+    ) -> (Vec<OutputRenderElements<GlesRenderer>>, Vec<OutputRenderElements<GlesRenderer>>) {
+        // X11 windows can be mapped before their surface is associated.
+        let Some(surface) = window.wl_surface() else {
+            return (Vec::new(), Vec::new());
+        };
+
+        let mut popups = Vec::new();
+        for (popup, offset) in PopupManager::popups_for_surface(&surface) {
+            let popup_location = render_location + window.geometry().loc + offset - popup.geometry().loc;
+            popups.extend(render_elements_from_surface_tree(
+                renderer,
+                popup.wl_surface(),
+                popup_location.to_physical_precise_round(scale),
+                scale,
+                1.0,
+                Kind::Unspecified,
+            ));
+        }
+
+        let elements = render_elements_from_surface_tree(
+            renderer,
+            &surface,
+            render_location.to_physical_precise_round(scale),
+            scale,
+            1.0,
+            Kind::Unspecified,
+        );
+        (popups, elements)
+    }
+
+    /// The cursor image at the pointer location, relative to `output`.
+    pub fn pointer_render_elements(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        output: &Output,
+    ) -> Vec<OutputRenderElements<GlesRenderer>> {
+        let output_geo = self.space.output_geometry(output).unwrap();
+        let output_scale = output.current_scale();
+        let pointer_location = self.seat.get_pointer().unwrap().current_location() - output_geo.loc.to_f64();
+
+        match self.cursor_manager.render_cursor(output_scale.integer_scale()) {
+            RenderCursor::Hidden => Vec::new(),
+            RenderCursor::Surface { surface, hotspot } => {
+                let location = (pointer_location - hotspot.to_f64())
+                    .to_physical_precise_round(output_scale.fractional_scale());
+                render_elements_from_surface_tree(
+                    renderer,
+                    &surface,
+                    location,
+                    output_scale.fractional_scale(),
+                    1.0,
+                    Kind::Cursor,
+                )
+            }
+            RenderCursor::Named { cursor, scale } => {
+                let (buffer, hotspot) = cursor.frame(self.start_time.elapsed().as_millis() as u32);
+                let hotspot = hotspot.to_f64().to_logical(scale as f64);
+                let location: Point<i32, _> = (pointer_location - hotspot)
+                    .to_physical_precise_round(output_scale.fractional_scale());
+
+                match MemoryRenderBufferRenderElement::from_buffer(
+                    renderer,
+                    location.to_f64(),
+                    buffer,
+                    None,
+                    None,
+                    None,
+                    Kind::Cursor,
+                ) {
+                    Ok(element) => vec![OutputRenderElements::NamedCursor(element)],
+                    Err(err) => {
+                        log::warn!("error importing cursor: {err}");
+                        Vec::new()
+                    }
+                }
+            }
+        }
+    }
+
+    /// The drag-and-drop icon at the pointer location, relative to `output`.
+    pub fn dnd_icon_render_elements(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        output: &Output,
+    ) -> Vec<OutputRenderElements<GlesRenderer>> {
+        // The icon goes away with its surface even if the drag never ended properly.
+        if self.dnd_icon.as_ref().is_some_and(|icon| !icon.surface.is_alive()) {
+            self.dnd_icon = None;
+        }
+        let Some(icon) = &self.dnd_icon else {
+            return Vec::new();
+        };
+
+        let output_geo = self.space.output_geometry(output).unwrap();
+        let scale = output.current_scale().fractional_scale();
+        let location = self.seat.get_pointer().unwrap().current_location() - output_geo.loc.to_f64()
+            + icon.offset.to_f64();
+
+        render_elements_from_surface_tree(
+            renderer,
+            &icon.surface,
+            location.to_physical_precise_round(scale),
+            scale,
+            1.0,
+            Kind::Unspecified,
+        )
+    }
+
     pub fn surface_under(&self, pos: Point<f64, Logical>) -> Option<(WlSurface, Point<f64, Logical>)> {
-        self.space.element_under(pos).and_then(|(window, location)| {
-            window
-                .surface_under(pos - location.to_f64(), WindowSurfaceType::ALL)
-                .map(|(s, p)| (s, (p + location).to_f64()))
+        if self.is_locked() {
+            return self.lock_surface_under(pos);
+        }
+        self.window_surface_under(pos).map(|(_, surface, location)| (surface, location))
+    }
+
+    pub fn window_under(&self, pos: Point<f64, Logical>) -> Option<Window> {
+        if self.is_locked() {
+            return None;
+        }
+        self.window_surface_under(pos).map(|(window, ..)| window)
+    }
+
+    /// Popups are drawn above every toplevel, so they are hit first, whichever window they
+    /// belong to.
+    fn window_surface_under(&self, pos: Point<f64, Logical>) -> Option<(Window, WlSurface, Point<f64, Logical>)> {
+        let surface_types = [
+            WindowSurfaceType::POPUP | WindowSurfaceType::SUBSURFACE,
+            WindowSurfaceType::TOPLEVEL | WindowSurfaceType::SUBSURFACE,
+        ];
+
+        surface_types.into_iter().find_map(|surface_type| {
+            self.space.elements().rev().find_map(|window| {
+                let render_location = self.space.element_location(window)? - window.geometry().loc;
+                window
+                    .surface_under(pos - render_location.to_f64(), surface_type)
+                    .map(|(surface, location)| (window.clone(), surface, (location + render_location).to_f64()))
+            })
         })
     }
 }
 
 render_elements! {
-    pub OutputRenderElements<R, E> where R: ImportAll;
-    Space=SpaceRenderElements<R, E>,
-    Pointer = SolidColorRenderElement,
+    pub OutputRenderElements<R> where R: ImportAll + ImportMem;
+    Surface = WaylandSurfaceRenderElement<R>,
+    NamedCursor = MemoryRenderBufferRenderElement<R>,
+    Solid = SolidColorRenderElement,
 }
 #[derive(Default)]
 pub struct ClientState {
@@ -0,0 +1,77 @@
+use wayland_client::protocol::{wl_output::WlOutput, wl_shm};
+use wayland_protocols_wlr::screencopy::v1::client::{
+    zwlr_screencopy_frame_v1::{self, Flags},
+    zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+};
+
+use super::{client::Events, fixture::fixture_with_window};
+
+#[test]
+fn screencopy_matches_the_framebuffer() {
+    let (mut f, id, _window, _buffer) = fixture_with_window();
+    let output = f.outputs()[0].clone();
+
+    let manager: ZwlrScreencopyManagerV1 = f.client(id).bind(3);
+    let wl_output: WlOutput = f.client(id).bind(4);
+    let events = Events::<zwlr_screencopy_frame_v1::Event>::default();
+    let frame = manager.capture_output(1, &wl_output, &f.client(id).qh, events.clone());
+    f.dispatch_until(|_| {
+        events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|event| matches!(event, zwlr_screencopy_frame_v1::Event::BufferDone))
+    });
+
+    let (format, width, height) = events
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|event| match *event {
+            zwlr_screencopy_frame_v1::Event::Buffer { format, width, height, .. } => {
+                Some((format, width as i32, height as i32))
+            }
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(format.into_result().unwrap(), wl_shm::Format::Xrgb8888);
+    let capture = f.client(id).create_shm_buffer_with_format(width, height, wl_shm::Format::Xrgb8888);
+    frame.copy(&capture.buffer);
+
+    f.dispatch_until(|_| {
+        events.lock().unwrap().iter().any(|event| {
+            matches!(
+                event,
+                zwlr_screencopy_frame_v1::Event::Ready { .. } | zwlr_screencopy_frame_v1::Event::Failed
+            )
+        })
+    });
+    let y_invert = events.lock().unwrap().iter().any(|event| match event {
+        zwlr_screencopy_frame_v1::Event::Flags { flags } => {
+            flags.into_result().unwrap().contains(Flags::YInvert)
+        }
+        _ => false,
+    });
+    assert!(events
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|event| matches!(event, zwlr_screencopy_frame_v1::Event::Ready { .. })));
+
+    // The capture is Xrgb8888 and the framebuffer Abgr8888, in memory BGRX and RGBA.
+    let mut captured: Vec<[u8; 3]> = capture
+        .pixels()
+        .chunks_exact(4)
+        .map(|pixel| [pixel[2], pixel[1], pixel[0]])
+        .collect();
+    if y_invert {
+        captured = captured.chunks_exact(width as usize).rev().flatten().copied().collect();
+    }
+    let framebuffer: Vec<[u8; 3]> = f
+        .with_headless(|headless| headless.framebuffer_pixels(&output))
+        .chunks_exact(4)
+        .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+        .collect();
+    assert!(captured == framebuffer);
+    assert_eq!(captured[(50 * width + 50) as usize], [0xff, 0x00, 0x00]);
+}
@@ -0,0 +1,451 @@
+//! A minimal Wayland client for driving the compositor from tests. Objects whose events the
+//! tests look at carry an [`Events`] list as user data.
+
+use std::{
+    fs::File,
+    io::ErrorKind,
+    os::{
+        fd::AsFd,
+        unix::{fs::FileExt, net::UnixStream},
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use smithay::reexports::rustix::fs::{memfd_create, MemfdFlags};
+use wayland_client::{
+    delegate_noop,
+    protocol::{
+        wl_buffer::WlBuffer,
+        wl_callback::{self, WlCallback},
+        wl_compositor::WlCompositor,
+        wl_display::WlDisplay,
+        wl_keyboard::WlKeyboard,
+        wl_output::{self, WlOutput},
+        wl_registry::{self, WlRegistry},
+        wl_seat::WlSeat,
+        wl_shm::{self, WlShm},
+        wl_shm_pool::WlShmPool,
+        wl_surface::WlSurface,
+    },
+    backend::WaylandError,
+    event_created_child, Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+};
+use wayland_protocols::{
+    ext::{
+        idle_notify::v1::client::{
+            ext_idle_notification_v1::ExtIdleNotificationV1, ext_idle_notifier_v1::ExtIdleNotifierV1,
+        },
+        session_lock::v1::client::{
+            ext_session_lock_manager_v1::ExtSessionLockManagerV1, ext_session_lock_v1::ExtSessionLockV1,
+        },
+    },
+    wp::linux_dmabuf::zv1::client::{
+        zwp_linux_dmabuf_feedback_v1::ZwpLinuxDmabufFeedbackV1, zwp_linux_dmabuf_v1::ZwpLinuxDmabufV1,
+    },
+    xdg::shell::client::{
+        xdg_surface::{self, XdgSurface},
+        xdg_toplevel::XdgToplevel,
+        xdg_wm_base::{self, XdgWmBase},
+    },
+};
+use wayland_protocols_wlr::{
+    data_control::v1::client::{
+        zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+        zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+        zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+        zwlr_data_control_source_v1::ZwlrDataControlSourceV1,
+    },
+    output_management::v1::client::{
+        zwlr_output_configuration_head_v1::ZwlrOutputConfigurationHeadV1,
+        zwlr_output_configuration_v1::ZwlrOutputConfigurationV1,
+        zwlr_output_head_v1::{self, ZwlrOutputHeadV1},
+        zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+        zwlr_output_mode_v1::ZwlrOutputModeV1,
+    },
+    screencopy::v1::client::{
+        zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1,
+    },
+};
+
+/// The events an object received so far.
+pub type Events<E> = Arc<Mutex<Vec<E>>>;
+
+pub struct Global {
+    pub name: u32,
+    pub interface: String,
+    pub version: u32,
+}
+
+pub struct Client {
+    pub connection: Connection,
+    pub qh: QueueHandle<State>,
+    event_queue: EventQueue<State>,
+    pub state: State,
+}
+
+#[derive(Default)]
+pub struct State {
+    pub globals: Vec<Global>,
+    registry: Option<WlRegistry>,
+}
+
+pub struct Window {
+    pub surface: WlSurface,
+    pub xdg_surface: XdgSurface,
+    pub toplevel: XdgToplevel,
+}
+
+/// A wl_buffer backed by a memfd, so that tests can look at what was copied into it.
+pub struct ShmBuffer {
+    pub buffer: WlBuffer,
+    pub width: i32,
+    pub height: i32,
+    file: File,
+}
+
+impl Client {
+    pub fn new(stream: UnixStream) -> Self {
+        let connection = Connection::from_socket(stream).unwrap();
+        let event_queue = connection.new_event_queue();
+        let qh = event_queue.handle();
+
+        let state = State {
+            registry: Some(connection.display().get_registry(&qh, ())),
+            ..Default::default()
+        };
+
+        Self {
+            connection,
+            qh,
+            event_queue,
+            state,
+        }
+    }
+
+    pub fn display(&self) -> WlDisplay {
+        self.connection.display()
+    }
+
+    /// Sends out the pending requests.
+    pub fn flush(&mut self) {
+        self.connection.flush().unwrap();
+    }
+
+    /// Reads what the compositor sent without blocking and handles it.
+    pub fn dispatch(&mut self) {
+        if let Some(guard) = self.event_queue.prepare_read() {
+            match guard.read() {
+                Ok(_) => (),
+                Err(WaylandError::Io(err)) if err.kind() == ErrorKind::WouldBlock => (),
+                Err(err) => panic!("error reading from the compositor: {err}"),
+            }
+        }
+        self.event_queue.dispatch_pending(&mut self.state).unwrap();
+    }
+
+    /// Asks for a callback once the compositor handled everything sent so far.
+    pub fn sync(&mut self) -> Arc<AtomicBool> {
+        let done = Arc::new(AtomicBool::new(false));
+        self.display().sync(&self.qh, done.clone());
+        done
+    }
+
+    /// Binds the global with the interface of `I`, panicking when there is none.
+    pub fn bind<I>(&mut self, version: u32) -> I
+    where
+        I: Proxy + 'static,
+        State: Dispatch<I, ()>,
+    {
+        self.try_bind(version)
+            .unwrap_or_else(|| panic!("no {} global", I::interface().name))
+    }
+
+    pub fn try_bind<I>(&mut self, version: u32) -> Option<I>
+    where
+        I: Proxy + 'static,
+        State: Dispatch<I, ()>,
+    {
+        self.try_bind_with(version, ())
+    }
+
+    /// Binds a global whose events are recorded.
+    pub fn bind_recording<I>(&mut self, version: u32) -> (I, Events<I::Event>)
+    where
+        I: Proxy + 'static,
+        I::Event: Send,
+        State: Dispatch<I, Events<I::Event>>,
+    {
+        let events = Events::default();
+        let proxy = self
+            .try_bind_with(version, events.clone())
+            .unwrap_or_else(|| panic!("no {} global", I::interface().name));
+        (proxy, events)
+    }
+
+    fn try_bind_with<I, U>(&mut self, version: u32, data: U) -> Option<I>
+    where
+        I: Proxy + 'static,
+        U: Send + Sync + 'static,
+        State: Dispatch<I, U>,
+    {
+        let global = self
+            .state
+            .globals
+            .iter()
+            .find(|global| global.interface == I::interface().name)?;
+        let version = version.min(global.version);
+        let registry = self.state.registry.as_ref().unwrap();
+        Some(registry.bind(global.name, version, &self.qh, data))
+    }
+
+    /// Creates an xdg toplevel and does the initial commit, the compositor answers with a
+    /// configure that is acked automatically.
+    pub fn create_window(&mut self) -> Window {
+        let compositor: WlCompositor = self.bind(4);
+        let wm_base: XdgWmBase = self.bind(1);
+
+        let surface = compositor.create_surface(&self.qh, ());
+        let xdg_surface = wm_base.get_xdg_surface(&surface, &self.qh, ());
+        let toplevel = xdg_surface.get_toplevel(&self.qh, Events::default());
+        surface.commit();
+
+        Window {
+            surface,
+            xdg_surface,
+            toplevel,
+        }
+    }
+
+    /// An Argb8888 buffer filled with `color`, given as `0xAARRGGBB`.
+    pub fn create_shm_buffer(&mut self, width: i32, height: i32, color: u32) -> ShmBuffer {
+        let buffer = self.create_shm_buffer_with_format(width, height, wl_shm::Format::Argb8888);
+        buffer.fill(color);
+        buffer
+    }
+
+    pub fn create_shm_buffer_with_format(&mut self, width: i32, height: i32, format: wl_shm::Format) -> ShmBuffer {
+        let shm: WlShm = self.bind(1);
+
+        let fd = memfd_create("tsuki-test", MemfdFlags::CLOEXEC).unwrap();
+        let file = File::from(fd);
+        let stride = width * 4;
+        file.set_len((stride * height) as u64).unwrap();
+
+        let pool = shm.create_pool(file.as_fd(), stride * height, &self.qh, ());
+        let buffer = pool.create_buffer(0, width, height, stride, format, &self.qh, ());
+        pool.destroy();
+
+        ShmBuffer {
+            buffer,
+            width,
+            height,
+            file,
+        }
+    }
+}
+
+impl Window {
+    /// Attaches `buffer` and commits with the whole surface damaged.
+    pub fn commit_buffer(&self, buffer: &ShmBuffer) {
+        self.surface.attach(Some(&buffer.buffer), 0, 0);
+        self.surface.damage_buffer(0, 0, buffer.width, buffer.height);
+        self.surface.commit();
+    }
+}
+
+impl ShmBuffer {
+    pub fn fill(&self, color: u32) {
+        let pixels = color.to_le_bytes().repeat((self.width * self.height) as usize);
+        self.file.write_all_at(&pixels, 0).unwrap();
+    }
+
+    /// What is in the buffer right now, e.g. after the compositor copied into it.
+    pub fn pixels(&self) -> Vec<u8> {
+        let mut pixels = vec![0; (self.width * self.height * 4) as usize];
+        self.file.read_exact_at(&mut pixels, 0).unwrap();
+        pixels
+    }
+
+    /// Fills a rectangle, leaving the rest of the buffer as it is.
+    pub fn fill_rect(&self, x: i32, y: i32, width: i32, height: i32, color: u32) {
+        let row = color.to_le_bytes().repeat(width as usize);
+        for y in y..y + height {
+            let offset = (y * self.width + x) * 4;
+            self.file.write_all_at(&row, offset as u64).unwrap();
+        }
+    }
+}
+
+impl Dispatch<WlRegistry, ()> for State {
+    fn event(
+        state: &mut Self,
+        _registry: &WlRegistry,
+        event: wl_registry::Event,
+        _data: &(),
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_registry::Event::Global { name, interface, version } => {
+                state.globals.push(Global { name, interface, version });
+            }
+            wl_registry::Event::GlobalRemove { name } => {
+                state.globals.retain(|global| global.name != name);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<WlCallback, Arc<AtomicBool>> for State {
+    fn event(
+        _state: &mut Self,
+        _callback: &WlCallback,
+        event: wl_callback::Event,
+        done: &Arc<AtomicBool>,
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let wl_callback::Event::Done { .. } = event {
+            done.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Dispatch<XdgWmBase, ()> for State {
+    fn event(
+        _state: &mut Self,
+        wm_base: &XdgWmBase,
+        event: xdg_wm_base::Event,
+        _data: &(),
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            wm_base.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<XdgSurface, ()> for State {
+    fn event(
+        _state: &mut Self,
+        xdg_surface: &XdgSurface,
+        event: xdg_surface::Event,
+        _data: &(),
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            xdg_surface.ack_configure(serial);
+        }
+    }
+}
+
+impl Dispatch<WlOutput, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _output: &WlOutput,
+        _event: wl_output::Event,
+        _data: &(),
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrOutputManagerV1, Events<zwlr_output_manager_v1::Event>> for State {
+    fn event(
+        _state: &mut Self,
+        _manager: &ZwlrOutputManagerV1,
+        event: zwlr_output_manager_v1::Event,
+        events: &Events<zwlr_output_manager_v1::Event>,
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+        events.lock().unwrap().push(event);
+    }
+
+    event_created_child!(State, ZwlrOutputManagerV1, [
+        zwlr_output_manager_v1::EVT_HEAD_OPCODE => (ZwlrOutputHeadV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrOutputHeadV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _head: &ZwlrOutputHeadV1,
+        _event: zwlr_output_head_v1::Event,
+        _data: &(),
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+
+    event_created_child!(State, ZwlrOutputHeadV1, [
+        zwlr_output_head_v1::EVT_MODE_OPCODE => (ZwlrOutputModeV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+    fn event(
+        _state: &mut Self,
+        _device: &ZwlrDataControlDeviceV1,
+        _event: zwlr_data_control_device_v1::Event,
+        _data: &(),
+        _connection: &Connection,
+        _qh: &QueueHandle<Self>,
+    ) {
+    }
+
+    event_created_child!(State, ZwlrDataControlDeviceV1, [
+        zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+    ]);
+}
+
+/// Implements `Dispatch` for objects with [`Events`] as user data, collecting their events.
+macro_rules! record_events {
+    ($($interface:ty),* $(,)?) => {$(
+        impl Dispatch<$interface, Events<<$interface as Proxy>::Event>> for State {
+            fn event(
+                _state: &mut Self,
+                _proxy: &$interface,
+                event: <$interface as Proxy>::Event,
+                events: &Events<<$interface as Proxy>::Event>,
+                _connection: &Connection,
+                _qh: &QueueHandle<Self>,
+            ) {
+                events.lock().unwrap().push(event);
+            }
+        }
+    )*};
+}
+
+record_events!(
+    WlKeyboard,
+    XdgToplevel,
+    ZwlrOutputConfigurationV1,
+    ZwpLinuxDmabufV1,
+    ZwpLinuxDmabufFeedbackV1,
+    ZwlrScreencopyFrameV1,
+    ExtIdleNotificationV1,
+    ZwlrDataControlSourceV1,
+    ExtSessionLockV1,
+);
+
+delegate_noop!(State: ignore WlCompositor);
+delegate_noop!(State: ignore WlSurface);
+delegate_noop!(State: ignore WlShm);
+delegate_noop!(State: ignore WlShmPool);
+delegate_noop!(State: ignore WlBuffer);
+delegate_noop!(State: ignore ZwlrOutputModeV1);
+delegate_noop!(State: ignore ZwlrOutputConfigurationHeadV1);
+delegate_noop!(State: ignore ZwlrScreencopyManagerV1);
+delegate_noop!(State: ignore WlSeat);
+delegate_noop!(State: ignore ExtIdleNotifierV1);
+delegate_noop!(State: ignore ZwlrDataControlManagerV1);
+delegate_noop!(State: ignore ZwlrDataControlOfferV1);
+delegate_noop!(State: ignore ExtSessionLockManagerV1);
@@ -0,0 +1,56 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    mem,
+    time::{Duration, Instant},
+};
+
+use smithay::wayland::selection::data_device::current_data_device_selection_userdata;
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_protocols_wlr::data_control::v1::client::{
+    zwlr_data_control_device_v1::ZwlrDataControlDeviceV1, zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_source_v1,
+};
+
+use super::{client::Events, fixture::Fixture};
+
+#[test]
+fn selection_outlives_its_client_despite_a_stalled_read() {
+    let mut f = Fixture::new();
+    f.tsuki().config.clipboard.persist = true;
+    let id = f.add_client();
+
+    // Set by a data-control client, which doesn't need keyboard focus to own the selection.
+    let manager: ZwlrDataControlManagerV1 = f.client(id).bind(2);
+    let seat: WlSeat = f.client(id).bind(1);
+    let qh = f.client(id).qh.clone();
+    let device: ZwlrDataControlDeviceV1 = manager.get_data_device(&seat, &qh, ());
+    let events = Events::<zwlr_data_control_source_v1::Event>::default();
+    let source = manager.create_data_source(&qh, events.clone());
+    source.offer("text/plain".to_owned());
+    source.offer("text/html".to_owned());
+    device.set_selection(Some(&source));
+    let start = Instant::now();
+
+    f.dispatch_until(|_| events.lock().unwrap().len() == 2);
+    // Only one of the reads is answered, the other pipe is left open.
+    let mut stalled = Vec::new();
+    for event in mem::take(&mut *events.lock().unwrap()) {
+        let zwlr_data_control_source_v1::Event::Send { mime_type, fd } = event else {
+            panic!("unexpected source event");
+        };
+        if mime_type == "text/plain" {
+            File::from(fd).write_all(b"tsuki").unwrap();
+        } else {
+            stalled.push(fd);
+        }
+    }
+
+    f.clients.remove(id);
+    f.dispatch_until(|f| current_data_device_selection_userdata(&f.tsuki().seat).is_some());
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    let contents = current_data_device_selection_userdata(&f.tsuki().seat).unwrap().clone();
+    assert_eq!(*contents, HashMap::from([("text/plain".to_owned(), b"tsuki".to_vec())]));
+}
@@ -0,0 +1,22 @@
+use crate::config::Config;
+
+fn parse(toml: &str) -> Result<Config, toml::de::Error> {
+    toml::from_str(toml)
+}
+
+#[test]
+fn output_scale() {
+    let config = parse("[[output]]\nname = \"eDP-1\"\nscale = 1.5").unwrap();
+    assert_eq!(config.outputs[0].scale.unwrap().0, 1.5);
+
+    let config = parse("[[output]]\nname = \"eDP-1\"\nscale = 2").unwrap();
+    assert_eq!(config.outputs[0].scale.unwrap().0, 2.);
+}
+
+#[test]
+fn output_scale_out_of_range() {
+    for scale in ["0", "-1", "0.0", "100", "nan", "inf"] {
+        let toml = format!("[[output]]\nname = \"eDP-1\"\nscale = {scale}");
+        assert!(parse(&toml).is_err(), "scale {scale} was accepted");
+    }
+}
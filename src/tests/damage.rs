@@ -0,0 +1,94 @@
+use smithay::{
+    input::pointer::CursorImageStatus,
+    output::Output,
+    utils::{Physical, Rectangle},
+};
+
+use super::fixture::{fixture_with_window, Fixture, BLUE};
+use crate::state::RedrawState;
+
+fn assert_within(damage: &[Rectangle<i32, Physical>], area: Rectangle<i32, Physical>) {
+    assert!(!damage.is_empty(), "expected damage in {area:?}");
+    for rect in damage {
+        assert!(area.contains_rect(*rect), "{rect:?} is outside of {area:?}");
+    }
+}
+
+#[test]
+fn partial_commit_only_repaints_its_damage() {
+    let (mut f, id, window, buffer) = fixture_with_window();
+    let output = f.outputs()[0].clone();
+
+    buffer.fill_rect(10, 10, 20, 20, BLUE);
+    window.surface.attach(Some(&buffer.buffer), 0, 0);
+    window.surface.damage_buffer(10, 10, 20, 20);
+    window.surface.commit();
+    f.roundtrip(id);
+    f.wait_for_redraws();
+
+    let damage = f.with_headless(|headless| headless.last_damage(&output).map(<[_]>::to_vec));
+    assert_within(&damage.unwrap(), Rectangle::new((10, 10).into(), (20, 20).into()));
+
+    // Repainting only the damage has to end up with the same image as a full redraw.
+    let pixels = f.with_headless(|headless| headless.framebuffer_pixels(&output));
+    assert!(pixels == f.with_headless(|headless| headless.full_repaint_pixels(&output)));
+}
+
+#[test]
+fn redraw_without_changes_has_no_damage() {
+    let (mut f, _, _window, _buffer) = fixture_with_window();
+    let output = f.outputs()[0].clone();
+
+    f.tsuki().queue_redraw(&output);
+    f.wait_for_redraws();
+
+    assert!(f.with_headless(|headless| headless.last_damage(&output).is_none()));
+}
+
+#[test]
+fn closed_window_damages_its_area() {
+    let (mut f, id, window, _buffer) = fixture_with_window();
+    let output = f.outputs()[0].clone();
+
+    window.toplevel.destroy();
+    window.xdg_surface.destroy();
+    window.surface.destroy();
+    f.roundtrip(id);
+    f.wait_for_redraws();
+
+    let damage = f.with_headless(|headless| headless.last_damage(&output).map(<[_]>::to_vec));
+    assert_within(&damage.unwrap(), Rectangle::from_size((100, 100).into()));
+
+    let pixels = f.with_headless(|headless| headless.framebuffer_pixels(&output));
+    assert!(pixels == f.with_headless(|headless| headless.full_repaint_pixels(&output)));
+}
+
+/// Whether a redraw of the output is pending.
+fn redraw_queued(f: &mut Fixture, output: &Output) -> bool {
+    matches!(
+        f.tsuki().output_state[output].redraw_state,
+        RedrawState::Queued
+            | RedrawState::Delayed(_)
+            | RedrawState::WaitingForEstimatedVBlankAndQueued(_)
+            | RedrawState::WaitingForVBlank { redraw_needed: true }
+    )
+}
+
+#[test]
+fn cursor_motion_only_redraws_outputs_under_the_cursor() {
+    let mut f = Fixture::with_outputs(2);
+    let outputs = f.outputs();
+    f.wait_for_redraws();
+
+    f.tsuki().queue_redraw_cursor((100., 100.).into(), (110., 100.).into());
+    assert!(redraw_queued(&mut f, &outputs[0]));
+    assert!(!redraw_queued(&mut f, &outputs[1]));
+    f.wait_for_redraws();
+
+    // A hidden cursor isn't drawn anywhere, moving it doesn't need a redraw.
+    f.tsuki().cursor_manager.set_image(CursorImageStatus::Hidden);
+    f.tsuki().queue_redraw_cursor((1910., 100.).into(), (1930., 100.).into());
+    for output in &outputs {
+        assert!(!redraw_queued(&mut f, output));
+    }
+}
@@ -0,0 +1,80 @@
+use smithay::backend::renderer::ImportDma;
+use wayland_client::{protocol::wl_shm, Proxy};
+use wayland_protocols::wp::linux_dmabuf::zv1::client::{
+    zwp_linux_dmabuf_feedback_v1, zwp_linux_dmabuf_v1::{self, ZwpLinuxDmabufV1},
+};
+
+use super::{client::Events, fixture::Fixture};
+use crate::backend::Backend;
+
+#[test]
+fn dmabuf_global_advertises_formats() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+
+    let (dmabuf, events) = f.client(id).bind_recording::<ZwpLinuxDmabufV1>(5);
+    f.roundtrip(id);
+
+    if dmabuf.version() < 4 {
+        // Without a render node there is no feedback, every importable format and modifier
+        // comes with the bind instead.
+        assert!(f.tsuki().capture_state.dmabuf.is_none());
+        let mut advertised: Vec<_> = events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match *event {
+                zwp_linux_dmabuf_v1::Event::Modifier { format, modifier_hi, modifier_lo } => {
+                    Some((format, (modifier_hi as u64) << 32 | modifier_lo as u64))
+                }
+                _ => None,
+            })
+            .collect();
+        let mut importable: Vec<_> = f.with_headless(|headless| {
+            let renderer = headless.renderer().unwrap();
+            renderer
+                .dmabuf_formats()
+                .iter()
+                .map(|format| (format.code as u32, u64::from(format.modifier)))
+                .collect()
+        });
+        advertised.sort_unstable();
+        importable.sort_unstable();
+        assert_eq!(advertised, importable);
+        return;
+    }
+
+    let feedback_events = Events::<zwp_linux_dmabuf_feedback_v1::Event>::default();
+    dmabuf.get_default_feedback(&f.client(id).qh, feedback_events.clone());
+    f.roundtrip(id);
+
+    let feedback_events = feedback_events.lock().unwrap();
+    assert!(feedback_events
+        .iter()
+        .any(|event| matches!(event, zwp_linux_dmabuf_feedback_v1::Event::MainDevice { .. })));
+    assert!(feedback_events
+        .iter()
+        .any(|event| matches!(event, zwp_linux_dmabuf_feedback_v1::Event::FormatTable { .. })));
+    assert!(matches!(feedback_events.last(), Some(zwp_linux_dmabuf_feedback_v1::Event::Done)));
+}
+
+#[test]
+fn shm_buffers_still_render() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+    let output = f.outputs()[0].clone();
+    let _dmabuf = f.client(id).bind_recording::<ZwpLinuxDmabufV1>(5);
+
+    let window = f.client(id).create_window();
+    f.roundtrip(id);
+    // Xrgb8888 ignores the alpha byte, the window has to come out opaque anyway.
+    let buffer = f.client(id).create_shm_buffer_with_format(100, 100, wl_shm::Format::Xrgb8888);
+    buffer.fill(0x0000ff00);
+    window.commit_buffer(&buffer);
+    f.roundtrip(id);
+    f.wait_for_redraws();
+
+    let pixels = f.with_headless(|headless| headless.framebuffer_pixels(&output));
+    let offset = (50 * 1920 + 50) * 4;
+    assert_eq!(pixels[offset..offset + 4], [0x00, 0xff, 0x00, 0xff]);
+}
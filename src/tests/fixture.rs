@@ -0,0 +1,149 @@
+//! A compositor on the headless backend, with clients connected over socket pairs.
+
+use std::{
+    cell::RefCell,
+    os::unix::net::UnixStream,
+    rc::Rc,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+
+use smithay::{
+    output::Output,
+    reexports::{calloop::EventLoop, wayland_server::Display},
+};
+
+use super::client::{Client, ShmBuffer, Window};
+use crate::{
+    backend::{Backend, Headless},
+    config::Config,
+    state::{ClientState, RedrawState},
+    CalloopData, Tsuki,
+};
+
+/// How long [`Fixture::dispatch_until`] waits before giving up.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Buffer colours, as `0xAARRGGBB`.
+pub const RED: u32 = 0xffff0000;
+pub const BLUE: u32 = 0xff0000ff;
+
+pub struct Fixture {
+    event_loop: EventLoop<'static, CalloopData>,
+    pub data: CalloopData,
+    pub clients: Vec<Client>,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        Self::with_outputs(1)
+    }
+
+    pub fn with_outputs(outputs: usize) -> Self {
+        let event_loop = EventLoop::try_new().unwrap();
+        let backend: Rc<RefCell<dyn Backend>> = Rc::new(RefCell::new(Headless::with_outputs(outputs)));
+
+        let display = Display::new().unwrap();
+        let display_handle = display.handle();
+        let tsuki = Tsuki::new(event_loop.handle(), event_loop.get_signal(), display, backend.clone(), Config::default());
+
+        let mut data = CalloopData {
+            tsuki,
+            display_handle,
+            backend: backend.clone(),
+        };
+        backend.borrow_mut().init(&mut data.tsuki);
+
+        Self {
+            event_loop,
+            data,
+            clients: Vec::new(),
+        }
+    }
+
+    pub fn tsuki(&mut self) -> &mut Tsuki {
+        &mut self.data.tsuki
+    }
+
+    pub fn outputs(&self) -> Vec<Output> {
+        self.data.tsuki.space.outputs().cloned().collect()
+    }
+
+    pub fn with_headless<T>(&mut self, f: impl FnOnce(&mut Headless) -> T) -> T {
+        let mut backend = self.data.backend.borrow_mut();
+        f(backend.as_any().downcast_mut::<Headless>().unwrap())
+    }
+
+    /// Connects a new client, returns its index in `clients`. The registry is filled in already.
+    pub fn add_client(&mut self) -> usize {
+        let (server, client) = UnixStream::pair().unwrap();
+        self.data
+            .display_handle
+            .insert_client(server, Arc::new(ClientState::default()))
+            .unwrap();
+
+        self.clients.push(Client::new(client));
+        let index = self.clients.len() - 1;
+        self.roundtrip(index);
+        index
+    }
+
+    pub fn client(&mut self, index: usize) -> &mut Client {
+        &mut self.clients[index]
+    }
+
+    /// One exchange in both directions: the compositor handles what the clients sent, runs its
+    /// main loop iteration and the clients handle the replies.
+    fn dispatch(&mut self, timeout: Duration) {
+        for client in &mut self.clients {
+            client.flush();
+        }
+        self.event_loop.dispatch(timeout, &mut self.data).unwrap();
+        self.data.refresh_and_flush();
+        for client in &mut self.clients {
+            client.dispatch();
+        }
+    }
+
+    /// Dispatches until the compositor handled everything the client sent so far.
+    pub fn roundtrip(&mut self, index: usize) {
+        let done = self.clients[index].sync();
+        self.dispatch_until(|_| done.load(Ordering::Relaxed));
+    }
+
+    /// Keeps dispatching, timers included, until `condition` holds.
+    pub fn dispatch_until(&mut self, mut condition: impl FnMut(&mut Self) -> bool) {
+        let start = Instant::now();
+        while !condition(self) {
+            assert!(start.elapsed() < TIMEOUT, "timed out waiting for the compositor");
+            self.dispatch(Duration::from_millis(1));
+        }
+    }
+
+    /// Dispatches until every output finished its frame and has nothing queued.
+    pub fn wait_for_redraws(&mut self) {
+        self.dispatch_until(|fixture| {
+            fixture.data.tsuki.output_state.values().all(|state| {
+                matches!(
+                    state.redraw_state,
+                    RedrawState::Idle | RedrawState::WaitingForEstimatedVBlank(_)
+                )
+            })
+        });
+    }
+}
+
+/// A window mapped at the origin, showing a 100x100 red buffer.
+pub fn fixture_with_window() -> (Fixture, usize, Window, ShmBuffer) {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+
+    let window = f.client(id).create_window();
+    f.roundtrip(id);
+    let buffer = f.client(id).create_shm_buffer(100, 100, RED);
+    window.commit_buffer(&buffer);
+    f.roundtrip(id);
+    f.wait_for_redraws();
+
+    (f, id, window, buffer)
+}
@@ -0,0 +1,70 @@
+use std::time::{Duration, Instant};
+
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_protocols::ext::idle_notify::v1::client::{
+    ext_idle_notification_v1, ext_idle_notifier_v1::ExtIdleNotifierV1,
+};
+
+use super::{client::Events, fixture::Fixture};
+
+#[test]
+fn idle_notification_fires_without_input() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+
+    let notifier: ExtIdleNotifierV1 = f.client(id).bind(1);
+    let seat: WlSeat = f.client(id).bind(1);
+    let events = Events::<ext_idle_notification_v1::Event>::default();
+    let start = Instant::now();
+    notifier.get_idle_notification(100, &seat, &f.client(id).qh, events.clone());
+
+    let idled = |events: &Events<ext_idle_notification_v1::Event>| {
+        events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| matches!(event, ext_idle_notification_v1::Event::Idled))
+            .count()
+    };
+    f.dispatch_until(|_| idled(&events) == 1);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    f.tsuki().notify_activity();
+    f.roundtrip(id);
+    assert!(matches!(
+        events.lock().unwrap().last(),
+        Some(ext_idle_notification_v1::Event::Resumed)
+    ));
+
+    // The timeout starts over with the activity.
+    f.dispatch_until(|_| idled(&events) == 2);
+}
+
+#[test]
+fn idle_timeouts_are_dropped_with_their_last_notification() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+
+    let notifier: ExtIdleNotifierV1 = f.client(id).bind(2);
+    let seat: WlSeat = f.client(id).bind(1);
+    let events = Events::<ext_idle_notification_v1::Event>::default();
+    let qh = f.client(id).qh.clone();
+    let first = notifier.get_idle_notification(60_000, &seat, &qh, events.clone());
+    let second = notifier.get_input_idle_notification(60_000, &seat, &qh, events.clone());
+    let third = notifier.get_idle_notification(1500, &seat, &qh, events.clone());
+    f.roundtrip(id);
+    let timeouts = |f: &mut Fixture| f.tsuki().idle_timers.timeouts.clone();
+    assert_eq!(timeouts(&mut f).len(), 2);
+
+    third.destroy();
+    first.destroy();
+    f.roundtrip(id);
+    assert_eq!(
+        timeouts(&mut f).into_keys().collect::<Vec<_>>(),
+        [Duration::from_secs(60)]
+    );
+
+    second.destroy();
+    f.roundtrip(id);
+    assert!(timeouts(&mut f).is_empty());
+}
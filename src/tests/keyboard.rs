@@ -0,0 +1,69 @@
+use smithay::{
+    backend::input::KeyState,
+    input::keyboard::Keycode,
+    utils::SERIAL_COUNTER,
+};
+use wayland_client::protocol::{wl_keyboard, wl_seat::WlSeat};
+
+use super::{
+    client::Events,
+    fixture::{fixture_with_window, Fixture},
+};
+
+// xkb keycodes, the evdev ones plus 8.
+const KEY_LEFTCTRL: u32 = 37;
+const KEY_LEFTSHIFT: u32 = 50;
+const KEY_P: u32 = 33;
+const KEY_ESC: u32 = 9;
+const KEY_SYSRQ: u32 = 107;
+
+/// Presses the keys in order and releases them in reverse.
+fn press_and_release(f: &mut Fixture, keys: &[u32]) {
+    for key in keys {
+        f.tsuki().handle_key(Keycode::new(*key), KeyState::Pressed, 0);
+    }
+    for key in keys.iter().rev() {
+        f.tsuki().handle_key(Keycode::new(*key), KeyState::Released, 0);
+    }
+}
+
+#[test]
+fn outputs_power_binding_toggles_once() {
+    let mut f = Fixture::new();
+    let output = f.outputs()[0].clone();
+
+    press_and_release(&mut f, &[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_P]);
+    assert!(!f.tsuki().output_state[&output].powered);
+    assert!(f.tsuki().suppressed_keys.is_empty());
+
+    press_and_release(&mut f, &[KEY_LEFTCTRL, KEY_LEFTSHIFT, KEY_P]);
+    assert!(f.tsuki().output_state[&output].powered);
+}
+
+#[test]
+fn region_selection_keys_stay_with_the_compositor() {
+    let (mut f, id, _window, _buffer) = fixture_with_window();
+
+    let seat: WlSeat = f.client(id).bind(1);
+    let events = Events::<wl_keyboard::Event>::default();
+    let _keyboard = seat.get_keyboard(&f.client(id).qh, events.clone());
+    f.roundtrip(id);
+    let mapped = f.tsuki().space.elements().next().unwrap().clone();
+    f.tsuki().focus_window(&mapped, SERIAL_COUNTER.next_serial());
+
+    press_and_release(&mut f, &[KEY_SYSRQ]);
+    assert!(f.tsuki().region_selection.is_some());
+
+    // Escape ends the selection on its press, the release must not reach the client alone.
+    press_and_release(&mut f, &[KEY_ESC]);
+    assert!(f.tsuki().region_selection.is_none());
+    f.roundtrip(id);
+
+    let keys = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|event| matches!(event, wl_keyboard::Event::Key { .. }))
+        .count();
+    assert_eq!(keys, 0);
+}
@@ -0,0 +1,36 @@
+use wayland_protocols::ext::session_lock::v1::client::{
+    ext_session_lock_manager_v1::ExtSessionLockManagerV1, ext_session_lock_v1,
+};
+
+use super::{client::Events, fixture::Fixture};
+use crate::{lock::LockState, state::RedrawState};
+
+#[test]
+fn lock_is_confirmed_once_presented() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+    let output = f.outputs()[0].clone();
+    f.wait_for_redraws();
+
+    let manager: ExtSessionLockManagerV1 = f.client(id).bind(1);
+    let events = Events::<ext_session_lock_v1::Event>::default();
+    let _lock = manager.lock(&f.client(id).qh, events.clone());
+
+    // The frame showing the lock screen went out, but it isn't on screen before its vblank.
+    f.dispatch_until(|f| {
+        matches!(
+            f.tsuki().output_state[&output].redraw_state,
+            RedrawState::WaitingForVBlank { .. }
+        )
+    });
+    assert!(matches!(f.tsuki().lock_state, LockState::Locking(_)));
+
+    f.dispatch_until(|_| {
+        events
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|event| matches!(event, ext_session_lock_v1::Event::Locked))
+    });
+    assert!(matches!(f.tsuki().lock_state, LockState::Locked(_)));
+}
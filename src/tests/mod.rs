@@ -0,0 +1,17 @@
+mod client;
+mod fixture;
+
+mod capture;
+mod clipboard;
+mod config;
+mod damage;
+mod dmabuf;
+mod idle;
+mod keyboard;
+mod lock;
+mod output_management;
+mod output_power;
+mod screenshot;
+mod window;
+#[cfg(feature = "xwayland")]
+mod xwayland;
@@ -0,0 +1,101 @@
+use wayland_protocols_wlr::output_management::v1::client::{
+    zwlr_output_configuration_v1::{self, ZwlrOutputConfigurationV1},
+    zwlr_output_head_v1::ZwlrOutputHeadV1,
+    zwlr_output_manager_v1::{self, ZwlrOutputManagerV1},
+};
+
+use super::{client::Events, fixture::Fixture};
+
+fn heads(events: &Events<zwlr_output_manager_v1::Event>) -> Vec<ZwlrOutputHeadV1> {
+    let events = events.lock().unwrap();
+    events
+        .iter()
+        .filter_map(|event| match event {
+            zwlr_output_manager_v1::Event::Head { head } => Some(head.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn last_serial(events: &Events<zwlr_output_manager_v1::Event>) -> Option<u32> {
+    let events = events.lock().unwrap();
+    events.iter().rev().find_map(|event| match event {
+        zwlr_output_manager_v1::Event::Done { serial } => Some(*serial),
+        _ => None,
+    })
+}
+
+/// Sends `configuration` as a test or an apply and returns how the compositor answered.
+fn send(
+    f: &mut Fixture,
+    id: usize,
+    configuration: &ZwlrOutputConfigurationV1,
+    events: &Events<zwlr_output_configuration_v1::Event>,
+    test_only: bool,
+) -> zwlr_output_configuration_v1::Event {
+    if test_only {
+        configuration.test();
+    } else {
+        configuration.apply();
+    }
+    f.roundtrip(id);
+    events.lock().unwrap().pop().expect("no answer to the configuration")
+}
+
+fn create_configuration(
+    f: &mut Fixture,
+    id: usize,
+    manager: &ZwlrOutputManagerV1,
+    serial: u32,
+) -> (ZwlrOutputConfigurationV1, Events<zwlr_output_configuration_v1::Event>) {
+    let events = Events::default();
+    let client = f.client(id);
+    let configuration = manager.create_configuration(serial, &client.qh, events.clone());
+    (configuration, events)
+}
+
+#[test]
+fn every_manager_of_a_client_gets_updates() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+
+    let (first, first_events) = f.client(id).bind_recording::<ZwlrOutputManagerV1>(4);
+    let (_second, second_events) = f.client(id).bind_recording::<ZwlrOutputManagerV1>(4);
+    f.roundtrip(id);
+    assert_eq!(heads(&first_events).len(), 1);
+    assert_eq!(heads(&second_events).len(), 1);
+
+    let serial = last_serial(&first_events).unwrap();
+    let (configuration, events) = create_configuration(&mut f, id, &first, serial);
+    configuration.enable_head(&heads(&first_events)[0], &f.client(id).qh, ()).set_scale(2.);
+    assert!(matches!(
+        send(&mut f, id, &configuration, &events, false),
+        zwlr_output_configuration_v1::Event::Succeeded
+    ));
+
+    assert_eq!(f.outputs()[0].current_scale().fractional_scale(), 2.);
+    assert!(last_serial(&first_events).unwrap() != serial);
+    assert_eq!(last_serial(&first_events), last_serial(&second_events));
+}
+
+#[test]
+fn disabling_every_output_fails_the_test_too() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+
+    let (manager, manager_events) = f.client(id).bind_recording::<ZwlrOutputManagerV1>(4);
+    f.roundtrip(id);
+    let head = heads(&manager_events)[0].clone();
+    let serial = last_serial(&manager_events).unwrap();
+
+    for test_only in [true, false] {
+        let (configuration, events) = create_configuration(&mut f, id, &manager, serial);
+        configuration.disable_head(&head);
+        assert!(matches!(
+            send(&mut f, id, &configuration, &events, test_only),
+            zwlr_output_configuration_v1::Event::Failed
+        ));
+    }
+
+    assert_eq!(f.outputs().len(), 1);
+}
@@ -0,0 +1,34 @@
+use smithay::utils::Rectangle;
+
+use super::fixture::{fixture_with_window, BLUE};
+use crate::state::RedrawState;
+
+#[test]
+fn output_that_is_off_is_not_redrawn() {
+    let (mut f, id, window, buffer) = fixture_with_window();
+    let output = f.outputs()[0].clone();
+
+    f.tsuki().set_output_power(&output, false);
+    buffer.fill(BLUE);
+    window.commit_buffer(&buffer);
+    f.roundtrip(id);
+    f.tsuki().queue_redraw(&output);
+
+    assert!(matches!(f.tsuki().output_state[&output].redraw_state, RedrawState::Idle));
+}
+
+#[test]
+fn output_turned_on_repaints_everything() {
+    let (mut f, _, _window, _buffer) = fixture_with_window();
+    let output = f.outputs()[0].clone();
+
+    f.tsuki().set_output_power(&output, false);
+    f.tsuki().set_output_power(&output, true);
+    f.wait_for_redraws();
+
+    let damage = f.with_headless(|headless| headless.last_damage(&output).map(<[_]>::to_vec));
+    assert_eq!(damage.unwrap(), [Rectangle::from_size((1920, 1080).into())]);
+
+    let pixels = f.with_headless(|headless| headless.framebuffer_pixels(&output));
+    assert!(pixels == f.with_headless(|headless| headless.full_repaint_pixels(&output)));
+}
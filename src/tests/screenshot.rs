@@ -0,0 +1,17 @@
+use crate::screenshot::encode_png;
+
+#[test]
+fn encoded_png_decodes_to_the_same_pixels() {
+    let (width, height) = (3, 2);
+    let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i * 7) as u8).collect();
+
+    let png = encode_png((width, height).into(), &rgba).unwrap();
+
+    let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+    let mut decoded = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut decoded).unwrap();
+    assert_eq!((info.width, info.height), (width as u32, height as u32));
+    assert_eq!(info.color_type, png::ColorType::Rgba);
+    assert_eq!(info.bit_depth, png::BitDepth::Eight);
+    assert_eq!(decoded, rgba);
+}
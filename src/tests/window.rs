@@ -0,0 +1,92 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use wayland_client::Proxy;
+use wayland_protocols::xdg::shell::client::xdg_toplevel;
+
+use super::{
+    client::Events,
+    fixture::{fixture_with_window, Fixture, RED},
+};
+
+#[test]
+fn mapped_window_is_rendered() {
+    let mut f = Fixture::new();
+    let id = f.add_client();
+    let output = f.outputs()[0].clone();
+
+    let window = f.client(id).create_window();
+    f.roundtrip(id);
+    let events = window.toplevel.data::<Events<xdg_toplevel::Event>>().unwrap();
+    assert!(events
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|event| matches!(event, xdg_toplevel::Event::Configure { .. })));
+
+    let buffer = f.client(id).create_shm_buffer(100, 100, RED);
+    window.commit_buffer(&buffer);
+    f.roundtrip(id);
+    f.wait_for_redraws();
+
+    // The window sits at the origin, the pointer covers its top left corner. The framebuffer
+    // is Abgr8888.
+    let pixels = f.with_headless(|headless| headless.framebuffer_pixels(&output));
+    let offset = (50 * 1920 + 50) * 4;
+    assert_eq!(pixels[offset..offset + 4], [0xff, 0x00, 0x00, 0xff]);
+}
+
+#[test]
+fn unchanged_maximized_state_is_configured() {
+    let (mut f, id, window, _buffer) = fixture_with_window();
+
+    let configures = || {
+        let events = window.toplevel.data::<Events<xdg_toplevel::Event>>().unwrap();
+        let events = events.lock().unwrap();
+        events
+            .iter()
+            .filter(|event| matches!(event, xdg_toplevel::Event::Configure { .. }))
+            .count()
+    };
+    let before = configures();
+    window.toplevel.unset_maximized();
+    window.toplevel.unset_fullscreen();
+    f.roundtrip(id);
+    assert_eq!(configures(), before + 2);
+}
+
+#[test]
+fn title_reaches_foreign_toplevels_on_commit() {
+    let (mut f, id, window, _buffer) = fixture_with_window();
+
+    let title = |f: &mut Fixture| {
+        let handles: Vec<_> = f.tsuki().foreign_toplevels.values().map(|handle| handle.title()).collect();
+        handles
+    };
+    assert_eq!(title(&mut f), [String::new()]);
+
+    window.toplevel.set_title("tsuki".into());
+    f.roundtrip(id);
+    assert_eq!(title(&mut f), [String::new()], "the title applies with the next commit");
+
+    window.surface.commit();
+    f.roundtrip(id);
+    assert_eq!(title(&mut f), ["tsuki".to_string()]);
+}
+
+#[test]
+fn minimized_window_gets_throttled_frame_callbacks() {
+    let (mut f, id, window, _buffer) = fixture_with_window();
+
+    let mapped = f.tsuki().space.elements().next().unwrap().clone();
+    f.tsuki().set_window_minimized(&mapped, true);
+    f.wait_for_redraws();
+
+    // No output draws the window anymore, the throttle timer answers within a second or two.
+    let done = Arc::new(AtomicBool::new(false));
+    window.surface.frame(&f.client(id).qh, done.clone());
+    window.surface.commit();
+    f.dispatch_until(|_| done.load(Ordering::Relaxed));
+}
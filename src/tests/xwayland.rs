@@ -0,0 +1,52 @@
+use std::{
+    env,
+    io::{ErrorKind, Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+};
+
+use super::fixture::Fixture;
+
+fn xwayland_installed() -> bool {
+    env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|dir| dir.join("Xwayland").exists()))
+}
+
+#[test]
+fn xwayland_waits_for_an_x_client() {
+    let mut f = Fixture::new();
+    let display = f.tsuki().setup_xwayland().unwrap();
+    let socket = format!("/tmp/.X11-unix/X{display}");
+    assert!(Path::new(&socket).exists());
+
+    f.wait_for_redraws();
+    assert!(f.tsuki().x11_sockets.is_some(), "Xwayland started without any X client");
+
+    let _client = UnixStream::connect(&socket).unwrap();
+    f.dispatch_until(|f| f.tsuki().x11_sockets.is_none());
+}
+
+#[test]
+fn first_x_client_is_served() {
+    if !xwayland_installed() {
+        eprintln!("Xwayland is not installed, skipping");
+        return;
+    }
+
+    let mut f = Fixture::new();
+    let display = f.tsuki().setup_xwayland().unwrap();
+
+    // The connection setup request: little endian, protocol 11.0, no authorization.
+    let mut client = UnixStream::connect(format!("/tmp/.X11-unix/X{display}")).unwrap();
+    client.write_all(&[b'l', 0, 11, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    client.set_nonblocking(true).unwrap();
+
+    let mut status = [0];
+    f.dispatch_until(|_| match client.read(&mut status) {
+        Ok(len) => len == 1,
+        Err(err) if err.kind() == ErrorKind::WouldBlock => false,
+        Err(err) => panic!("error reading the X11 setup reply: {err}"),
+    });
+    assert_eq!(status[0], 1, "X11 connection setup failed");
+
+    f.dispatch_until(|f| f.tsuki().xwm.is_some());
+}
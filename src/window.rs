@@ -0,0 +1,205 @@
+//! Window management actions shared by input handling, xdg-shell requests and taskbars.
+
+use std::cell::RefCell;
+
+use smithay::{
+    desktop::Window,
+    output::Output,
+    reexports::{wayland_protocols::xdg::shell::server::xdg_toplevel, wayland_server::Resource},
+    utils::{Logical, Point, Rectangle, Serial, SERIAL_COUNTER},
+};
+
+use crate::{focus::KeyboardFocusTarget, Tsuki};
+
+/// Kept in the window's user data.
+#[derive(Debug, Default)]
+pub struct WindowState {
+    pub maximized: bool,
+    pub fullscreen: bool,
+    pub minimized: bool,
+    /// Where the window goes back to once it's neither maximized nor fullscreen.
+    restore: Option<Rectangle<i32, Logical>>,
+    fullscreen_output: Option<Output>,
+    minimized_location: Option<Point<i32, Logical>>,
+}
+
+pub fn window_state(window: &Window) -> &RefCell<WindowState> {
+    window.user_data().insert_if_missing(|| RefCell::new(WindowState::default()));
+    window.user_data().get::<RefCell<WindowState>>().unwrap()
+}
+
+/// xdg-shell clients get a configure in reply to every state request, even when nothing
+/// changes.
+fn send_configure(window: &Window) {
+    if let Some(toplevel) = window.toplevel().filter(|toplevel| toplevel.is_initial_configure_sent()) {
+        toplevel.send_configure();
+    }
+}
+
+impl Tsuki {
+    /// Mapped windows and minimized ones, which are only out of the space.
+    pub fn toplevel_windows(&self) -> impl Iterator<Item = &Window> {
+        self.space.elements().chain(self.minimized_windows.iter())
+    }
+
+    /// Raises the window and gives it keyboard focus.
+    pub fn focus_window(&mut self, window: &Window, serial: Serial) {
+        // The lock screen keeps the keyboard until the session is unlocked.
+        if self.is_locked() {
+            return;
+        }
+
+        if window_state(window).borrow().minimized {
+            self.set_window_minimized(window, false);
+        }
+
+        let keyboard = self.seat.get_keyboard().unwrap();
+        self.space.raise_element(window, true);
+        #[cfg(feature = "xwayland")]
+        if let (Some(xwm), Some(x11)) = (self.xwm.as_mut(), window.x11_surface()) {
+            let _ = xwm.raise_window(x11);
+        }
+        keyboard.set_focus(self, KeyboardFocusTarget::from_window(window), serial);
+        self.space.elements().for_each(|window| {
+            if let Some(toplevel) = window.toplevel() {
+                toplevel.send_pending_configure();
+            }
+        });
+        self.queue_redraw_all();
+    }
+
+    pub fn close_window(&self, window: &Window) {
+        if let Some(toplevel) = window.toplevel() {
+            toplevel.send_close();
+        }
+        #[cfg(feature = "xwayland")]
+        if let Some(x11) = window.x11_surface() {
+            let _ = x11.close();
+        }
+    }
+
+    pub fn set_window_maximized(&mut self, window: &Window, maximized: bool) {
+        {
+            let mut state = window_state(window).borrow_mut();
+            if state.maximized == maximized {
+                drop(state);
+                send_configure(window);
+                return;
+            }
+            if maximized && !state.fullscreen {
+                state.restore = self.space.element_geometry(window);
+            }
+            state.maximized = maximized;
+        }
+        self.update_window_layout(window);
+    }
+
+    pub fn set_window_fullscreen(&mut self, window: &Window, fullscreen: bool, output: Option<Output>) {
+        {
+            let mut state = window_state(window).borrow_mut();
+            if state.fullscreen == fullscreen && (!fullscreen || state.fullscreen_output == output) {
+                drop(state);
+                send_configure(window);
+                return;
+            }
+            if fullscreen && !state.fullscreen && !state.maximized {
+                state.restore = self.space.element_geometry(window);
+            }
+            state.fullscreen = fullscreen;
+            state.fullscreen_output = output.filter(|_| fullscreen);
+        }
+        self.update_window_layout(window);
+    }
+
+    /// Minimized windows leave the space, they come back where they were.
+    pub fn set_window_minimized(&mut self, window: &Window, minimized: bool) {
+        {
+            let mut state = window_state(window).borrow_mut();
+            if state.minimized == minimized {
+                return;
+            }
+            state.minimized = minimized;
+            if minimized {
+                state.minimized_location = self.space.element_location(window);
+            }
+        }
+
+        if minimized {
+            self.space.unmap_elem(window);
+            self.minimized_windows.push(window.clone());
+
+            let keyboard = self.seat.get_keyboard().unwrap();
+            let focused = keyboard
+                .current_focus()
+                .is_some_and(|focus| Some(focus) == KeyboardFocusTarget::from_window(window));
+            if focused {
+                keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+            }
+        } else {
+            self.minimized_windows.retain(|w| w != window);
+            let location = window_state(window).borrow_mut().minimized_location.take();
+            self.space.map_element(window.clone(), location.unwrap_or_default(), true);
+        }
+        self.update_foreign_toplevel(window);
+        self.queue_redraw_all();
+    }
+
+    /// Applies the maximized and fullscreen states: the window covers its output, or goes back
+    /// to where it was before.
+    fn update_window_layout(&mut self, window: &Window) {
+        let state = window_state(window);
+        let (maximized, fullscreen, minimized, fullscreen_output) = {
+            let state = state.borrow();
+            (state.maximized, state.fullscreen, state.minimized, state.fullscreen_output.clone())
+        };
+
+        let output = fullscreen_output
+            .filter(|output| self.space.outputs().any(|o| o == output))
+            .or_else(|| self.output_for_window(window))
+            .or_else(|| self.space.outputs().next().cloned());
+        let target = if maximized || fullscreen {
+            output.as_ref().and_then(|output| self.space.output_geometry(output))
+        } else {
+            state.borrow_mut().restore.take()
+        };
+
+        if let Some(toplevel) = window.toplevel() {
+            toplevel.with_pending_state(|pending| {
+                for (set, flag) in [
+                    (maximized, xdg_toplevel::State::Maximized),
+                    (fullscreen, xdg_toplevel::State::Fullscreen),
+                ] {
+                    if set {
+                        pending.states.set(flag);
+                    } else {
+                        pending.states.unset(flag);
+                    }
+                }
+                pending.size = target.map(|target| target.size);
+                pending.fullscreen_output = output
+                    .as_ref()
+                    .filter(|_| fullscreen)
+                    .and_then(|output| output.client_outputs(&toplevel.wl_surface().client()?).pop());
+            });
+            toplevel.send_pending_configure();
+        }
+
+        #[cfg(feature = "xwayland")]
+        if let Some(x11) = window.x11_surface() {
+            let _ = x11.set_maximized(maximized);
+            let _ = x11.set_fullscreen(fullscreen);
+            if let Some(target) = target {
+                let _ = x11.configure(target);
+            }
+        }
+
+        // Minimized windows take the new location once they come back.
+        match target {
+            Some(target) if minimized => state.borrow_mut().minimized_location = Some(target.loc),
+            Some(target) => self.space.map_element(window.clone(), target.loc, false),
+            None => (),
+        }
+        self.update_foreign_toplevel(window);
+        self.queue_redraw_all();
+    }
+}
@@ -0,0 +1,573 @@
+use std::{
+    fs::{self, OpenOptions, Permissions},
+    io::{self, BufRead, BufReader, IoSlice, IoSliceMut, Write},
+    net::Shutdown,
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{fs::PermissionsExt, net::{SocketAddr, UnixListener, UnixStream}},
+    },
+    process::Stdio,
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+};
+
+use smithay::{
+    delegate_xwayland_shell,
+    desktop::Window,
+    input::{
+        pointer::{Focus, GrabStartData as PointerGrabStartData},
+        Seat,
+    },
+    reexports::{
+        calloop::{generic::Generic, EventLoop, Interest, Mode, PostAction, RegistrationToken},
+        wayland_server::{Client, Resource},
+    },
+    utils::{Logical, Rectangle, SERIAL_COUNTER},
+    wayland::xwayland_shell::{XWaylandShellHandler, XWaylandShellState},
+    xwayland::{
+        xwm::{Reorder, ResizeEdge as X11ResizeEdge, WmWindowProperty, XwmId},
+        X11Surface, X11Wm, XWayland, XWaylandEvent, XwmHandler,
+    },
+};
+
+use crate::{
+    focus::KeyboardFocusTarget,
+    grabs::{resize_grab::ResizeEdge, MoveSurfaceGrab, ResizeSurfaceGrab},
+    Tsuki,
+};
+
+impl Tsuki {
+    /// Reserves an X11 display and returns its number for `DISPLAY`. Xwayland only starts once
+    /// an X client connects to it, until then nothing runs.
+    pub fn setup_xwayland(&mut self) -> Option<u32> {
+        let mut sockets = match X11Sockets::bind() {
+            Ok(sockets) => sockets,
+            Err(err) => {
+                log::error!("error binding the X11 sockets: {err}");
+                return None;
+            }
+        };
+
+        for listener in &sockets.listeners {
+            match self.watch_x11_socket(listener) {
+                Ok(token) => sockets.tokens.push(token),
+                Err(err) => {
+                    log::error!("error watching the X11 sockets: {err}");
+                    for token in sockets.tokens.drain(..) {
+                        self.event_loop.remove(token);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        let display = sockets.display;
+        log::info!("X11 display :{display} reserved for Xwayland");
+        self.x11_sockets = Some(sockets);
+        Some(display)
+    }
+
+    fn watch_x11_socket(&self, listener: &UnixListener) -> anyhow::Result<RegistrationToken> {
+        let fd = listener.as_fd().try_clone_to_owned()?;
+        let token = self
+            .event_loop
+            .insert_source(Generic::new(fd, Interest::READ, Mode::Level), |_, _, data| {
+                data.tsuki.start_xwayland();
+                Ok(PostAction::Continue)
+            })
+            .map_err(|err| err.error)?;
+        Ok(token)
+    }
+
+    /// Hands the reserved display over to Xwayland. The clients that connected to our sockets
+    /// get relayed to it, everyone after talks to Xwayland directly.
+    fn start_xwayland(&mut self) {
+        let Some(mut sockets) = self.x11_sockets.take() else {
+            return;
+        };
+        for token in sockets.tokens.drain(..) {
+            self.event_loop.remove(token);
+        }
+
+        let display = sockets.display;
+        let early_clients = sockets.accept_pending();
+        // Smithay binds the sockets and takes the lock itself. Only the lock keeps other X
+        // servers off the display, so it goes right before spawning.
+        sockets.close_listeners();
+
+        let spawned = forward_output("stdout")
+            .and_then(|stdout| Ok((stdout, forward_output("stderr")?)))
+            .and_then(|(stdout, stderr)| {
+                drop(sockets);
+                XWayland::spawn(
+                    &self.display_handle,
+                    display,
+                    std::iter::empty::<(String, String)>(),
+                    true,
+                    stdout,
+                    stderr,
+                    |_| (),
+                )
+            });
+        let (xwayland, client) = match spawned {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                log::error!("error spawning Xwayland: {err}");
+                turn_away(early_clients);
+                return;
+            }
+        };
+
+        let res = self.event_loop.insert_source(xwayland, move |event, _, data| match event {
+            XWaylandEvent::Ready { x11_socket, .. } => {
+                data.tsuki.start_xwm(x11_socket, client.clone());
+            }
+            XWaylandEvent::Error => log::error!("Xwayland exited during startup"),
+        });
+        if let Err(err) = res {
+            log::error!("error inserting the Xwayland source: {err}");
+            turn_away(early_clients);
+            return;
+        }
+
+        for early_client in early_clients {
+            relay_to_xwayland(early_client, display);
+        }
+        log::info!("Xwayland started on :{display}");
+    }
+
+    fn start_xwm(&mut self, x11_socket: UnixStream, client: Client) {
+        // The window manager hands its events to the loop data, which has to be `Tsuki` like
+        // for the xwayland_shell commit hook. So it gets a loop of its own, dispatched from
+        // the main loop whenever it has something ready.
+        let mut xwm_loop = match EventLoop::<Tsuki>::try_new() {
+            Ok(xwm_loop) => xwm_loop,
+            Err(err) => {
+                log::error!("error creating the X11 window manager loop: {err}");
+                return;
+            }
+        };
+        let xwm_loop_fd = match xwm_loop.as_fd().try_clone_to_owned() {
+            Ok(fd) => fd,
+            Err(err) => {
+                log::error!("error creating the X11 window manager loop: {err}");
+                return;
+            }
+        };
+
+        match X11Wm::start_wm(xwm_loop.handle(), x11_socket, client) {
+            Ok(xwm) => self.xwm = Some(xwm),
+            Err(err) => {
+                log::error!("error starting the X11 window manager: {err}");
+                return;
+            }
+        }
+
+        let res = self.event_loop.insert_source(
+            Generic::new(xwm_loop_fd, Interest::READ, Mode::Level),
+            move |_, _, data| {
+                if let Err(err) = xwm_loop.dispatch(Some(Duration::ZERO), &mut data.tsuki) {
+                    log::error!("error dispatching the X11 window manager: {err}");
+                }
+                Ok(PostAction::Continue)
+            },
+        );
+        if let Err(err) = res {
+            log::error!("error inserting the X11 window manager source: {err}");
+        }
+    }
+
+    fn x11_window(&self, window: &X11Surface) -> Option<Window> {
+        self.toplevel_windows()
+            .find(|w| w.x11_surface() == Some(window))
+            .cloned()
+    }
+}
+
+impl XWaylandShellHandler for Tsuki {
+    fn xwayland_shell_state(&mut self) -> &mut XWaylandShellState {
+        &mut self.xwayland_shell_state
+    }
+}
+
+delegate_xwayland_shell!(Tsuki);
+
+impl XwmHandler for Tsuki {
+    fn xwm_state(&mut self, _xwm: XwmId) -> &mut X11Wm {
+        self.xwm.as_mut().unwrap()
+    }
+
+    fn new_window(&mut self, _xwm: XwmId, _window: X11Surface) {}
+
+    fn new_override_redirect_window(&mut self, _xwm: XwmId, _window: X11Surface) {}
+
+    fn map_window_request(&mut self, _xwm: XwmId, window: X11Surface) {
+        if let Err(err) = window.set_mapped(true) {
+            log::warn!("error mapping X11 window: {err}");
+            return;
+        }
+
+        // X11 windows pick their own position, keep it like the X server would.
+        let geometry = window.geometry();
+        self.space
+            .map_element(Window::new_x11_window(window.clone()), geometry.loc, false);
+        let _ = window.configure(geometry);
+
+        if let Some(xwm) = self.xwm.as_mut() {
+            let _ = xwm.raise_window(&window);
+        }
+        self.queue_redraw_all();
+    }
+
+    fn mapped_override_redirect_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        let location = window.geometry().loc;
+        self.space
+            .map_element(Window::new_x11_window(window), location, true);
+        self.queue_redraw_all();
+    }
+
+    fn unmapped_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        if let Some(mapped) = self.x11_window(&window) {
+            self.space.unmap_elem(&mapped);
+            self.minimized_windows.retain(|w| w != &mapped);
+            self.remove_foreign_toplevel(&mapped);
+        }
+
+        if !window.is_override_redirect() {
+            let _ = window.set_mapped(false);
+        }
+
+        // Unmapped X11 windows stay alive, so the keyboard focus wouldn't go away by itself.
+        let keyboard = self.seat.get_keyboard().unwrap();
+        let focused = keyboard.current_focus().is_some_and(|focus| {
+            matches!(focus, KeyboardFocusTarget::X11 { window: focused, .. } if *focused == window)
+        });
+        if focused {
+            keyboard.set_focus(self, None, SERIAL_COUNTER.next_serial());
+        }
+        self.queue_redraw_all();
+    }
+
+    fn destroyed_window(&mut self, _xwm: XwmId, window: X11Surface) {
+        if let Some(mapped) = self.x11_window(&window) {
+            self.space.unmap_elem(&mapped);
+            self.minimized_windows.retain(|w| w != &mapped);
+            self.remove_foreign_toplevel(&mapped);
+            self.queue_redraw_all();
+        }
+    }
+
+    fn property_notify(&mut self, _xwm: XwmId, window: X11Surface, property: WmWindowProperty) {
+        if !matches!(property, WmWindowProperty::Title | WmWindowProperty::Class) {
+            return;
+        }
+        if let Some(mapped) = self.x11_window(&window) {
+            self.update_foreign_toplevel(&mapped);
+        }
+    }
+
+    fn configure_request(
+        &mut self,
+        _xwm: XwmId,
+        window: X11Surface,
+        x: Option<i32>,
+        y: Option<i32>,
+        w: Option<u32>,
+        h: Option<u32>,
+        _reorder: Option<Reorder>,
+    ) {
+        // Mapped windows are placed by us, they only get to pick their size.
+        let mapped = self.x11_window(&window);
+        let mut geometry = window.geometry();
+        if mapped.is_none() {
+            geometry.loc.x = x.unwrap_or(geometry.loc.x);
+            geometry.loc.y = y.unwrap_or(geometry.loc.y);
+        }
+        geometry.size.w = w.map_or(geometry.size.w, |w| w as i32);
+        geometry.size.h = h.map_or(geometry.size.h, |h| h as i32);
+
+        let _ = window.configure(geometry);
+    }
+
+    fn configure_notify(
+        &mut self,
+        _xwm: XwmId,
+        window: X11Surface,
+        geometry: Rectangle<i32, Logical>,
+        _above: Option<u32>,
+    ) {
+        // Only override-redirect windows move themselves.
+        if !window.is_override_redirect() {
+            return;
+        }
+
+        if let Some(mapped) = self.x11_window(&window) {
+            self.space.map_element(mapped, geometry.loc, false);
+            self.queue_redraw_all();
+        }
+    }
+
+    fn resize_request(&mut self, _xwm: XwmId, window: X11Surface, _button: u32, resize_edge: X11ResizeEdge) {
+        let Some(mapped) = self.x11_window(&window) else {
+            return;
+        };
+        let Some(start_data) = check_grab(&self.seat, &window) else {
+            return;
+        };
+
+        let Some(location) = self.space.element_location(&mapped) else {
+            return;
+        };
+        let size = mapped.geometry().size;
+        let grab = ResizeSurfaceGrab::start(start_data, mapped, resize_edge.into(), Rectangle::new(location, size));
+        let pointer = self.seat.get_pointer().unwrap();
+        pointer.set_grab(self, grab, SERIAL_COUNTER.next_serial(), Focus::Clear);
+    }
+
+    fn move_request(&mut self, _xwm: XwmId, window: X11Surface, _button: u32) {
+        let Some(mapped) = self.x11_window(&window) else {
+            return;
+        };
+        let Some(start_data) = check_grab(&self.seat, &window) else {
+            return;
+        };
+
+        let Some(initial_window_location) = self.space.element_location(&mapped) else {
+            return;
+        };
+        let grab = MoveSurfaceGrab {
+            start_data,
+            window: mapped,
+            initial_window_location,
+        };
+        let pointer = self.seat.get_pointer().unwrap();
+        pointer.set_grab(self, grab, SERIAL_COUNTER.next_serial(), Focus::Clear);
+    }
+}
+
+/// A reserved X11 display, the lock file plus the listening sockets X clients connect to.
+pub struct X11Sockets {
+    display: u32,
+    listeners: Vec<UnixListener>,
+    tokens: Vec<RegistrationToken>,
+}
+
+impl X11Sockets {
+    /// Takes the first free display, the way X servers pick one.
+    fn bind() -> io::Result<Self> {
+        for display in 0..33 {
+            let lock = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(lock_path(display));
+            let Ok(mut lock) = lock else {
+                continue;
+            };
+
+            // Owns the lock from here on, dropping it on errors cleans up.
+            let mut sockets = Self {
+                display,
+                listeners: Vec::new(),
+                tokens: Vec::new(),
+            };
+            let res = writeln!(lock, "{:>10}", std::process::id()).and_then(|()| sockets.listen());
+            match res {
+                Ok(()) => return Ok(sockets),
+                Err(err) => log::warn!("error binding X11 display :{display}: {err}"),
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::AddrInUse, "no free X11 display"))
+    }
+
+    fn listen(&mut self) -> io::Result<()> {
+        // Normally set up at boot, but the first X server creates it otherwise.
+        if fs::create_dir("/tmp/.X11-unix").is_ok() {
+            fs::set_permissions("/tmp/.X11-unix", Permissions::from_mode(0o1777))?;
+        }
+
+        let path = socket_path(self.display);
+        let _ = fs::remove_file(&path);
+        self.listeners.push(UnixListener::bind(&path)?);
+        // Clients try the abstract socket first.
+        let addr = SocketAddr::from_abstract_name(path.as_bytes())?;
+        self.listeners.push(UnixListener::bind_addr(&addr)?);
+
+        for listener in &self.listeners {
+            listener.set_nonblocking(true)?;
+        }
+        Ok(())
+    }
+
+    /// Stops listening, the display stays reserved until the lock is dropped as well.
+    fn close_listeners(&mut self) {
+        self.listeners.clear();
+        let _ = fs::remove_file(socket_path(self.display));
+    }
+
+    fn accept_pending(&self) -> Vec<UnixStream> {
+        let mut clients = Vec::new();
+        for listener in &self.listeners {
+            while let Ok((client, _)) = listener.accept() {
+                clients.push(client);
+            }
+        }
+        clients
+    }
+}
+
+impl Drop for X11Sockets {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(socket_path(self.display));
+        let _ = fs::remove_file(lock_path(self.display));
+    }
+}
+
+/// Closes the connections of X clients that Xwayland won't serve, instead of leaving them
+/// waiting for a setup reply.
+fn turn_away(clients: Vec<UnixStream>) {
+    if clients.is_empty() {
+        return;
+    }
+    log::warn!("closing {} X client connections, there is no X server for them", clients.len());
+    for client in clients {
+        let _ = client.shutdown(Shutdown::Both);
+    }
+}
+
+fn lock_path(display: u32) -> String {
+    format!("/tmp/.X{display}-lock")
+}
+
+fn socket_path(display: u32) -> String {
+    format!("/tmp/.X11-unix/X{display}")
+}
+
+/// Connects a client accepted on our sockets to Xwayland and shuttles its traffic until one
+/// side hangs up.
+fn relay_to_xwayland(client: UnixStream, display: u32) {
+    let res = std::thread::Builder::new()
+        .name("xwayland relay".to_owned())
+        .spawn(move || {
+            if let Err(err) = client.set_nonblocking(false) {
+                log::warn!("error relaying an X11 client: {err}");
+                return;
+            }
+            // Blocks until Xwayland gets to accepting connections.
+            let server = match UnixStream::connect(socket_path(display)) {
+                Ok(server) => server,
+                Err(err) => {
+                    log::warn!("error relaying an X11 client: {err}");
+                    return;
+                }
+            };
+
+            let (Ok(client_writer), Ok(server_writer)) = (client.try_clone(), server.try_clone()) else {
+                log::warn!("error relaying an X11 client");
+                return;
+            };
+            let upstream = std::thread::spawn(move || relay(&client, &server_writer));
+            relay(&server, &client_writer);
+            let _ = upstream.join();
+        });
+    if let Err(err) = res {
+        log::warn!("error relaying an X11 client: {err}");
+    }
+}
+
+/// Copies everything `from` receives to `to`, including the file descriptors X11 passes
+/// around for buffers and fences.
+fn relay(from: &UnixStream, to: &UnixStream) {
+    let mut buf = vec![0; 64 * 1024];
+    let mut cmsg = nix::cmsg_space!([RawFd; 16]);
+
+    loop {
+        let (len, fds) = {
+            let mut iov = [IoSliceMut::new(&mut buf)];
+            let msg = match recvmsg::<()>(
+                from.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg),
+                MsgFlags::MSG_CMSG_CLOEXEC,
+            ) {
+                Ok(msg) => msg,
+                Err(Errno::EINTR) => continue,
+                Err(_) => break,
+            };
+
+            let mut fds = Vec::new();
+            for message in msg.cmsgs().into_iter().flatten() {
+                if let ControlMessageOwned::ScmRights(received) = message {
+                    // SAFETY: the kernel just handed these over, nothing else owns them.
+                    fds.extend(received.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+                }
+            }
+            (msg.bytes, fds)
+        };
+        if len == 0 {
+            break;
+        }
+
+        let raw_fds: Vec<_> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        let cmsgs = [ControlMessage::ScmRights(&raw_fds)];
+        let cmsgs = if raw_fds.is_empty() { &[][..] } else { &cmsgs[..] };
+        let sent = match sendmsg::<()>(to.as_raw_fd(), &[IoSlice::new(&buf[..len])], cmsgs, MsgFlags::empty(), None) {
+            Ok(sent) => sent,
+            Err(_) => break,
+        };
+        if (&*to).write_all(&buf[sent..len]).is_err() {
+            break;
+        }
+    }
+
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+/// A pipe for one of the Xwayland output streams, whose lines end up in the log.
+fn forward_output(name: &'static str) -> std::io::Result<Stdio> {
+    let (reader, writer) = UnixStream::pair()?;
+    std::thread::Builder::new()
+        .name(format!("xwayland {name}"))
+        .spawn(move || {
+            // Ends once Xwayland exits and closes its end.
+            for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                log::info!(target: "xwayland", "{line}");
+            }
+        })?;
+    Ok(OwnedFd::from(writer).into())
+}
+
+/// X11 requests come without a serial, so accept them while a button pressed on the window
+/// keeps the pointer grabbed.
+fn check_grab(seat: &Seat<Tsuki>, window: &X11Surface) -> Option<PointerGrabStartData<Tsuki>> {
+    let pointer = seat.get_pointer()?;
+    let start_data = pointer.grab_start_data()?;
+
+    let (focus, _) = start_data.focus.as_ref()?;
+    let surface = window.wl_surface()?;
+    if !focus.id().same_client_as(&surface.id()) {
+        return None;
+    }
+
+    Some(start_data)
+}
+
+impl From<X11ResizeEdge> for ResizeEdge {
+    fn from(edge: X11ResizeEdge) -> Self {
+        match edge {
+            X11ResizeEdge::Top => Self::TOP,
+            X11ResizeEdge::Bottom => Self::BOTTOM,
+            X11ResizeEdge::Left => Self::LEFT,
+            X11ResizeEdge::Right => Self::RIGHT,
+            X11ResizeEdge::TopLeft => Self::TOP_LEFT,
+            X11ResizeEdge::TopRight => Self::TOP_RIGHT,
+            X11ResizeEdge::BottomLeft => Self::BOTTOM_LEFT,
+            X11ResizeEdge::BottomRight => Self::BOTTOM_RIGHT,
+        }
+    }
+}